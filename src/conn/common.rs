@@ -1,5 +1,12 @@
 use crate::error::*;
-use crate::obid::advanced_protocol;
+use crate::obid::{
+    advanced_protocol, Framing, GetDeviceId, GetSoftwareVersion, ObidCommand, ReaderDiagnostic,
+    SoftwareVersion, Status, BROADCAST_COM_ADR,
+};
+use serde::{Deserialize, Serialize};
+
+/// Feig readers need at least 5 milliseconds between messages
+pub const MESSAGE_GAP: std::time::Duration = std::time::Duration::from_millis(6);
 
 #[derive(Debug)]
 pub struct AntennaState {
@@ -14,14 +21,217 @@ pub struct AntennaState {
     pub cmd_op: Option<String>,
 
     pub max_attempts: i32,
+    pub framing: Framing,
+}
+/// What the reader reported about itself when the connection was created
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReaderInfo {
+    pub software_version: SoftwareVersion,
+    /// Not every reader supports the Device-ID
+    pub device_id: Option<u32>,
+}
+
+impl std::fmt::Display for ReaderInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let version = &self.software_version;
+        write!(
+            f,
+            "SW-REV {:#06X}, D-REV {:#04X}, HW-TYPE {:#04X}, SW-TYPE {:#04X}, TR-TYPE {:#06X}",
+            version.sw_rev, version.d_rev, version.hw_type, version.sw_type, version.tr_type
+        )?;
+        match self.device_id {
+            Some(device_id) => write!(f, ", Device-ID {:#010X}", device_id),
+            None => Ok(()),
+        }
+    }
 }
+
 pub trait Connection<'a> {
     fn send_command(
         self: &mut Self,
         serial_message: advanced_protocol::HostToReader,
     ) -> Result<advanced_protocol::ReaderToHost>;
+
+    fn reader_info(self: &Self) -> Option<&ReaderInfo>;
+
+    /// Connect to the reader again, such as after a SystemReset
+    fn reconnect(self: &mut Self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// The link that carries frames between the host and a reader, such as USB or TCP
+pub trait Transport {
+    fn antenna_state(self: &Self) -> &AntennaState;
+
+    /// Send the serialized request after discarding the stale bytes of any earlier response
+    fn write_request(
+        self: &mut Self,
+        request: &advanced_protocol::HostToReader,
+        msg: &[u8],
+    ) -> Result<()>;
+
+    /// Read until a complete response frame has arrived
+    /// Returns None if the read failed or the frame was corrupt and the request should be sent again
+    fn read_response(self: &mut Self) -> Result<Option<advanced_protocol::ReaderToHost>>;
 }
 
 pub trait Context<'a> {
-    fn connection(self: &'a Self) -> Result<Box<dyn Connection<'a> + 'a>>;
+    fn connection(self: &Self) -> Result<Box<dyn Connection<'a> + 'a>>;
+}
+
+/**
+ * A monitor is continusously checking the RF hardware and if an error occurs
+ * the Reader answers every command with the error code 0x84. The details are
+ * requested with [0x6E] Reader Diagnostic and attached to the error.
+ */
+pub fn rf_warning<'a>(
+    connection: &mut dyn Connection<'a>,
+    request: &advanced_protocol::HostToReader,
+) -> InternalError {
+    let command = ReaderDiagnostic {};
+    let diagnostics = connection
+        .send_command(command.request(request.com_adr))
+        .and_then(|response| command.parse_response(&response));
+    let error = match diagnostics {
+        Ok(diagnostics) => InternalError::RFWarning(Some(diagnostics)),
+        Err(err) => {
+            log::warn!("Failed to read diagnostics for RF warning: {}", err);
+            InternalError::RFWarning(None)
+        }
+    };
+    log::error!("{}", error);
+    error
+}
+
+/**
+ * Send the request over the transport and check the status of the response.
+ *
+ * The request is sent again after a failed read or a corrupt response, and
+ * while no transponder answers a request that requires one, until the max
+ * attempts of the antenna state are used up.
+ */
+pub fn transceive<'a, T: Transport + Connection<'a>>(
+    transport: &mut T,
+    serial_message: advanced_protocol::HostToReader,
+) -> Result<advanced_protocol::ReaderToHost> {
+    let mut serial_message = serial_message;
+    let max_attempts = transport.antenna_state().max_attempts;
+    let msg = transport
+        .antenna_state()
+        .framing
+        .serialize(&mut serial_message);
+    let mut attempts = 0;
+    loop {
+        transport.write_request(&serial_message, &msg)?;
+
+        // Read the response to the command
+        attempts += 1;
+        let response = match transport.read_response()? {
+            Some(response) => response,
+            None if attempts >= max_attempts => {
+                // The link may be fine, such as when no reader on a bus has the COM-ADR
+                return Err(InternalError::NoResponse(format!(
+                    "No response from reader after {} attempts",
+                    attempts
+                )));
+            }
+            None => continue,
+        };
+        log::trace!(
+            "Interpretting response for attempt {}: {:#?}",
+            attempts,
+            response
+        );
+        if !response.is_response_to(&serial_message) {
+            let error_message = format!(
+                "Rejected response from reader {:#04X} to command for reader {:#04X}",
+                response.com_adr, serial_message.com_adr
+            );
+            log::error!("{}", error_message);
+            return Err(InternalError::from(error_message));
+        }
+
+        // Check for errors
+        let status = Status::from(response.status);
+        if status == Status::RFWarning
+            && serial_message.control_byte != ReaderDiagnostic::CONTROL_BYTE
+        {
+            return Err(rf_warning(transport, &serial_message));
+        } else if status == Status::FirmwareActivationRequired || status == Status::WrongFirmware {
+            return Err(firmware_error(status, transport.reader_info()));
+        } else if serial_message.device_required && status == Status::NoTransponder {
+            log::error!(
+                "No devices found on attempt {} of {}",
+                attempts,
+                max_attempts
+            );
+            if attempts >= max_attempts {
+                return Err(InternalError::from(
+                    "Failed to communicate with device in antenna",
+                ));
+            } else {
+                std::thread::sleep(std::time::Duration::from_millis(8 * attempts as u64));
+                continue;
+            }
+        }
+
+        // All done
+        return Ok(response);
+    }
+}
+
+/// Ask the reader for its firmware version and Device-ID with [0x66] Get Software Version
+pub fn query_reader_info<'a>(connection: &mut dyn Connection<'a>) -> Option<ReaderInfo> {
+    let command = GetSoftwareVersion {};
+    let software_version = match connection
+        .send_command(command.request(BROADCAST_COM_ADR))
+        .and_then(|response| command.parse_response(&response))
+    {
+        Ok(software_version) => software_version,
+        Err(err) => {
+            log::warn!("Failed to read the software version of the reader: {}", err);
+            return None;
+        }
+    };
+
+    let command = GetDeviceId {};
+    let device_id = match connection
+        .send_command(command.request(BROADCAST_COM_ADR))
+        .and_then(|response| command.parse_response(&response))
+    {
+        Ok(device_id) => Some(device_id),
+        Err(err) => {
+            log::warn!("Failed to read the Device-ID of the reader: {}", err);
+            None
+        }
+    };
+
+    let reader_info = ReaderInfo {
+        software_version,
+        device_id,
+    };
+    log::info!("Connected to reader with {}", reader_info);
+    Some(reader_info)
+}
+
+/// Explain a firmware status with what is known about the reader
+pub fn firmware_error(status: Status, reader_info: Option<&ReaderInfo>) -> InternalError {
+    let reader = match reader_info {
+        Some(reader_info) => reader_info.to_string(),
+        None => String::from("unknown firmware"),
+    };
+    let error_message = match status {
+        Status::FirmwareActivationRequired => format!(
+            "Firmware activation required for reader ({}). Send the Device-ID and serial number of the reader to Feig Electronic for an upgrade code.",
+            reader
+        ),
+        Status::WrongFirmware => format!(
+            "Wrong firmware on reader ({}). The RFC firmware doesn't match the FPGA or the hardware.",
+            reader
+        ),
+        other => format!("Unexpected firmware status {:?} from reader ({})", other, reader),
+    };
+    log::error!("{}", error_message);
+    InternalError::from(error_message)
 }
@@ -2,7 +2,20 @@ use crate::conn::common::*;
 use crate::error::*;
 use crate::obid::*;
 
-use std::{io::prelude::*, sync::mpsc, thread, time::Duration};
+use serde::{Deserialize, Serialize};
+use std::{
+    io::prelude::*,
+    net::{Ipv4Addr, SocketAddr, TcpStream, ToSocketAddrs},
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+
+/// The TCP port of the Feig reader
+pub const READER_PORT: u16 = 10001;
+
+/// The largest subnet that is scanned for a reader, which takes about 30 s at the default connect timeout
+pub const MIN_SUBNET_PREFIX: u32 = 22;
 
 /*Notes on Ethernet connection:
     Fieg Reader IP: 192.168.10.10, netmask: 255.255.0.0
@@ -14,8 +27,10 @@ use std::{io::prelude::*, sync::mpsc, thread, time::Duration};
 
 pub struct EthernetConnection {
     state: AntennaState,
+    reader_info: Option<ReaderInfo>,
     response_message_buffer: std::vec::Vec<u8>,
-    stream: std::net::TcpStream,
+    decoder: ObidFrameDecoder,
+    stream: TcpStream,
 }
 
 impl<'a> Connection<'a> for EthernetConnection {
@@ -23,134 +38,343 @@ impl<'a> Connection<'a> for EthernetConnection {
         self: &mut Self,
         serial_message: advanced_protocol::HostToReader,
     ) -> Result<advanced_protocol::ReaderToHost> {
-        let mut serial_message = serial_message;
-        let msg = serial_message.serialize();
-        let mut attempts = 0;
+        transceive(self, serial_message)
+    }
+
+    fn reader_info(self: &Self) -> Option<&ReaderInfo> {
+        self.reader_info.as_ref()
+    }
+}
+
+impl Transport for EthernetConnection {
+    fn antenna_state(self: &Self) -> &AntennaState {
+        &self.state
+    }
+
+    fn write_request(
+        self: &mut Self,
+        _request: &advanced_protocol::HostToReader,
+        msg: &[u8],
+    ) -> Result<()> {
+        if self.decoder.buffered() > 0 {
+            log::warn!(
+                "Discarding {} stale bytes before sending TCP Command",
+                self.decoder.buffered()
+            );
+            self.decoder.clear();
+        }
+        match self.stream.write(msg) {
+            Ok(bytes_written) => {
+                log::debug!(
+                    "Sent TCP Command with {} bytes: {}",
+                    bytes_written,
+                    hex::encode(msg)
+                );
+                Ok(())
+            }
+            Err(err) => {
+                log::error!("Failed TCP Command Send: {}", err.to_string());
+                Err(InternalError::LinkLost(err.to_string()))
+            }
+        }
+    }
+
+    /// Read from the stream until a complete response frame has arrived
+    fn read_response(self: &mut Self) -> Result<Option<advanced_protocol::ReaderToHost>> {
         loop {
-            match self.stream.write(&msg) {
-                Ok(bytes_written) => {
-                    log::debug!(
-                        "Sent TCP Command with {} bytes: {}",
-                        bytes_written,
-                        hex::encode(&msg)
-                    );
-                }
+            match self.decoder.next_frame() {
+                Ok(Some(response)) => return Ok(Some(response)),
+                Ok(None) => {}
                 Err(err) => {
-                    log::error!("Failed TCP Command Send: {}", err.to_string());
-                    return Err(InternalError::from(err));
+                    log::warn!("Discarding corrupt response: {}", err);
+                    return Ok(None);
                 }
             }
-            attempts += 1;
-            let response_message_size;
+
             match self
                 .stream
                 .read(self.response_message_buffer.as_mut_slice())
             {
+                Ok(0) => {
+                    log::error!("Failed Serial Command Read: connection closed");
+                    return Err(InternalError::LinkLost(String::from(
+                        "Ethernet connection closed by reader",
+                    )));
+                }
                 Ok(bytes_read) => {
                     log::debug!(
                         "Received Response to Serial Command with {} bytes: {}",
                         bytes_read,
                         hex::encode(&self.response_message_buffer[..bytes_read])
                     );
-                    response_message_size = bytes_read;
+                    self.decoder
+                        .push(&self.response_message_buffer[..bytes_read]);
                 }
                 Err(err) => {
                     log::error!("Failed Serial Command Read: {}", err.to_string());
-                    continue;
+                    return Ok(None);
                 }
             }
-            // Interpret the response
-            let response = advanced_protocol::ReaderToHost::deserialize(
-                &self.response_message_buffer[..response_message_size],
-            )?;
-            log::trace!(
-                "Interpretting response for attempt {}: {:#?}",
-                attempts,
-                response
-            );
+        }
+    }
+}
 
-            // Check for errors
-            let status = Status::from(response.status);
-            if status == Status::RFWarning {
-                /*
-                 * A monitor is continusously checking the RF hardware and
-                 * if an error occurs the Reader answers every command with
-                 * the error code 0x84
-                 */
-                let error_message = String::from(
-                    "Generic Antenna Error: RF hardware monitor error status code 0x84",
-                );
-                log::error!("{}", error_message);
-                return Err(InternalError::from(error_message));
-            } else if serial_message.device_required && status == Status::NoTransponder {
-                log::error!(
-                    "No devices found on attempt {} of {}",
-                    attempts,
-                    self.state.max_attempts
-                );
-                if attempts >= self.state.max_attempts {
-                    return Err(InternalError::from(
-                        "Failed to communicate with device in antenna",
-                    ));
-                } else {
-                    std::thread::sleep(std::time::Duration::from_millis(8 * attempts as u64));
+impl EthernetConnection {
+    pub fn new(addr: &str, framing: Framing) -> Result<EthernetConnection> {
+        EthernetConnection::with_options(addr, framing, &EthernetOptions::default())
+    }
+
+    pub fn with_options(
+        addr: &str,
+        framing: Framing,
+        options: &EthernetOptions,
+    ) -> Result<EthernetConnection> {
+        log::debug!("Checking Ethernet Connection");
+        let stream = match connect(addr, options) {
+            Ok(stream) => stream,
+            Err(err) => match &options.discover_subnet {
+                Some(subnet) => {
+                    log::warn!(
+                        "Couldn't connect to reader at {} ({}), scanning {} ...",
+                        addr,
+                        err,
+                        subnet
+                    );
+                    return EthernetConnection::discover_reader(addr, subnet, framing, options);
+                }
+                None => return Err(err),
+            },
+        };
+        EthernetConnection::from_stream(stream, framing, options)
+    }
+
+    /// Connect to the first host in the subnet that answers [0x66] Get Software Version
+    fn discover_reader(
+        addr: &str,
+        subnet: &str,
+        framing: Framing,
+        options: &EthernetOptions,
+    ) -> Result<EthernetConnection> {
+        for found in discover(subnet, READER_PORT, options.connect_timeout())? {
+            let connection = match connect(&found.to_string(), options)
+                .and_then(|stream| EthernetConnection::from_stream(stream, framing, options))
+            {
+                Ok(connection) => connection,
+                Err(err) => {
+                    log::warn!("Skipping {}: {}", found, err);
                     continue;
                 }
+            };
+            if connection.reader_info.is_some() {
+                log::info!("Discovered reader at {}", found);
+                return Ok(connection);
             }
+            log::warn!(
+                "Skipping {}, which doesn't answer like a Feig reader",
+                found
+            );
+        }
+        Err(InternalError::from(format!(
+            "Could not connect over ethernet to {} or discover a reader in {}",
+            addr, subnet
+        )))
+    }
+
+    fn from_stream(
+        stream: TcpStream,
+        framing: Framing,
+        options: &EthernetOptions,
+    ) -> Result<EthernetConnection> {
+        stream.set_read_timeout(options.read_timeout())?;
+        stream.set_write_timeout(options.write_timeout())?;
+
+        log::info!("Connected to the Fieg Reader!");
+        let mut connection = EthernetConnection {
+            state: AntennaState {
+                antenna_id: None,
+                pulse_mode: None,
+                hf_mod: None,
+                lf_mod: None,
+
+                command: None,
+                cmd_op: None,
+                act_block_count: None,
+
+                max_attempts: 5,
+                framing,
+            },
+            stream,
+            response_message_buffer: vec![0; 1024 * 1024 * 64],
+            decoder: ObidFrameDecoder::new(framing),
+            reader_info: None,
+        };
+        connection.reader_info = query_reader_info(&mut connection);
+        Ok(connection)
+    }
+}
 
-            // All done
-            return Ok(response);
+/// Timeouts in milliseconds for the TCP connection and where to look for a missing reader
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EthernetOptions {
+    pub connect_timeout_ms: u64,
+    pub read_timeout_ms: u64,
+    pub write_timeout_ms: u64,
+    /// Scan the subnet, such as 192.168.10.0/24, if the reader isn't at its address
+    pub discover_subnet: Option<String>,
+}
+
+impl Default for EthernetOptions {
+    fn default() -> Self {
+        EthernetOptions {
+            connect_timeout_ms: 2000,
+            read_timeout_ms: 1000,
+            write_timeout_ms: 1000,
+            discover_subnet: None,
         }
     }
 }
 
-impl EthernetConnection {
-    pub fn new(addr: &str) -> Result<EthernetConnection> {
-        log::debug!("Checking Ethernet Connection");
-        match std::net::TcpStream::connect(addr) {
-            Ok(stream) => {
-                log::info!("Connected to the Fieg Reader!");
-                return Ok(EthernetConnection {
-                    state: AntennaState {
-                        antenna_id: None,
-                        pulse_mode: None,
-                        hf_mod: None,
-                        lf_mod: None,
-
-                        command: None,
-                        cmd_op: None,
-                        act_block_count: None,
-
-                        max_attempts: 5,
-                    },
-                    stream,
-                    response_message_buffer: vec![0; 1024 * 1024 * 64],
-                });
-            }
-            Err(_) => {
-                log::error!("Couldn't connect to server...");
-                return Err(InternalError::from("Could not connect over ethernet"));
+impl EthernetOptions {
+    fn connect_timeout(self: &Self) -> Duration {
+        Duration::from_millis(self.connect_timeout_ms)
+    }
+
+    fn read_timeout(self: &Self) -> Option<Duration> {
+        Some(Duration::from_millis(self.read_timeout_ms)).filter(|d| !d.is_zero())
+    }
+
+    fn write_timeout(self: &Self) -> Option<Duration> {
+        Some(Duration::from_millis(self.write_timeout_ms)).filter(|d| !d.is_zero())
+    }
+}
+
+fn connect(addr: &str, options: &EthernetOptions) -> Result<TcpStream> {
+    let mut last_err = None;
+    for socket_addr in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&socket_addr, options.connect_timeout()) {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    log::error!("Couldn't connect to server...");
+    Err(match last_err {
+        Some(err) => InternalError::from(format!(
+            "Could not connect over ethernet to {}: {}",
+            addr, err
+        )),
+        None => InternalError::from(format!("Could not resolve ethernet address {}", addr)),
+    })
+}
+
+/// The host addresses of an IPv4 subnet such as 192.168.10.0/24
+fn subnet_hosts(subnet: &str) -> Result<std::vec::Vec<Ipv4Addr>> {
+    let mut parts = subnet.splitn(2, '/');
+    let network: Ipv4Addr = match parts.next().unwrap().parse() {
+        Ok(network) => network,
+        Err(_) => return Err(InternalError::from(format!("Invalid subnet {}", subnet))),
+    };
+    let prefix: u32 = parts.next().unwrap_or("32").parse()?;
+    if !(MIN_SUBNET_PREFIX..=32).contains(&prefix) {
+        return Err(InternalError::from(format!(
+            "Subnet {} must have a prefix between /{} and /32",
+            subnet, MIN_SUBNET_PREFIX
+        )));
+    }
+
+    let mask = if prefix == 32 { !0 } else { !(!0u32 >> prefix) };
+    let first = u32::from(network) & mask;
+    let last = first | !mask;
+    if prefix >= 31 {
+        return Ok((first..=last).map(Ipv4Addr::from).collect());
+    }
+    // Skip the network and broadcast addresses
+    Ok((first + 1..last).map(Ipv4Addr::from).collect())
+}
+
+/// Find the hosts in the subnet that accept connections on the port
+pub fn discover(subnet: &str, port: u16, timeout: Duration) -> Result<std::vec::Vec<SocketAddr>> {
+    let hosts = subnet_hosts(subnet)?;
+    log::info!(
+        "Scanning {} hosts in {} for port {} ...",
+        hosts.len(),
+        subnet,
+        port
+    );
+
+    let mut found = vec![];
+    for batch in hosts.chunks(64) {
+        let handles: std::vec::Vec<_> = batch
+            .iter()
+            .map(|host| {
+                let addr = SocketAddr::from((*host, port));
+                thread::spawn(move || match TcpStream::connect_timeout(&addr, timeout) {
+                    Ok(_) => Some(addr),
+                    Err(_) => None,
+                })
+            })
+            .collect();
+        for handle in handles {
+            if let Ok(Some(addr)) = handle.join() {
+                log::debug!("Found open port at {}", addr);
+                found.push(addr);
             }
         }
     }
+    Ok(found)
 }
 
 pub struct EthernetContext<'a> {
     pub addr: &'a str,
+    pub framing: Framing,
+    pub options: EthernetOptions,
 }
 
 impl<'a> EthernetContext<'a> {
-    pub fn new(addr: &'a str) -> Result<EthernetContext<'a>> {
-        Ok(EthernetContext { addr })
+    pub fn new(addr: &'a str, framing: Framing) -> Result<EthernetContext<'a>> {
+        EthernetContext::with_options(addr, framing, EthernetOptions::default())
+    }
+
+    pub fn with_options(
+        addr: &'a str,
+        framing: Framing,
+        options: EthernetOptions,
+    ) -> Result<EthernetContext<'a>> {
+        Ok(EthernetContext {
+            addr,
+            framing,
+            options,
+        })
     }
 }
 
 impl<'a> Context<'a> for EthernetContext<'a> {
-    fn connection(self: &'a Self) -> Result<Box<dyn Connection<'a> + 'a>> {
-        Ok(Box::new(EthernetConnection::new(self.addr)?))
+    fn connection(self: &Self) -> Result<Box<dyn Connection<'a> + 'a>> {
+        Ok(Box::new(EthernetConnection::with_options(
+            self.addr,
+            self.framing,
+            &self.options,
+        )?))
     }
 }
 
+#[test]
+fn subnet_hosts_of_cidr() -> Result<()> {
+    let hosts = subnet_hosts("192.168.10.0/24")?;
+    assert_eq!(254, hosts.len());
+    assert_eq!(Ipv4Addr::new(192, 168, 10, 1), hosts[0]);
+    assert_eq!(Ipv4Addr::new(192, 168, 10, 254), hosts[253]);
+
+    let hosts = subnet_hosts("192.168.10.10")?;
+    assert_eq!(vec![Ipv4Addr::new(192, 168, 10, 10)], hosts);
+
+    assert_eq!(1022, subnet_hosts("192.168.8.0/22")?.len());
+    assert!(subnet_hosts("192.168.0.0/16").is_err());
+    assert!(subnet_hosts("10.0.0.0/8").is_err());
+    assert!(subnet_hosts("reader/24").is_err());
+    Ok(())
+}
+
 #[cfg(feature = "ethernet")]
 #[test]
 fn check_ethernet_connection() -> Result<()> {
@@ -159,13 +383,15 @@ fn check_ethernet_connection() -> Result<()> {
         .init();
     _panic_after(Duration::from_millis(5000), move || -> () {
         let work = move || -> Result<()> {
-            let context = Box::new(EthernetContext::new("192.168.10.10:10001")?);
+            let context = Box::new(EthernetContext::new(
+                "192.168.10.10:10001",
+                Framing::Advanced,
+            )?);
             let _connection = Box::new(context.connection()?);
             Ok(())
         };
-        match work() {
-            Err(err) => panic!("Panicked with error {}", err),
-            _ => {}
+        if let Err(err) = work() {
+            panic!("Panicked with error {}", err);
         }
     });
 
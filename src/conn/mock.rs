@@ -2,7 +2,9 @@ use crate::conn::common::*;
 use crate::error::*;
 use crate::obid::*;
 
-pub struct MockConnection {}
+pub struct MockConnection {
+    framing: Framing,
+}
 
 impl<'a> Connection<'a> for MockConnection {
     fn send_command(
@@ -10,30 +12,392 @@ impl<'a> Connection<'a> for MockConnection {
         serial_message: advanced_protocol::HostToReader,
     ) -> Result<advanced_protocol::ReaderToHost> {
         let mut serial_message = serial_message;
-        let msg = serial_message.serialize();
+        let msg = self.framing.serialize(&mut serial_message);
         log::debug!("Sent msg: {:?}", msg);
-        let response = advanced_protocol::ReaderToHost::deserialize(&msg)?;
+        let response = self.framing.deserialize(&msg)?;
         log::debug!("Recieved response: {:?}", response);
         Ok(response)
     }
+
+    fn reader_info(self: &Self) -> Option<&ReaderInfo> {
+        None // An echo isn't a reader to ask
+    }
 }
 
 impl MockConnection {
-    pub fn new() -> MockConnection {
-        MockConnection {}
+    pub fn new(framing: Framing) -> MockConnection {
+        MockConnection { framing }
     }
 }
 
-pub struct MockContext {}
+pub struct MockContext {
+    pub framing: Framing,
+}
 
 impl<'a> Context<'a> for MockContext {
-    fn connection(self: &'a Self) -> Result<Box<dyn Connection<'a> + 'a>> {
-        Ok(Box::new(MockConnection::new()))
+    fn connection(self: &Self) -> Result<Box<dyn Connection<'a> + 'a>> {
+        Ok(Box::new(MockConnection::new(self.framing)))
     }
 }
 
 impl MockContext {
-    pub fn new() -> MockContext {
-        MockContext {}
+    pub fn new(framing: Framing) -> MockContext {
+        MockContext { framing }
+    }
+}
+
+/// A fault injected into the reply of the reader to a request
+#[derive(Debug, Clone, PartialEq)]
+pub enum Fault {
+    /// The reply never arrives, as if the read timed out
+    DropReply,
+    /// The reply arrives with a CRC16 that doesn't match its bytes
+    CorruptCrc,
+    /// The reader replies with the status and no data
+    Status(Status),
+    /// The reply arrives after the delay
+    Delay(std::time::Duration),
+    /// The reader closes the connection instead of replying
+    Disconnect,
+}
+
+/// Which replies get a fault
+#[derive(Debug, Clone)]
+pub enum FaultPlan {
+    /// Faults for the replies in order, where None lets a reply through
+    /// Replies after the end of the script are never faulted
+    Script(std::collections::VecDeque<Option<Fault>>),
+    /// Each reply gets one of the faults with the probability
+    /// The faults are chosen by a generator from the seed, so a failing run can be repeated
+    /// A seed of 0 is replaced by a fixed nonzero seed
+    Random {
+        probability: f64,
+        faults: std::vec::Vec<Fault>,
+        seed: u64,
+    },
+}
+
+impl FaultPlan {
+    pub fn script(faults: std::vec::Vec<Option<Fault>>) -> FaultPlan {
+        FaultPlan::Script(faults.into_iter().collect())
+    }
+
+    /// The fault for the next reply, if any
+    fn next_fault(self: &mut Self) -> Option<Fault> {
+        match self {
+            FaultPlan::Script(faults) => faults.pop_front().flatten(),
+            FaultPlan::Random {
+                probability,
+                faults,
+                seed,
+            } => {
+                // xorshift64, which is plenty random for picking faults but stays at a seed of 0
+                if *seed == 0 {
+                    *seed = 0x9E37_79B9_7F4A_7C15;
+                }
+                let mut next = || {
+                    *seed ^= *seed << 13;
+                    *seed ^= *seed >> 7;
+                    *seed ^= *seed << 17;
+                    *seed
+                };
+                let roll = (next() >> 11) as f64 / (1u64 << 53) as f64;
+                if faults.is_empty() || roll >= *probability {
+                    None
+                } else {
+                    Some(faults[next() as usize % faults.len()].clone())
+                }
+            }
+        }
+    }
+}
+
+/**
+ * A connection that injects faults into the replies of another connection.
+ *
+ * The replies travel as bytes through the same retry loop as a UsbConnection
+ * or an EthernetConnection, so the faults exercise how those recover. The plan
+ * is shared with the context, so faults continue across reconnects.
+ */
+pub struct FaultyConnection<'a> {
+    state: AntennaState,
+    reader: Box<dyn Connection<'a> + 'a>,
+    plan: std::sync::Arc<std::sync::Mutex<FaultPlan>>,
+    decoder: ObidFrameDecoder,
+    reply: Option<std::vec::Vec<u8>>,
+    delay: Option<std::time::Duration>,
+    connected: bool,
+}
+
+impl<'a> Connection<'a> for FaultyConnection<'a> {
+    fn send_command(
+        self: &mut Self,
+        serial_message: advanced_protocol::HostToReader,
+    ) -> Result<advanced_protocol::ReaderToHost> {
+        transceive(self, serial_message)
+    }
+
+    fn reader_info(self: &Self) -> Option<&ReaderInfo> {
+        self.reader.reader_info()
+    }
+}
+
+impl<'a> Transport for FaultyConnection<'a> {
+    fn antenna_state(self: &Self) -> &AntennaState {
+        &self.state
+    }
+
+    fn write_request(
+        self: &mut Self,
+        request: &advanced_protocol::HostToReader,
+        msg: &[u8],
+    ) -> Result<()> {
+        if !self.connected {
+            return Err(InternalError::LinkLost(String::from(
+                "Mock connection closed by reader",
+            )));
+        }
+        log::debug!("Sent msg: {}", hex::encode(msg));
+        self.decoder.clear();
+        self.reply = None;
+        self.delay = None;
+
+        // The retry loop of this connection decides what to do without a transponder
+        let mut response = self
+            .reader
+            .send_command(advanced_protocol::HostToReader::new(
+                0,
+                request.com_adr,
+                request.control_byte,
+                request.data.as_slice(),
+                0,
+                false,
+            ))?;
+
+        let fault = self.plan.lock().unwrap().next_fault();
+        if let Some(fault) = &fault {
+            log::warn!(
+                "Injecting {:?} into the reply to {:#04X}",
+                fault,
+                request.control_byte
+            );
+        }
+        match fault {
+            Some(Fault::DropReply) => return Ok(()),
+            Some(Fault::Disconnect) => {
+                self.connected = false;
+                return Ok(());
+            }
+            Some(Fault::Status(status)) => {
+                response.status = status as u8;
+                response.data.clear();
+            }
+            Some(Fault::Delay(delay)) => self.delay = Some(delay),
+            Some(Fault::CorruptCrc) | None => {}
+        }
+
+        let mut reply = response._serialize();
+        if fault == Some(Fault::CorruptCrc) {
+            let crc = reply.len() - 1;
+            reply[crc] ^= 0xFF;
+        }
+        self.reply = Some(reply);
+        Ok(())
+    }
+
+    fn read_response(self: &mut Self) -> Result<Option<advanced_protocol::ReaderToHost>> {
+        if let Some(delay) = self.delay.take() {
+            std::thread::sleep(delay);
+        }
+        if !self.connected {
+            return Err(InternalError::LinkLost(String::from(
+                "Mock connection closed by reader",
+            )));
+        }
+        match self.reply.take() {
+            Some(reply) => {
+                log::debug!("Recieved reply: {}", hex::encode(&reply));
+                self.decoder.push(&reply);
+            }
+            None => {
+                log::error!("Failed Serial Command Read: no reply");
+                return Ok(None);
+            }
+        }
+        match self.decoder.next_frame() {
+            Ok(response) => Ok(response),
+            Err(err) => {
+                log::warn!("Discarding corrupt response: {}", err);
+                Ok(None)
+            }
+        }
+    }
+}
+
+impl<'a> FaultyConnection<'a> {
+    pub fn new(
+        framing: Framing,
+        reader: Box<dyn Connection<'a> + 'a>,
+        plan: std::sync::Arc<std::sync::Mutex<FaultPlan>>,
+    ) -> FaultyConnection<'a> {
+        FaultyConnection {
+            state: AntennaState {
+                antenna_id: None,
+                pulse_mode: None,
+                hf_mod: None,
+                lf_mod: None,
+
+                command: None,
+                cmd_op: None,
+                act_block_count: None,
+
+                max_attempts: 5,
+                framing,
+            },
+            reader,
+            plan,
+            decoder: ObidFrameDecoder::new(framing),
+            reply: None,
+            delay: None,
+            connected: true,
+        }
+    }
+}
+
+/// Connects to the reader of another context through a FaultyConnection
+pub struct FaultyContext<'a> {
+    pub reader: Box<dyn Context<'a> + 'a>,
+    pub framing: Framing,
+    pub plan: std::sync::Arc<std::sync::Mutex<FaultPlan>>,
+}
+
+impl<'a> Context<'a> for FaultyContext<'a> {
+    fn connection(self: &Self) -> Result<Box<dyn Connection<'a> + 'a>> {
+        Ok(Box::new(FaultyConnection::new(
+            self.framing,
+            self.reader.connection()?,
+            self.plan.clone(),
+        )))
+    }
+}
+
+impl<'a> FaultyContext<'a> {
+    pub fn new(
+        reader: Box<dyn Context<'a> + 'a>,
+        framing: Framing,
+        plan: FaultPlan,
+    ) -> FaultyContext<'a> {
+        FaultyContext {
+            reader,
+            framing,
+            plan: std::sync::Arc::new(std::sync::Mutex::new(plan)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conn::simulated::{SimulatedContext, VirtualTransponder};
+
+    const UID: [u8; 8] = [1, 2, 3, 4, 5, 6, 7, 8];
+
+    fn faulty(plan: FaultPlan) -> Box<dyn Connection<'static>> {
+        let reader = SimulatedContext::with_transponders(
+            Framing::Advanced,
+            vec![VirtualTransponder::new(UID)],
+        );
+        FaultyContext::new(Box::new(reader), Framing::Advanced, plan)
+            .connection()
+            .unwrap()
+    }
+
+    fn inventory(connection: &mut Box<dyn Connection<'static>>) -> Result<usize> {
+        let command = Inventory {
+            device_required: true,
+        };
+        let response = connection.send_command(command.request(BROADCAST_COM_ADR))?;
+        Ok(command.parse_response(&response)?.len())
+    }
+
+    #[test]
+    fn retry_after_lost_and_corrupt_replies() {
+        let mut connection = faulty(FaultPlan::script(vec![
+            Some(Fault::DropReply),
+            Some(Fault::CorruptCrc),
+            None,
+            Some(Fault::Delay(std::time::Duration::from_millis(5))),
+        ]));
+        assert_eq!(1, inventory(&mut connection).unwrap());
+        assert_eq!(1, inventory(&mut connection).unwrap());
+    }
+
+    #[test]
+    fn give_up_after_max_attempts() {
+        let mut connection = faulty(FaultPlan::script(vec![Some(Fault::DropReply); 5]));
+        let err = inventory(&mut connection).unwrap_err();
+        assert_eq!("No response from reader after 5 attempts", err.to_string());
+        assert_eq!(1, inventory(&mut connection).unwrap());
+    }
+
+    #[test]
+    fn retry_until_a_transponder_answers() {
+        let no_transponder = Some(Fault::Status(Status::NoTransponder));
+        let mut connection = faulty(FaultPlan::script(vec![no_transponder.clone(); 4]));
+        assert_eq!(1, inventory(&mut connection).unwrap());
+
+        let mut connection = faulty(FaultPlan::script(vec![no_transponder; 5]));
+        let err = inventory(&mut connection).unwrap_err();
+        assert_eq!(
+            "Failed to communicate with device in antenna",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn abort_with_diagnostics_on_rf_warning() {
+        let mut connection = faulty(FaultPlan::script(vec![Some(Fault::Status(
+            Status::RFWarning,
+        ))]));
+        match inventory(&mut connection) {
+            Err(InternalError::RFWarning(Some(diagnostics))) => {
+                assert!(diagnostics.is_healthy())
+            }
+            other => panic!("Expected an RF warning but got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn stay_disconnected() {
+        let mut connection = faulty(FaultPlan::script(vec![Some(Fault::Disconnect)]));
+        assert!(inventory(&mut connection).is_err());
+        assert!(inventory(&mut connection).is_err());
+    }
+
+    #[test]
+    fn recover_from_random_faults() {
+        let mut connection = faulty(FaultPlan::Random {
+            probability: 0.3,
+            faults: vec![
+                Fault::DropReply,
+                Fault::CorruptCrc,
+                Fault::Status(Status::NoTransponder),
+            ],
+            seed: 0x5EED,
+        });
+        for _ in 0..20 {
+            assert_eq!(1, inventory(&mut connection).unwrap());
+        }
+    }
+
+    #[test]
+    fn random_faults_from_seed_0() {
+        let mut plan = FaultPlan::Random {
+            probability: 0.5,
+            faults: vec![Fault::DropReply],
+            seed: 0,
+        };
+        let faults = (0..100).filter_map(|_| plan.next_fault()).count();
+        assert!(faults > 20 && faults < 80, "{} faults", faults);
     }
 }
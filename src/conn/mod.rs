@@ -1,8 +1,14 @@
 pub mod common;
+pub mod record;
+pub mod supervisor;
 
 #[cfg(feature = "ethernet")]
 pub mod ethernet;
 #[cfg(feature = "mock")]
 pub mod mock;
+#[cfg(feature = "serial")]
+pub mod serial;
+#[cfg(feature = "mock")]
+pub mod simulated;
 #[cfg(feature = "usb")]
 pub mod usb;
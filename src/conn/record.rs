@@ -0,0 +1,407 @@
+use crate::conn::common::*;
+use crate::error::*;
+use crate::obid::*;
+
+use serde::{Deserialize, Serialize};
+use std::io::prelude::*;
+
+/// A line of a capture file, which holds one JSON entry per line
+///
+/// Frames are kept as hex of the advanced protocol, like the debug logs.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum CaptureEntry {
+    /// A connection to the reader was made, which begins a session of exchanges
+    Connected {
+        timestamp_ms: u64,
+        reader_info: Option<ReaderInfo>,
+    },
+    /// A request and the response or error it got
+    Exchange {
+        timestamp_ms: u64,
+        request: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        response: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+        /// Captures without it replay their errors as Generic
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error_kind: Option<ErrorKind>,
+    },
+}
+
+/// The variant of an error an exchange failed with, so a replay fails the same way
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ErrorKind {
+    Generic,
+    RFWarning(Option<ReaderDiagnostics>),
+    LinkLost,
+    NoResponse,
+    ReaderUnavailable,
+}
+
+impl ErrorKind {
+    pub fn of(err: &InternalError) -> ErrorKind {
+        match err {
+            InternalError::RFWarning(diagnostics) => ErrorKind::RFWarning(*diagnostics),
+            InternalError::LinkLost(_) => ErrorKind::LinkLost,
+            InternalError::NoResponse(_) => ErrorKind::NoResponse,
+            InternalError::ReaderUnavailable(_) => ErrorKind::ReaderUnavailable,
+            _ => ErrorKind::Generic,
+        }
+    }
+
+    /// The error of this kind with the recorded message
+    pub fn error(self: &Self, message: String) -> InternalError {
+        match self {
+            ErrorKind::Generic => InternalError::Generic(message),
+            ErrorKind::RFWarning(diagnostics) => InternalError::RFWarning(*diagnostics),
+            ErrorKind::LinkLost => InternalError::LinkLost(message),
+            ErrorKind::NoResponse => InternalError::NoResponse(message),
+            ErrorKind::ReaderUnavailable => InternalError::ReaderUnavailable(message),
+        }
+    }
+}
+
+fn timestamp_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// The request as hex of the advanced protocol, regardless of the framing it is sent with
+fn request_frame(serial_message: &advanced_protocol::HostToReader) -> String {
+    let mut request = advanced_protocol::HostToReader::new(
+        0,
+        serial_message.com_adr,
+        serial_message.control_byte,
+        serial_message.data.as_slice(),
+        0,
+        serial_message.device_required,
+    );
+    hex::encode(request.serialize())
+}
+
+/// Writes every request to another connection and what it got back to a capture file
+pub struct RecordingConnection<'a> {
+    reader: Box<dyn Connection<'a> + 'a>,
+    file: std::fs::File,
+}
+
+impl<'a> Connection<'a> for RecordingConnection<'a> {
+    fn send_command(
+        self: &mut Self,
+        serial_message: advanced_protocol::HostToReader,
+    ) -> Result<advanced_protocol::ReaderToHost> {
+        let request = request_frame(&serial_message);
+        let result = self.reader.send_command(serial_message);
+        let entry = match &result {
+            Ok(response) => CaptureEntry::Exchange {
+                timestamp_ms: timestamp_ms(),
+                request,
+                response: Some(hex::encode(response.clone()._serialize())),
+                error: None,
+                error_kind: None,
+            },
+            Err(err) => CaptureEntry::Exchange {
+                timestamp_ms: timestamp_ms(),
+                request,
+                response: None,
+                error: Some(err.to_string()),
+                error_kind: Some(ErrorKind::of(err)),
+            },
+        };
+        if let Err(err) = self.write(&entry) {
+            log::error!("Failed to record exchange: {}", err);
+        }
+        result
+    }
+
+    fn reader_info(self: &Self) -> Option<&ReaderInfo> {
+        self.reader.reader_info()
+    }
+}
+
+impl<'a> RecordingConnection<'a> {
+    /// Record to the end of the capture file
+    pub fn new(
+        reader: Box<dyn Connection<'a> + 'a>,
+        path: &std::path::Path,
+    ) -> Result<RecordingConnection<'a>> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        let mut connection = RecordingConnection { reader, file };
+        connection.write(&CaptureEntry::Connected {
+            timestamp_ms: timestamp_ms(),
+            reader_info: connection.reader.reader_info().cloned(),
+        })?;
+        Ok(connection)
+    }
+
+    /// Flushed line by line, so the capture survives a crash of the host
+    fn write(self: &mut Self, entry: &CaptureEntry) -> Result<()> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+/// Records the connections of another context to the end of a capture file
+pub struct RecordingContext<'a> {
+    pub reader: Box<dyn Context<'a> + 'a>,
+    pub path: std::path::PathBuf,
+}
+
+impl<'a> Context<'a> for RecordingContext<'a> {
+    fn connection(self: &Self) -> Result<Box<dyn Connection<'a> + 'a>> {
+        Ok(Box::new(RecordingConnection::new(
+            self.reader.connection()?,
+            self.path.as_path(),
+        )?))
+    }
+}
+
+impl<'a> RecordingContext<'a> {
+    /// Appends to any existing capture at the path, so a restarted server keeps what came before
+    pub fn new(
+        reader: Box<dyn Context<'a> + 'a>,
+        path: &std::path::Path,
+    ) -> Result<RecordingContext<'a>> {
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        Ok(RecordingContext {
+            reader,
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+/// A request, and the response or error, as hex and messages
+pub type Exchange = (String, std::result::Result<String, (ErrorKind, String)>);
+
+/// One connection of a capture, with the exchanges in the order they happened
+#[derive(Debug, Clone, PartialEq)]
+pub struct CaptureSession {
+    pub reader_info: Option<ReaderInfo>,
+    pub exchanges: std::vec::Vec<Exchange>,
+}
+
+/// Read the sessions of a capture file
+pub fn read_capture(path: &std::path::Path) -> Result<std::vec::Vec<CaptureSession>> {
+    let file = std::io::BufReader::new(std::fs::File::open(path)?);
+    let mut sessions: std::vec::Vec<CaptureSession> = vec![];
+    for (index, line) in file.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line)? {
+            CaptureEntry::Connected { reader_info, .. } => sessions.push(CaptureSession {
+                reader_info,
+                exchanges: vec![],
+            }),
+            CaptureEntry::Exchange {
+                request,
+                response,
+                error,
+                error_kind,
+                ..
+            } => {
+                let session = sessions.last_mut().ok_or_else(|| {
+                    InternalError::from(format!(
+                        "Line {} of {} is an exchange before any connection",
+                        index + 1,
+                        path.display()
+                    ))
+                })?;
+                let error_kind = error_kind.unwrap_or(ErrorKind::Generic);
+                let result = match (response, error) {
+                    (Some(response), _) => Ok(response),
+                    (None, Some(error)) => Err((error_kind, error)),
+                    (None, None) => Err((error_kind, String::from("No response was recorded"))),
+                };
+                session.exchanges.push((request, result));
+            }
+        }
+    }
+    Ok(sessions)
+}
+
+/// Serves the responses of a recorded session back in order
+///
+/// A request that differs from the recorded one fails, but still uses up its
+/// exchange so that the rest of the session stays in step.
+pub struct ReplayConnection {
+    session: CaptureSession,
+    next: usize,
+}
+
+impl<'a> Connection<'a> for ReplayConnection {
+    fn send_command(
+        self: &mut Self,
+        serial_message: advanced_protocol::HostToReader,
+    ) -> Result<advanced_protocol::ReaderToHost> {
+        let request = request_frame(&serial_message);
+        log::debug!("Sent msg: {}", request);
+        let (expected, result) = match self.session.exchanges.get(self.next) {
+            Some(exchange) => exchange.clone(),
+            None => {
+                return Err(InternalError::from(format!(
+                    "Replay has no response for request #{} {}",
+                    self.next + 1,
+                    request
+                )))
+            }
+        };
+        self.next += 1;
+
+        if expected != request {
+            let error_message = format!(
+                "Replayed request #{} differs from the capture: expected {} but got {}",
+                self.next, expected, request
+            );
+            log::error!("{}", error_message);
+            return Err(InternalError::from(error_message));
+        }
+
+        match result {
+            Ok(response) => {
+                log::debug!("Recieved response: {}", response);
+                let response = hex::decode(&response).map_err(|err| {
+                    InternalError::from(format!("Invalid recorded response: {}", err))
+                })?;
+                Ok(advanced_protocol::ReaderToHost::deserialize(&response)?)
+            }
+            Err((error_kind, error)) => Err(error_kind.error(error)),
+        }
+    }
+
+    fn reader_info(self: &Self) -> Option<&ReaderInfo> {
+        self.session.reader_info.as_ref()
+    }
+}
+
+impl ReplayConnection {
+    pub fn new(session: CaptureSession) -> ReplayConnection {
+        ReplayConnection { session, next: 0 }
+    }
+}
+
+/// Each connection replays the next session of the capture, like a host reconnecting after a reset
+pub struct ReplayContext {
+    pub sessions: std::vec::Vec<CaptureSession>,
+    next: std::cell::Cell<usize>,
+}
+
+impl<'a> Context<'a> for ReplayContext {
+    fn connection(self: &Self) -> Result<Box<dyn Connection<'a> + 'a>> {
+        let next = self.next.get();
+        match self.sessions.get(next) {
+            Some(session) => {
+                self.next.set(next + 1);
+                Ok(Box::new(ReplayConnection::new(session.clone())))
+            }
+            None => Err(InternalError::from(format!(
+                "Replay has no session for connection #{}",
+                next + 1
+            ))),
+        }
+    }
+}
+
+impl ReplayContext {
+    pub fn new(path: &std::path::Path) -> Result<ReplayContext> {
+        Ok(ReplayContext {
+            sessions: read_capture(path)?,
+            next: std::cell::Cell::new(0),
+        })
+    }
+}
+
+#[cfg(all(test, feature = "mock"))]
+mod tests {
+    use super::*;
+    use crate::conn::simulated::SimulatedContext;
+
+    #[test]
+    fn replay_a_recorded_session() {
+        let path = std::env::temp_dir().join(format!("capture-{}.jsonl", uuid::Uuid::new_v4()));
+        let reader = Box::new(SimulatedContext::new(Framing::Advanced));
+        let recording = RecordingContext::new(reader, &path).unwrap();
+        let inventory = Inventory {
+            device_required: true,
+        };
+        let rf_off = RfOnOff { rf: 0 };
+        let mut connection = recording.connection().unwrap();
+        let transponders = execute(connection.as_mut(), &inventory).unwrap();
+        execute(connection.as_mut(), &rf_off).unwrap();
+        assert!(execute(connection.as_mut(), &inventory).is_err());
+        drop(connection);
+
+        let replay = ReplayContext::new(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(1, replay.sessions.len());
+        assert_eq!(3, replay.sessions[0].exchanges.len());
+        let mut connection = replay.connection().unwrap();
+        assert!(connection.reader_info().is_some());
+        assert_eq!(
+            transponders,
+            execute(connection.as_mut(), &inventory).unwrap()
+        );
+        assert!(execute(connection.as_mut(), &RfOnOff { rf: 1 }).is_err());
+        assert!(execute(connection.as_mut(), &inventory).is_err());
+        assert!(execute(connection.as_mut(), &inventory).is_err());
+        assert!(replay.connection().is_err());
+    }
+
+    #[test]
+    fn replay_the_kind_of_a_recorded_error() {
+        let path = std::env::temp_dir().join(format!("capture-{}.jsonl", uuid::Uuid::new_v4()));
+        let reader = Box::new(SimulatedContext::new(Framing::Advanced));
+        let recording = RecordingContext::new(reader, &path).unwrap();
+        drop(recording.connection().unwrap());
+
+        // A restarted server keeps the capture, which then gets a session with a lost link
+        let reader = Box::new(SimulatedContext::new(Framing::Advanced));
+        RecordingContext::new(reader, &path).unwrap();
+        let request = RfOnOff { rf: 0 }.request(BROADCAST_COM_ADR);
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        for entry in [
+            CaptureEntry::Connected {
+                timestamp_ms: 0,
+                reader_info: None,
+            },
+            CaptureEntry::Exchange {
+                timestamp_ms: 0,
+                request: request_frame(&request),
+                response: None,
+                error: Some(String::from("Ethernet connection closed by reader")),
+                error_kind: Some(ErrorKind::of(&InternalError::LinkLost(String::new()))),
+            },
+        ]
+        .iter()
+        {
+            writeln!(file, "{}", serde_json::to_string(entry).unwrap()).unwrap();
+        }
+
+        let replay = ReplayContext::new(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(2, replay.sessions.len());
+        replay.connection().unwrap();
+        match replay.connection().unwrap().send_command(request) {
+            Err(InternalError::LinkLost(message)) => {
+                assert_eq!("Ethernet connection closed by reader", message)
+            }
+            other => panic!("Expected a lost link but got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,395 @@
+use crate::conn::common::*;
+use crate::error::*;
+use crate::obid::*;
+
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+/// Parity of the serial line
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum SerialParity {
+    None,
+    Even,
+    Odd,
+}
+
+impl std::str::FromStr for SerialParity {
+    type Err = String;
+
+    fn from_str(parity: &str) -> std::result::Result<Self, Self::Err> {
+        match parity {
+            "none" => Ok(SerialParity::None),
+            "even" => Ok(SerialParity::Even),
+            "odd" => Ok(SerialParity::Odd),
+            other => Err(format!("Invalid parity: {} not supported", other)),
+        }
+    }
+}
+
+impl From<SerialParity> for serialport::Parity {
+    fn from(parity: SerialParity) -> serialport::Parity {
+        match parity {
+            SerialParity::None => serialport::Parity::None,
+            SerialParity::Even => serialport::Parity::Even,
+            SerialParity::Odd => serialport::Parity::Odd,
+        }
+    }
+}
+
+/// Line settings of an RS-232 or RS-485 reader, where the defaults are the 38400 8E1 of a Feig reader
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SerialOptions {
+    pub baud_rate: u32,
+    pub data_bits: u8,
+    pub parity: SerialParity,
+    pub stop_bits: u8,
+    pub read_timeout_ms: u64,
+    /// The COM-ADR of the reader on an RS-485 bus
+    /// Every reader on the bus would answer a broadcast, so broadcasts are sent to this address instead
+    pub bus_address: Option<u8>,
+}
+
+impl Default for SerialOptions {
+    fn default() -> Self {
+        SerialOptions {
+            baud_rate: 38400,
+            data_bits: 8,
+            parity: SerialParity::Even,
+            stop_bits: 1,
+            read_timeout_ms: 1000,
+            bus_address: None,
+        }
+    }
+}
+
+impl SerialOptions {
+    /// A timeout of 0 waits forever, like the timeouts of a TCP stream
+    pub fn read_timeout(self: &Self) -> Duration {
+        match self.read_timeout_ms {
+            0 => Duration::from_secs(u32::MAX as u64),
+            timeout => Duration::from_millis(timeout),
+        }
+    }
+
+    fn data_bits(self: &Self) -> Result<serialport::DataBits> {
+        match self.data_bits {
+            5 => Ok(serialport::DataBits::Five),
+            6 => Ok(serialport::DataBits::Six),
+            7 => Ok(serialport::DataBits::Seven),
+            8 => Ok(serialport::DataBits::Eight),
+            other => Err(InternalError::from(format!(
+                "Invalid data bits: {} isn't one of 5, 6, 7 or 8",
+                other
+            ))),
+        }
+    }
+
+    fn stop_bits(self: &Self) -> Result<serialport::StopBits> {
+        match self.stop_bits {
+            1 => Ok(serialport::StopBits::One),
+            2 => Ok(serialport::StopBits::Two),
+            other => Err(InternalError::from(format!(
+                "Invalid stop bits: {} isn't one of 1 or 2",
+                other
+            ))),
+        }
+    }
+}
+
+pub struct SerialConnection {
+    state: AntennaState,
+    reader_info: Option<ReaderInfo>,
+    bus_address: Option<u8>,
+    port: Box<dyn serialport::SerialPort>,
+    response_message_buffer: std::vec::Vec<u8>,
+    decoder: ObidFrameDecoder,
+    last_message: Option<Instant>,
+}
+
+impl<'a> Connection<'a> for SerialConnection {
+    fn send_command(
+        self: &mut Self,
+        serial_message: advanced_protocol::HostToReader,
+    ) -> Result<advanced_protocol::ReaderToHost> {
+        let mut serial_message = serial_message;
+        if let Some(bus_address) = self.bus_address {
+            if serial_message.com_adr == BROADCAST_COM_ADR {
+                serial_message.com_adr = bus_address;
+            }
+        }
+        transceive(self, serial_message)
+    }
+
+    fn reader_info(self: &Self) -> Option<&ReaderInfo> {
+        self.reader_info.as_ref()
+    }
+}
+
+impl Transport for SerialConnection {
+    fn antenna_state(self: &Self) -> &AntennaState {
+        &self.state
+    }
+
+    fn write_request(
+        self: &mut Self,
+        _request: &advanced_protocol::HostToReader,
+        msg: &[u8],
+    ) -> Result<()> {
+        // The line must be quiet between messages, which also lets other readers on a bus finish
+        if let Some(last_message) = self.last_message {
+            let elapsed = last_message.elapsed();
+            if elapsed < MESSAGE_GAP {
+                std::thread::sleep(MESSAGE_GAP - elapsed);
+            }
+        }
+        if self.decoder.buffered() > 0 {
+            log::warn!(
+                "Discarding {} stale bytes before sending Serial Command",
+                self.decoder.buffered()
+            );
+            self.decoder.clear();
+        }
+        self.port
+            .clear(serialport::ClearBuffer::Input)
+            .map_err(|err| InternalError::LinkLost(err.to_string()))?;
+
+        let sent = self.port.write_all(msg).and_then(|_| self.port.flush());
+        self.last_message = Some(Instant::now());
+        match sent {
+            Ok(()) => {
+                log::debug!(
+                    "Sent Serial Command with {} bytes: {}",
+                    msg.len(),
+                    hex::encode(msg)
+                );
+                Ok(())
+            }
+            Err(err) => {
+                log::error!("Failed Serial Command Send: {}", err.to_string());
+                Err(InternalError::LinkLost(err.to_string()))
+            }
+        }
+    }
+
+    /// Read from the tty until a complete response frame has arrived
+    fn read_response(self: &mut Self) -> Result<Option<advanced_protocol::ReaderToHost>> {
+        loop {
+            match self.decoder.next_frame() {
+                Ok(Some(response)) => {
+                    self.last_message = Some(Instant::now());
+                    return Ok(Some(response));
+                }
+                Ok(None) => {}
+                Err(err) => {
+                    log::warn!("Discarding corrupt response: {}", err);
+                    return Ok(None);
+                }
+            }
+
+            match self.port.read(self.response_message_buffer.as_mut_slice()) {
+                Ok(0) => {
+                    log::error!("Failed Serial Command Read: no bytes");
+                    return Ok(None);
+                }
+                Ok(bytes_read) => {
+                    log::debug!(
+                        "Received Response to Serial Command with {} bytes: {}",
+                        bytes_read,
+                        hex::encode(&self.response_message_buffer[..bytes_read])
+                    );
+                    self.decoder
+                        .push(&self.response_message_buffer[..bytes_read]);
+                }
+                Err(err) => {
+                    log::error!("Failed Serial Command Read: {}", err.to_string());
+                    return Ok(None);
+                }
+            }
+        }
+    }
+}
+
+impl SerialConnection {
+    /// Open the tty, such as /dev/ttyUSB0
+    pub fn new(path: &str, framing: Framing, options: &SerialOptions) -> Result<SerialConnection> {
+        log::debug!("Opening serial port {} with {:?}", path, options);
+        let port = serialport::new(path, options.baud_rate)
+            .data_bits(options.data_bits()?)
+            .parity(options.parity.into())
+            .stop_bits(options.stop_bits()?)
+            .flow_control(serialport::FlowControl::None)
+            .timeout(options.read_timeout())
+            .open_native()?;
+        // Readers sharing an RS-485 bus share the tty, where only Unix locks it by default
+        #[cfg(unix)]
+        let port = {
+            let mut port = port;
+            if options.bus_address.is_some() {
+                port.set_exclusive(false)?;
+            }
+            port
+        };
+        SerialConnection::with_port(Box::new(port), framing, options)
+    }
+
+    /// Talk to a reader over a port that is already open
+    pub fn with_port(
+        port: Box<dyn serialport::SerialPort>,
+        framing: Framing,
+        options: &SerialOptions,
+    ) -> Result<SerialConnection> {
+        let mut connection = SerialConnection {
+            state: AntennaState {
+                antenna_id: None,
+                pulse_mode: None,
+                hf_mod: None,
+                lf_mod: None,
+
+                command: None,
+                cmd_op: None,
+                act_block_count: None,
+
+                max_attempts: 5,
+                framing,
+            },
+            reader_info: None,
+            bus_address: options.bus_address,
+            port,
+            response_message_buffer: vec![0; 4096],
+            decoder: ObidFrameDecoder::new(framing),
+            last_message: None,
+        };
+        log::info!("Opened serial port to the Feig Reader!");
+        connection.reader_info = query_reader_info(&mut connection);
+        Ok(connection)
+    }
+}
+
+pub struct SerialContext {
+    pub path: String,
+    pub framing: Framing,
+    pub options: SerialOptions,
+}
+
+impl<'a> Context<'a> for SerialContext {
+    fn connection(self: &Self) -> Result<Box<dyn Connection<'a> + 'a>> {
+        Ok(Box::new(SerialConnection::new(
+            &self.path,
+            self.framing,
+            &self.options,
+        )?))
+    }
+}
+
+impl SerialContext {
+    pub fn new(path: &str, framing: Framing) -> SerialContext {
+        SerialContext::with_options(path, framing, SerialOptions::default())
+    }
+
+    pub fn with_options(path: &str, framing: Framing, options: SerialOptions) -> SerialContext {
+        SerialContext {
+            path: String::from(path),
+            framing,
+            options,
+        }
+    }
+}
+
+// The tests talk to a simulated reader over a pty
+#[cfg(all(test, feature = "mock", unix))]
+mod tests {
+    use super::*;
+    use crate::conn::simulated::{SimulatedReader, VirtualTransponder};
+    use std::io::{Read, Write};
+
+    /// A simulated reader on the other end of a pty, which ignores requests for other COM-ADRs like a bus
+    fn spawn_reader(
+        mut port: serialport::TTYPort,
+        com_adr: u8,
+    ) -> std::thread::JoinHandle<std::vec::Vec<u8>> {
+        std::thread::spawn(move || {
+            serialport::SerialPort::set_timeout(&mut port, Duration::from_secs(5)).unwrap();
+            let mut reader =
+                SimulatedReader::new(vec![VirtualTransponder::new([1, 2, 3, 4, 5, 6, 7, 8])]);
+            reader.com_adr = com_adr;
+            let mut addressed = vec![];
+            let mut buffer = vec![];
+            let mut byte = [0u8; 1];
+            while port.read_exact(&mut byte).is_ok() {
+                buffer.push(byte[0]);
+                if buffer.len() < 3
+                    || buffer.len() < ((buffer[1] as usize) << 8 | buffer[2] as usize)
+                {
+                    continue;
+                }
+                let request = advanced_protocol::HostToReader::_deserialize(&buffer).unwrap();
+                buffer.clear();
+                addressed.push(request.com_adr);
+                if request.com_adr != com_adr && request.com_adr != BROADCAST_COM_ADR {
+                    continue;
+                }
+                let (status, data) = reader.handle(request.control_byte, &request.data);
+                let mut response = advanced_protocol::ReaderToHost::new(
+                    0,
+                    com_adr,
+                    request.control_byte,
+                    status as u8,
+                    &data,
+                    0,
+                );
+                port.write_all(&response._serialize()).unwrap();
+            }
+            addressed
+        })
+    }
+
+    fn options(bus_address: Option<u8>) -> SerialOptions {
+        SerialOptions {
+            read_timeout_ms: 100,
+            bus_address,
+            ..SerialOptions::default()
+        }
+    }
+
+    #[test]
+    fn inventory_over_a_pty() {
+        let (host, reader) = serialport::TTYPort::pair().unwrap();
+        let reader = spawn_reader(reader, 0x00);
+        let mut connection =
+            SerialConnection::with_port(Box::new(host), Framing::Advanced, &options(None)).unwrap();
+        assert!(connection.reader_info().is_some());
+
+        let command = Inventory {
+            device_required: true,
+        };
+        let response = connection
+            .send_command(command.request(BROADCAST_COM_ADR))
+            .unwrap();
+        assert_eq!(1, command.parse_response(&response).unwrap().len());
+        drop(connection);
+        assert!(reader
+            .join()
+            .unwrap()
+            .iter()
+            .all(|com_adr| *com_adr == 0xFF));
+    }
+
+    #[test]
+    fn address_the_reader_on_a_bus() {
+        let (host, reader) = serialport::TTYPort::pair().unwrap();
+        let reader = spawn_reader(reader, 0x02);
+        let mut connection =
+            SerialConnection::with_port(Box::new(host), Framing::Advanced, &options(Some(0x02)))
+                .unwrap();
+        assert!(connection.reader_info().is_some());
+
+        // A reader at another address on the bus never answers
+        let command = RfOnOff { rf: 1 };
+        let err = connection.send_command(command.request(0x03)).unwrap_err();
+        assert_eq!("No response from reader after 5 attempts", err.to_string());
+        drop(connection);
+        let addressed = reader.join().unwrap();
+        assert_eq!(vec![0x02, 0x02, 0x03, 0x03, 0x03, 0x03, 0x03], addressed);
+    }
+}
@@ -0,0 +1,513 @@
+use crate::conn::common::*;
+use crate::error::*;
+use crate::obid::*;
+use byteorder::{BigEndian, ByteOrder};
+use std::collections::HashMap;
+
+/// A transponder in the antenna field of a simulated reader
+#[derive(Debug, Clone, PartialEq)]
+pub struct VirtualTransponder {
+    pub uid: [u8; 8],
+    pub tr_type: u8,
+    pub dsfid: u8,
+    pub block_size: u8,
+    pub memory: std::vec::Vec<u8>,
+}
+
+impl VirtualTransponder {
+    /// An ISO 15693 transponder with 64 blocks of 4 bytes
+    pub fn new(uid: [u8; 8]) -> VirtualTransponder {
+        VirtualTransponder {
+            uid,
+            tr_type: 0x03,
+            dsfid: 0x00,
+            block_size: 4,
+            memory: vec![0; 64 * 4],
+        }
+    }
+
+    /// The bytes of the blocks, or None if they are outside of the memory
+    fn blocks(self: &Self, db_adr: u8, db_n: u8) -> Option<std::ops::Range<usize>> {
+        let begin = db_adr as usize * self.block_size as usize;
+        let end = begin + db_n as usize * self.block_size as usize;
+        if end <= self.memory.len() {
+            Some(begin..end)
+        } else {
+            None
+        }
+    }
+}
+
+/// Parses comma separated hex UIDs, such as E004010012345678,E004010087654321
+impl std::str::FromStr for VirtualTransponder {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let bytes = hex::decode(s).map_err(|err| format!("Invalid UID {}: {}", s, err))?;
+        if bytes.len() != 8 {
+            return Err(format!("Expected an 8 byte UID but found {}", s));
+        }
+        let mut uid = [0u8; 8];
+        uid.copy_from_slice(&bytes);
+        Ok(VirtualTransponder::new(uid))
+    }
+}
+
+/// The state of an emulated Feig reader
+#[derive(Debug, Clone)]
+pub struct SimulatedReader {
+    pub com_adr: u8,
+    pub transponders: std::vec::Vec<VirtualTransponder>,
+    pub rf_on: bool,
+    pub resets: u32,
+    pub software_version: SoftwareVersion,
+    pub device_id: u32,
+    /// CFG blocks of [0x80] and [0x81]
+    pub ram: HashMap<u8, [u8; CFG_BLOCK_SIZE]>,
+    pub eeprom: HashMap<u8, [u8; CFG_BLOCK_SIZE]>,
+    /// CFG blocks of [0x8B] by DEVICE, BANK, EEPROM and CFG-ADR
+    pub advanced_config: HashMap<(u8, u8, bool, u16), std::vec::Vec<u8>>,
+    /// How many transponders fit into the response to an inventory, where the rest follow with MORE
+    pub inventory_page: usize,
+    inventory_next: usize,
+}
+
+impl SimulatedReader {
+    pub fn new(transponders: std::vec::Vec<VirtualTransponder>) -> SimulatedReader {
+        SimulatedReader {
+            com_adr: 0x00,
+            transponders,
+            rf_on: true,
+            resets: 0,
+            software_version: SoftwareVersion {
+                sw_rev: 0x0100,
+                d_rev: 0x00,
+                hw_type: 0x00,
+                sw_type: 0x00,
+                tr_type: 0x0008,
+                rx_buf: 0x0400,
+                tx_buf: 0x0400,
+            },
+            device_id: 0x0000_0000,
+            ram: HashMap::new(),
+            eeprom: HashMap::new(),
+            advanced_config: HashMap::new(),
+            inventory_page: 24,
+            inventory_next: 0,
+        }
+    }
+
+    /// Execute the request and return the STATUS and DATA of the response
+    pub fn handle(self: &mut Self, control_byte: u8, data: &[u8]) -> (Status, std::vec::Vec<u8>) {
+        match (control_byte, data) {
+            (0xB0, [0x01, mode]) => self.inventory(mode & 0b1000_0000 != 0),
+            (0xB0, [0x23, _, uid @ .., db_adr, db_n]) if uid.len() == 8 => {
+                self.read_blocks(uid, *db_adr, *db_n)
+            }
+            (0xB0, [0x24, _, rest @ ..]) if rest.len() >= 11 => {
+                self.write_blocks(&rest[..8], rest[8], rest[9], rest[10], &rest[11..])
+            }
+            (0x80, [cfg_adr]) => {
+                let block = self.config(*cfg_adr).get(&(cfg_adr & 0b0011_1111));
+                (Status::Ok, block.copied().unwrap_or_default().to_vec())
+            }
+            (0x81, [cfg_adr, block @ ..]) if block.len() == CFG_BLOCK_SIZE => {
+                let mut cfg = [0u8; CFG_BLOCK_SIZE];
+                cfg.copy_from_slice(block);
+                self.config(*cfg_adr).insert(cfg_adr & 0b0011_1111, cfg);
+                (Status::Ok, vec![])
+            }
+            (0x8B, [device, bank, mode, cfg_n, cfg_size, blocks @ ..]) => {
+                self.write_advanced_config(*device, *bank, *mode, *cfg_n, *cfg_size, blocks)
+            }
+            (0x64, [_]) => {
+                // The configuration in EEPROM takes effect after a reset
+                self.ram = self.eeprom.clone();
+                self.rf_on = true;
+                self.resets += 1;
+                (Status::Ok, vec![])
+            }
+            (0x6A, [rf]) => {
+                self.rf_on = rf & 0b0000_0001 != 0;
+                (Status::Ok, vec![])
+            }
+            (0x66, [0x00]) => {
+                let version = &self.software_version;
+                let mut data = vec![0u8; 11];
+                BigEndian::write_u16(&mut data[0..2], version.sw_rev);
+                data[2] = version.d_rev;
+                data[3] = version.hw_type;
+                data[4] = version.sw_type;
+                BigEndian::write_u16(&mut data[5..7], version.tr_type);
+                BigEndian::write_u16(&mut data[7..9], version.rx_buf);
+                BigEndian::write_u16(&mut data[9..11], version.tx_buf);
+                (Status::Ok, data)
+            }
+            (0x66, [0x80]) => {
+                let mut data = vec![0u8; 4];
+                BigEndian::write_u32(&mut data, self.device_id);
+                (Status::Ok, data)
+            }
+            (0x6E, [0x01]) => (Status::Ok, vec![0x00, 0x00]),
+            (0xB0, _)
+            | (0x80, _)
+            | (0x81, _)
+            | (0x8B, _)
+            | (0x64, _)
+            | (0x6A, _)
+            | (0x66, _)
+            | (0x6E, _) => (Status::LengthError, vec![]),
+            _ => (Status::UnknownCommand, vec![]),
+        }
+    }
+
+    fn config(self: &mut Self, cfg_adr: u8) -> &mut HashMap<u8, [u8; CFG_BLOCK_SIZE]> {
+        if cfg_adr & 0b1000_0000 != 0 {
+            &mut self.eeprom
+        } else {
+            &mut self.ram
+        }
+    }
+
+    /// A page of the transponders, which continues after the last page if MORE is set
+    fn inventory(self: &mut Self, more: bool) -> (Status, std::vec::Vec<u8>) {
+        let begin = if more { self.inventory_next } else { 0 };
+        if !self.rf_on || begin >= self.transponders.len() {
+            return (Status::NoTransponder, vec![]);
+        }
+        let end = self
+            .transponders
+            .len()
+            .min(begin + self.inventory_page.max(1));
+        let mut data = vec![(end - begin) as u8];
+        for transponder in self.transponders[begin..end].iter() {
+            data.push(transponder.tr_type);
+            data.push(transponder.dsfid);
+            data.extend_from_slice(&transponder.uid);
+        }
+        self.inventory_next = end;
+        if end < self.transponders.len() {
+            (Status::MoreData, data)
+        } else {
+            (Status::Ok, data)
+        }
+    }
+
+    fn transponder(self: &mut Self, uid: &[u8]) -> Option<&mut VirtualTransponder> {
+        if !self.rf_on {
+            return None;
+        }
+        self.transponders
+            .iter_mut()
+            .find(|transponder| transponder.uid == uid)
+    }
+
+    fn read_blocks(
+        self: &mut Self,
+        uid: &[u8],
+        db_adr: u8,
+        db_n: u8,
+    ) -> (Status, std::vec::Vec<u8>) {
+        let transponder = match self.transponder(uid) {
+            Some(transponder) => transponder,
+            None => return (Status::NoTransponder, vec![]),
+        };
+        let range = match transponder.blocks(db_adr, db_n) {
+            Some(range) => range,
+            None => return (Status::AddressError, vec![]),
+        };
+        let mut data = vec![db_n, transponder.block_size];
+        for block in transponder.memory[range].chunks(transponder.block_size as usize) {
+            data.push(0x00); // Security status
+            data.extend_from_slice(block);
+        }
+        (Status::Ok, data)
+    }
+
+    fn write_blocks(
+        self: &mut Self,
+        uid: &[u8],
+        db_adr: u8,
+        db_n: u8,
+        db_size: u8,
+        data: &[u8],
+    ) -> (Status, std::vec::Vec<u8>) {
+        let transponder = match self.transponder(uid) {
+            Some(transponder) => transponder,
+            None => return (Status::NoTransponder, vec![]),
+        };
+        if db_size != transponder.block_size || data.len() != db_n as usize * db_size as usize {
+            return (Status::LengthError, vec![]);
+        }
+        match transponder.blocks(db_adr, db_n) {
+            Some(range) => {
+                transponder.memory[range].copy_from_slice(data);
+                (Status::Ok, vec![])
+            }
+            None => (Status::AddressError, vec![]),
+        }
+    }
+
+    fn write_advanced_config(
+        self: &mut Self,
+        device: u8,
+        bank: u8,
+        mode: u8,
+        cfg_n: u8,
+        cfg_size: u8,
+        blocks: &[u8],
+    ) -> (Status, std::vec::Vec<u8>) {
+        let stride = 2 + cfg_size as usize;
+        if blocks.len() != cfg_n as usize * stride {
+            return (Status::LengthError, vec![]);
+        }
+        let eeprom = mode & 0b0000_0001 != 0;
+        for block in blocks.chunks(stride) {
+            let cfg_adr = BigEndian::read_u16(&block[0..2]);
+            self.advanced_config
+                .insert((device, bank, eeprom, cfg_adr), block[2..].to_vec());
+        }
+        (Status::Ok, vec![])
+    }
+}
+
+/// A connection to an emulated Feig reader, so protocols can run without hardware
+pub struct SimulatedReaderConnection {
+    framing: Framing,
+    reader: SimulatedReader,
+    reader_info: Option<ReaderInfo>,
+}
+
+impl<'a> Connection<'a> for SimulatedReaderConnection {
+    fn send_command(
+        self: &mut Self,
+        serial_message: advanced_protocol::HostToReader,
+    ) -> Result<advanced_protocol::ReaderToHost> {
+        let mut serial_message = serial_message;
+        let msg = self.framing.serialize(&mut serial_message);
+        log::debug!("Sent msg: {:?}", msg);
+
+        if serial_message.com_adr != BROADCAST_COM_ADR
+            && serial_message.com_adr != self.reader.com_adr
+        {
+            return Err(InternalError::from(format!(
+                "No simulated reader at COM-ADR {:#04X}",
+                serial_message.com_adr
+            )));
+        }
+
+        let (status, data) = self
+            .reader
+            .handle(serial_message.control_byte, serial_message.data.as_slice());
+        if serial_message.device_required && status == Status::NoTransponder {
+            return Err(InternalError::from(
+                "Failed to communicate with device in antenna",
+            ));
+        }
+
+        let response = advanced_protocol::ReaderToHost::new(
+            (data.len() + 8) as u16,
+            self.reader.com_adr,
+            serial_message.control_byte,
+            status as u8,
+            data.as_slice(),
+            0,
+        );
+        log::debug!("Recieved response: {:?}", response);
+        Ok(response)
+    }
+
+    fn reader_info(self: &Self) -> Option<&ReaderInfo> {
+        self.reader_info.as_ref()
+    }
+}
+
+impl SimulatedReaderConnection {
+    pub fn new(framing: Framing, reader: SimulatedReader) -> SimulatedReaderConnection {
+        let mut connection = SimulatedReaderConnection {
+            framing,
+            reader,
+            reader_info: None,
+        };
+        connection.reader_info = query_reader_info(&mut connection);
+        connection
+    }
+}
+
+pub struct SimulatedContext {
+    pub framing: Framing,
+    pub transponders: std::vec::Vec<VirtualTransponder>,
+}
+
+impl<'a> Context<'a> for SimulatedContext {
+    fn connection(self: &Self) -> Result<Box<dyn Connection<'a> + 'a>> {
+        let reader = SimulatedReader::new(self.transponders.clone());
+        Ok(Box::new(SimulatedReaderConnection::new(
+            self.framing,
+            reader,
+        )))
+    }
+}
+
+impl SimulatedContext {
+    /// A reader with one transponder in its antenna field
+    pub fn new(framing: Framing) -> SimulatedContext {
+        SimulatedContext::with_transponders(
+            framing,
+            vec![VirtualTransponder::new([
+                0xE0, 0x04, 0x01, 0x00, 0x00, 0x00, 0x00, 0x01,
+            ])],
+        )
+    }
+
+    pub fn with_transponders(
+        framing: Framing,
+        transponders: std::vec::Vec<VirtualTransponder>,
+    ) -> SimulatedContext {
+        SimulatedContext {
+            framing,
+            transponders,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connection() -> SimulatedReaderConnection {
+        let reader = SimulatedReader::new(vec![
+            VirtualTransponder::new([1, 2, 3, 4, 5, 6, 7, 8]),
+            VirtualTransponder::new([8, 7, 6, 5, 4, 3, 2, 1]),
+        ]);
+        SimulatedReaderConnection::new(Framing::Advanced, reader)
+    }
+
+    #[test]
+    fn inventory_pages_with_more_data() {
+        let mut reader = SimulatedReader::new(
+            (1..=5)
+                .map(|id| VirtualTransponder::new([0xE0, 0x04, 0, 0, 0, 0, 0, id]))
+                .collect(),
+        );
+        reader.inventory_page = 2;
+        let mut connection = SimulatedReaderConnection::new(Framing::Advanced, reader);
+
+        let inventory = Inventory {
+            device_required: true,
+        };
+        let mut response = connection
+            .send_command(inventory.request(BROADCAST_COM_ADR))
+            .unwrap();
+        let mut uids = vec![];
+        loop {
+            for transponder in inventory.parse_response(&response).unwrap() {
+                uids.push(transponder.uid[7]);
+            }
+            if Status::from(response.status) != Status::MoreData {
+                break;
+            }
+            response = connection
+                .send_command(InventoryMore {}.request(BROADCAST_COM_ADR))
+                .unwrap();
+        }
+        assert_eq!(vec![1, 2, 3, 4, 5], uids);
+    }
+
+    #[test]
+    fn inventory_finds_transponders() {
+        let mut connection = connection();
+        let transponders = execute(
+            &mut connection,
+            &Inventory {
+                device_required: true,
+            },
+        )
+        .unwrap();
+        assert_eq!(2, transponders.len());
+        assert_eq!(&[8, 7, 6, 5, 4, 3, 2, 1], transponders[1].uid.as_slice());
+
+        execute(&mut connection, &RfOnOff { rf: 0 }).unwrap();
+        assert!(execute(
+            &mut connection,
+            &Inventory {
+                device_required: true
+            }
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn write_then_read_blocks() {
+        let mut connection = connection();
+        let uid = [8, 7, 6, 5, 4, 3, 2, 1];
+        execute(
+            &mut connection,
+            &WriteMultipleBlocks {
+                uid,
+                db_adr: 1,
+                db_n: 2,
+                db_size: 4,
+                data: vec![1, 2, 3, 4, 5, 6, 7, 8],
+            },
+        )
+        .unwrap();
+
+        let blocks = execute(
+            &mut connection,
+            &ReadMultipleBlocks {
+                uid,
+                db_adr: 0,
+                db_n: 3,
+            },
+        )
+        .unwrap();
+        assert_eq!(vec![vec![0; 4], vec![1, 2, 3, 4], vec![5, 6, 7, 8]], blocks);
+
+        let blocks = execute(
+            &mut connection,
+            &ReadMultipleBlocks {
+                uid: [1, 2, 3, 4, 5, 6, 7, 8],
+                db_adr: 1,
+                db_n: 1,
+            },
+        )
+        .unwrap();
+        assert_eq!(vec![vec![0; 4]], blocks);
+    }
+
+    #[test]
+    fn configuration_takes_effect_after_reset() {
+        let mut connection = connection();
+        let mut cfg = [0u8; CFG_BLOCK_SIZE];
+        cfg[2] = 0x88;
+        execute(
+            &mut connection,
+            &WriteConfiguration {
+                location: ConfigLocation::Eeprom,
+                address: 3,
+                data: cfg,
+            },
+        )
+        .unwrap();
+
+        let read = ReadConfiguration {
+            location: ConfigLocation::Ram,
+            address: 3,
+        };
+        assert_eq!(
+            [0u8; CFG_BLOCK_SIZE],
+            execute(&mut connection, &read).unwrap()
+        );
+        execute(&mut connection, &SystemReset {}).unwrap();
+        assert_eq!(cfg, execute(&mut connection, &read).unwrap());
+    }
+
+    #[test]
+    fn reader_info_on_connect() {
+        let connection = connection();
+        let reader_info = connection.reader_info().unwrap();
+        assert_eq!(0x0100, reader_info.software_version.sw_rev);
+        assert_eq!(Some(0), reader_info.device_id);
+    }
+}
@@ -0,0 +1,391 @@
+use crate::conn::common::*;
+use crate::error::*;
+use crate::obid::*;
+
+use std::time::{Duration, Instant};
+
+/// How many requests in a row may go unanswered before the link counts as lost
+const MAX_UNANSWERED_REQUESTS: u32 = 3;
+
+/// A request that set up the reader, which has to be sent again to a reader that lost it
+struct StartupRequest {
+    /// Identifies the setting, so a later request for the same setting replaces this one
+    key: std::vec::Vec<u8>,
+    com_adr: u8,
+    control_byte: u8,
+    data: std::vec::Vec<u8>,
+}
+
+impl StartupRequest {
+    /// The settings a reader loses when it is power cycled: the RAM configuration and the RF field
+    fn from_request(serial_message: &advanced_protocol::HostToReader) -> Option<StartupRequest> {
+        let data = &serial_message.data;
+        let key = match serial_message.control_byte {
+            // [0x81] Write Configuration, where bit 7 of CFG-ADR selects the EEPROM
+            0x81 if !data.is_empty() && data[0] & 0b1000_0000 == 0 => vec![0x81, data[0]],
+            // [0x8B] Write Configuration (Advanced Protocol) with MODE 0x00 for the RAM
+            0x8B if data.len() >= 5 && data[2] == 0x00 => {
+                let block_size = data[4] as usize;
+                let mut key = vec![0x8B, data[0], data[1]];
+                for block in data[5..].chunks(2 + block_size) {
+                    key.extend_from_slice(&block[..block.len().min(2)]);
+                }
+                key
+            }
+            // [0x6A] RF On/Off
+            0x6A => vec![0x6A],
+            _ => return None,
+        };
+        Some(StartupRequest {
+            key,
+            com_adr: serial_message.com_adr,
+            control_byte: serial_message.control_byte,
+            data: data.clone(),
+        })
+    }
+
+    fn request(self: &Self) -> advanced_protocol::HostToReader {
+        advanced_protocol::HostToReader::new(
+            0,
+            self.com_adr,
+            self.control_byte,
+            self.data.as_slice(),
+            0,
+            false,
+        )
+    }
+}
+
+/**
+ * Keeps a connection to the reader of a context, so the protocol above it
+ * and its fabrics survive an unplugged cable or a dropped socket.
+ *
+ * A lost link fails the request that noticed it, and each later request
+ * tries to reconnect once the backoff since the last failed attempt has
+ * passed. Until then requests fail with ReaderUnavailable right away. A new
+ * connection gets the RAM configuration and RF state the host gave the old
+ * one before it serves requests again.
+ *
+ * A link that goes silent, such as a TCP peer that died without closing the
+ * socket, is lost once several requests in a row get no response. A single
+ * one is not, since no reader on a bus may have the COM-ADR of the request.
+ */
+pub struct SupervisedConnection<'a> {
+    context: Box<dyn Context<'a> + 'a>,
+    connection: Option<Box<dyn Connection<'a> + 'a>>,
+    reader_info: Option<ReaderInfo>,
+    startup: std::vec::Vec<StartupRequest>,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    backoff: Duration,
+    retry_at: Option<Instant>,
+    /// The requests in a row that got no response
+    unanswered: u32,
+}
+
+impl<'a> Connection<'a> for SupervisedConnection<'a> {
+    fn send_command(
+        self: &mut Self,
+        serial_message: advanced_protocol::HostToReader,
+    ) -> Result<advanced_protocol::ReaderToHost> {
+        self.ensure_connected()?;
+        let connection = match self.connection.as_mut() {
+            Some(connection) => connection,
+            None => {
+                return Err(InternalError::ReaderUnavailable(String::from(
+                    "not connected",
+                )))
+            }
+        };
+
+        let startup = StartupRequest::from_request(&serial_message);
+        let reset = serial_message.control_byte == 0x64;
+        let result = connection.send_command(serial_message);
+        match result {
+            Err(InternalError::NoResponse(_)) => self.unanswered += 1,
+            _ => self.unanswered = 0,
+        }
+        match result {
+            Ok(response) => {
+                if Status::from(response.status) == Status::Ok {
+                    self.remember(startup, reset);
+                }
+                Ok(response)
+            }
+            Err(InternalError::LinkLost(err)) => Err(self.lose_link(err)),
+            Err(InternalError::NoResponse(err)) if self.unanswered >= MAX_UNANSWERED_REQUESTS => {
+                let err = format!(
+                    "{} requests in a row got no response: {}",
+                    self.unanswered, err
+                );
+                Err(self.lose_link(err))
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    fn reader_info(self: &Self) -> Option<&ReaderInfo> {
+        self.reader_info.as_ref()
+    }
+
+    /// Replace the connection right away, even while the link is up
+    fn reconnect(self: &mut Self) -> Result<()> {
+        self.connection = None;
+        self.backoff = self.initial_backoff;
+        self.retry_at = None;
+        self.ensure_connected()
+    }
+}
+
+impl<'a> SupervisedConnection<'a> {
+    /// Connect to the reader, failing if it can't be reached to begin with
+    pub fn new(context: Box<dyn Context<'a> + 'a>) -> Result<SupervisedConnection<'a>> {
+        SupervisedConnection::with_backoff(
+            context,
+            Duration::from_millis(100),
+            Duration::from_secs(10),
+        )
+    }
+
+    /// The backoff doubles after each failed attempt to reconnect, up to the max
+    pub fn with_backoff(
+        context: Box<dyn Context<'a> + 'a>,
+        initial_backoff: Duration,
+        max_backoff: Duration,
+    ) -> Result<SupervisedConnection<'a>> {
+        let connection = context.connection()?;
+        Ok(SupervisedConnection {
+            reader_info: connection.reader_info().cloned(),
+            context,
+            connection: Some(connection),
+            startup: vec![],
+            initial_backoff,
+            max_backoff,
+            backoff: initial_backoff,
+            retry_at: None,
+            unanswered: 0,
+        })
+    }
+
+    pub fn is_connected(self: &Self) -> bool {
+        self.connection.is_some()
+    }
+
+    /// Replace the earlier request for the same setting, where a reset returns to the EEPROM configuration
+    fn remember(self: &mut Self, startup: Option<StartupRequest>, reset: bool) {
+        if reset {
+            self.startup.clear();
+        }
+        if let Some(startup) = startup {
+            match self.startup.iter().position(|s| s.key == startup.key) {
+                Some(index) => self.startup[index] = startup,
+                None => self.startup.push(startup),
+            }
+        }
+    }
+
+    /// Drop the connection, so the next request reconnects to the reader
+    fn lose_link(self: &mut Self, err: String) -> InternalError {
+        log::error!("Lost the link to the reader: {}", err);
+        self.connection = None;
+        self.backoff = self.initial_backoff;
+        self.retry_at = Some(Instant::now());
+        self.unanswered = 0;
+        InternalError::ReaderUnavailable(format!("lost the link to the reader: {}", err))
+    }
+
+    /// Connect again if the link is down and the backoff has passed
+    fn ensure_connected(self: &mut Self) -> Result<()> {
+        if self.connection.is_some() {
+            return Ok(());
+        }
+        if let Some(retry_at) = self.retry_at {
+            let now = Instant::now();
+            if now < retry_at {
+                return Err(InternalError::ReaderUnavailable(format!(
+                    "reconnecting in {} ms",
+                    (retry_at - now).as_millis()
+                )));
+            }
+        }
+
+        log::info!("Reconnecting to the reader ...");
+        match self.restart() {
+            Ok(connection) => {
+                log::info!(
+                    "Reconnected to the reader and restored {} settings",
+                    self.startup.len()
+                );
+                if let Some(reader_info) = connection.reader_info() {
+                    self.reader_info = Some(reader_info.clone());
+                }
+                self.connection = Some(connection);
+                self.backoff = self.initial_backoff;
+                self.retry_at = None;
+                Ok(())
+            }
+            Err(err) => {
+                let backoff = self.backoff;
+                self.retry_at = Some(Instant::now() + backoff);
+                self.backoff = (backoff * 2).min(self.max_backoff);
+                log::warn!(
+                    "Failed to reconnect to the reader, trying again in {} ms: {}",
+                    backoff.as_millis(),
+                    err
+                );
+                Err(InternalError::ReaderUnavailable(format!(
+                    "failed to reconnect, trying again in {} ms: {}",
+                    backoff.as_millis(),
+                    err
+                )))
+            }
+        }
+    }
+
+    /// A new connection with the settings of the old one
+    fn restart(self: &Self) -> Result<Box<dyn Connection<'a> + 'a>> {
+        let mut connection = self.context.connection()?;
+        for startup in self.startup.iter() {
+            let response = connection.send_command(startup.request())?;
+            check_status(&response)?;
+        }
+        Ok(connection)
+    }
+}
+
+#[cfg(all(test, feature = "mock"))]
+mod tests {
+    use super::*;
+    use crate::conn::mock::{Fault, FaultPlan, FaultyContext};
+    use crate::conn::simulated::SimulatedContext;
+
+    /// Refuses to connect until it has failed the given number of times
+    struct FlakyContext {
+        failures: std::cell::Cell<u32>,
+    }
+
+    impl<'a> Context<'a> for FlakyContext {
+        fn connection(self: &Self) -> Result<Box<dyn Connection<'a> + 'a>> {
+            let failures = self.failures.get();
+            if failures == 0 {
+                return SimulatedContext::new(Framing::Advanced).connection();
+            }
+            self.failures.set(failures - 1);
+            Err(InternalError::from("Reader unplugged"))
+        }
+    }
+
+    fn read_ram(connection: &mut SupervisedConnection<'static>, address: u8) -> Result<[u8; 14]> {
+        execute(
+            connection,
+            &ReadConfiguration {
+                location: ConfigLocation::Ram,
+                address,
+            },
+        )
+    }
+
+    #[test]
+    fn restore_settings_after_reconnecting() {
+        let reader = Box::new(SimulatedContext::new(Framing::Advanced));
+        let context = FaultyContext::new(
+            reader,
+            Framing::Advanced,
+            FaultPlan::script(vec![None, None, Some(Fault::Disconnect)]),
+        );
+        let mut connection = SupervisedConnection::new(Box::new(context)).unwrap();
+        let written = [7u8; 14];
+        let write = WriteConfiguration {
+            location: ConfigLocation::Ram,
+            address: 3,
+            data: written,
+        };
+        execute(&mut connection, &write).unwrap();
+        execute(&mut connection, &RfOnOff { rf: 0 }).unwrap();
+
+        match read_ram(&mut connection, 3) {
+            Err(InternalError::ReaderUnavailable(_)) => {}
+            other => panic!("Expected the reader to be unavailable but got {:?}", other),
+        }
+        assert!(!connection.is_connected());
+        assert_eq!(written, read_ram(&mut connection, 3).unwrap());
+        assert!(connection.is_connected());
+        assert!(connection.reader_info().is_some());
+
+        // The RF field is still off, so no transponder answers
+        let inventory = Inventory {
+            device_required: true,
+        };
+        assert!(execute(&mut connection, &inventory).is_err());
+        assert!(connection.is_connected());
+    }
+
+    #[test]
+    fn keep_the_link_without_a_response() {
+        let reader = Box::new(SimulatedContext::new(Framing::Advanced));
+        let faults = FaultPlan::script(vec![Some(Fault::DropReply); 5]);
+        let context = FaultyContext::new(reader, Framing::Advanced, faults);
+        let mut connection = SupervisedConnection::new(Box::new(context)).unwrap();
+
+        let err = read_ram(&mut connection, 3).unwrap_err();
+        assert_eq!("No response from reader after 5 attempts", err.to_string());
+        assert!(connection.is_connected());
+        assert!(read_ram(&mut connection, 3).is_ok());
+    }
+
+    #[test]
+    fn reconnect_to_a_reader_that_went_silent() {
+        let reader = Box::new(SimulatedContext::new(Framing::Advanced));
+        let faults = FaultPlan::script(vec![Some(Fault::DropReply); 15]);
+        let context = FaultyContext::new(reader, Framing::Advanced, faults);
+        let mut connection = SupervisedConnection::new(Box::new(context)).unwrap();
+
+        for _ in 1..MAX_UNANSWERED_REQUESTS {
+            match read_ram(&mut connection, 3) {
+                Err(InternalError::NoResponse(_)) => {}
+                other => panic!("Expected no response but got {:?}", other),
+            }
+            assert!(connection.is_connected());
+        }
+        match read_ram(&mut connection, 3) {
+            Err(InternalError::ReaderUnavailable(message)) => {
+                assert!(message.contains("3 requests in a row got no response"))
+            }
+            other => panic!("Expected the reader to be unavailable but got {:?}", other),
+        }
+        assert!(!connection.is_connected());
+        assert!(read_ram(&mut connection, 3).is_ok());
+        assert!(connection.is_connected());
+    }
+
+    #[test]
+    fn back_off_while_the_reader_is_unplugged() {
+        let context = FlakyContext {
+            failures: std::cell::Cell::new(0),
+        };
+        let mut connection = SupervisedConnection::with_backoff(
+            Box::new(context),
+            Duration::from_millis(20),
+            Duration::from_millis(40),
+        )
+        .unwrap();
+        connection.connection = None;
+        connection.retry_at = Some(Instant::now());
+        connection.context = Box::new(FlakyContext {
+            failures: std::cell::Cell::new(3),
+        });
+
+        // Each failed attempt waits longer before the next, up to the max backoff
+        for backoff in [20, 40, 40].iter() {
+            let err = read_ram(&mut connection, 1).unwrap_err();
+            assert!(err.to_string().contains("failed to reconnect"));
+            let err = read_ram(&mut connection, 1).unwrap_err();
+            assert!(err
+                .to_string()
+                .starts_with("Reader unavailable: reconnecting in"));
+            std::thread::sleep(Duration::from_millis(backoff + 5));
+        }
+        assert!(read_ram(&mut connection, 1).is_ok());
+        assert!(connection.is_connected());
+    }
+}
@@ -7,8 +7,10 @@ use crate::obid::*;
 
 pub struct UsbConnection<'a> {
     state: AntennaState,
+    reader_info: Option<ReaderInfo>,
     device_handle: libusb::DeviceHandle<'a>,
     response_message_buffer: std::vec::Vec<u8>,
+    decoder: ObidFrameDecoder,
 }
 
 impl<'a> Connection<'a> for UsbConnection<'a> {
@@ -16,36 +18,65 @@ impl<'a> Connection<'a> for UsbConnection<'a> {
         self: &mut Self,
         serial_message: advanced_protocol::HostToReader,
     ) -> Result<advanced_protocol::ReaderToHost> {
-        // Marshal the serial command
-        let mut serial_message = serial_message;
-        let msg = serial_message.serialize();
-        let mut attempts = 0;
+        transceive(self, serial_message)
+    }
+
+    fn reader_info(self: &Self) -> Option<&ReaderInfo> {
+        self.reader_info.as_ref()
+    }
+}
+
+impl<'a> Transport for UsbConnection<'a> {
+    fn antenna_state(self: &Self) -> &AntennaState {
+        &self.state
+    }
+
+    fn write_request(
+        self: &mut Self,
+        _request: &advanced_protocol::HostToReader,
+        msg: &[u8],
+    ) -> Result<()> {
+        std::thread::sleep(MESSAGE_GAP);
+        if self.decoder.buffered() > 0 {
+            log::warn!(
+                "Discarding {} stale bytes before sending Serial Command",
+                self.decoder.buffered()
+            );
+            self.decoder.clear();
+        }
+
+        // Send the command to the Feig reader
+        match self
+            .device_handle
+            .write_bulk(2, msg, std::time::Duration::from_millis(50))
+        {
+            Ok(bytes_written) => {
+                log::debug!(
+                    "Sent Serial Command with {} bytes: {}",
+                    bytes_written,
+                    hex::encode(msg)
+                );
+                Ok(())
+            }
+            Err(err) => {
+                log::error!("Failed Serial Command Send: {}", err.to_string());
+                Err(InternalError::LinkLost(err.to_string()))
+            }
+        }
+    }
+
+    /// Read bulk transfers until a complete response frame has arrived
+    fn read_response(self: &mut Self) -> Result<Option<advanced_protocol::ReaderToHost>> {
         loop {
-            // Documented not less than 5 milliseconds between messages
-            std::thread::sleep(std::time::Duration::from_millis(6));
-
-            // Send the command to the Feig reader
-            match self.device_handle.write_bulk(
-                2,
-                msg.as_slice(),
-                std::time::Duration::from_millis(50),
-            ) {
-                Ok(bytes_written) => {
-                    log::debug!(
-                        "Sent Serial Command with {} bytes: {}",
-                        bytes_written,
-                        hex::encode(&msg)
-                    );
-                }
+            match self.decoder.next_frame() {
+                Ok(Some(response)) => return Ok(Some(response)),
+                Ok(None) => {}
                 Err(err) => {
-                    log::error!("Failed Serial Command Send: {}", err.to_string());
-                    return Err(InternalError::from(err));
+                    log::warn!("Discarding corrupt response: {}", err);
+                    return Ok(None);
                 }
             }
 
-            // Read the response to the command
-            attempts += 1;
-            let response_message_size;
             match self.device_handle.read_bulk(
                 129,
                 self.response_message_buffer.as_mut_slice(),
@@ -57,61 +88,20 @@ impl<'a> Connection<'a> for UsbConnection<'a> {
                         bytes_read,
                         hex::encode(&self.response_message_buffer[..bytes_read])
                     );
-                    response_message_size = bytes_read;
+                    self.decoder
+                        .push(&self.response_message_buffer[..bytes_read]);
                 }
                 Err(err) => {
                     log::error!("Failed Serial Command Read: {}", err.to_string());
-                    continue;
+                    return Ok(None);
                 }
             }
-
-            // Interpret the response
-            let response = advanced_protocol::ReaderToHost::deserialize(
-                &self.response_message_buffer[..response_message_size],
-            )?;
-            log::trace!(
-                "Interpretting response for attempt {}: {:#?}",
-                attempts,
-                response
-            );
-
-            // Check for errors
-            let status = Status::from(response.status);
-            if status == Status::RFWarning {
-                /*
-                 * A monitor is continusously checking the RF hardware and
-                 * if an error occurs the Reader answers every command with
-                 * the error code 0x84
-                 */
-                let error_message = String::from(
-                    "Generic Antenna Error: RF hardware monitor error status code 0x84",
-                );
-                log::error!("{}", error_message);
-                return Err(InternalError::from(error_message));
-            } else if serial_message.device_required && status == Status::NoTransponder {
-                log::error!(
-                    "No devices found on attempt {} of {}",
-                    attempts,
-                    self.state.max_attempts
-                );
-                if attempts >= self.state.max_attempts {
-                    return Err(InternalError::from(
-                        "Failed to communicate with device in antenna",
-                    ));
-                } else {
-                    std::thread::sleep(std::time::Duration::from_millis(8 * attempts as u64));
-                    continue;
-                }
-            }
-
-            // All done
-            return Ok(response);
         }
     }
 }
 
 impl<'a> UsbConnection<'a> {
-    pub fn new(ctx: &'a UsbContext<'a>) -> Result<UsbConnection<'a>> {
+    pub fn new(ctx: &UsbContext<'a>) -> Result<UsbConnection<'a>> {
         for _ in 0..10 {
             for device in ctx.ctx.devices()?.iter() {
                 let device_desc = device.device_descriptor()?;
@@ -123,7 +113,7 @@ impl<'a> UsbConnection<'a> {
                     device_desc.product_id()
                 );
 
-                if device_desc.vendor_id() == 2737 {
+                if device_desc.vendor_id() == 2737 && ctx.device.is_at(&device) {
                     log::debug!(
                         "Found Obid/Feig USB Device || Bus {:03} Device {:03} ID {} : {}",
                         device.bus_number(),
@@ -133,6 +123,9 @@ impl<'a> UsbConnection<'a> {
                     );
 
                     let mut device_handle = device.open()?;
+                    if !ctx.device.has_serial_number(&device_handle, &device_desc) {
+                        continue;
+                    }
                     device_handle.reset()?;
                     for interface in device.active_config_descriptor()?.interfaces() {
                         let interface_number = interface.number();
@@ -158,7 +151,7 @@ impl<'a> UsbConnection<'a> {
                         }
                     }
 
-                    return Ok(UsbConnection {
+                    let mut connection = UsbConnection {
                         device_handle: device_handle,
                         state: AntennaState {
                             antenna_id: None,
@@ -171,32 +164,117 @@ impl<'a> UsbConnection<'a> {
                             act_block_count: None,
 
                             max_attempts: 5,
+                            framing: ctx.framing,
                         },
                         response_message_buffer: vec![0; 1024 * 1024 * 64],
-                    });
+                        decoder: ObidFrameDecoder::new(ctx.framing),
+                        reader_info: None,
+                    };
+                    connection.reader_info = query_reader_info(&mut connection);
+                    return Ok(connection);
                 }
             }
 
-            log::error!("No matching USB device found ...");
+            log::error!("No matching USB device found for {:?} ...", ctx.device);
             std::thread::sleep(std::time::Duration::from_secs(1));
         }
 
-        return Err(InternalError::from("No matching USB device found"));
+        return Err(InternalError::from(format!(
+            "No matching USB device found for {:?}",
+            ctx.device
+        )));
+    }
+}
+
+/// Which of the attached Obid/Feig USB devices to open
+#[derive(Debug, Clone, PartialEq)]
+pub enum UsbDevice {
+    /// The first device found
+    Any,
+    BusAddress {
+        bus: u8,
+        address: u8,
+    },
+    SerialNumber(String),
+}
+
+impl UsbDevice {
+    fn is_at(self: &Self, device: &libusb::Device) -> bool {
+        match self {
+            UsbDevice::BusAddress { bus, address } => {
+                device.bus_number() == *bus && device.address() == *address
+            }
+            _ => true,
+        }
+    }
+
+    fn has_serial_number(
+        self: &Self,
+        device_handle: &libusb::DeviceHandle,
+        device_desc: &libusb::DeviceDescriptor,
+    ) -> bool {
+        match self {
+            UsbDevice::SerialNumber(serial_number) => {
+                match device_handle.read_serial_number_string_ascii(device_desc) {
+                    Ok(found) => {
+                        log::debug!("Found USB device with serial number {}", found);
+                        found == *serial_number
+                    }
+                    Err(err) => {
+                        log::warn!("Failed to read USB serial number: {}", err);
+                        false
+                    }
+                }
+            }
+            _ => true,
+        }
+    }
+}
+
+/// Parses `<bus>:<address>` or a serial number, where empty is any device
+impl std::str::FromStr for UsbDevice {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Ok(UsbDevice::Any);
+        }
+        let parts: std::vec::Vec<&str> = s.split(':').collect();
+        if let [bus, address] = parts.as_slice() {
+            if let (Ok(bus), Ok(address)) = (bus.parse(), address.parse()) {
+                return Ok(UsbDevice::BusAddress { bus, address });
+            }
+        }
+        Ok(UsbDevice::SerialNumber(String::from(s)))
     }
 }
 
 pub struct UsbContext<'a> {
     pub ctx: &'a libusb::Context,
+    pub framing: Framing,
+    pub device: UsbDevice,
 }
 
 impl<'a> UsbContext<'a> {
-    pub fn new(ctx: &'a libusb::Context) -> Result<UsbContext<'a>> {
-        Ok(UsbContext { ctx })
+    pub fn new(ctx: &'a libusb::Context, framing: Framing) -> Result<UsbContext<'a>> {
+        UsbContext::for_device(ctx, framing, UsbDevice::Any)
+    }
+
+    pub fn for_device(
+        ctx: &'a libusb::Context,
+        framing: Framing,
+        device: UsbDevice,
+    ) -> Result<UsbContext<'a>> {
+        Ok(UsbContext {
+            ctx,
+            framing,
+            device,
+        })
     }
 }
 
 impl<'a> Context<'a> for UsbContext<'a> {
-    fn connection(self: &'a Self) -> Result<Box<dyn Connection<'a> + 'a>> {
+    fn connection(self: &Self) -> Result<Box<dyn Connection<'a> + 'a>> {
         Ok(Box::new(UsbConnection::new(self)?))
     }
 }
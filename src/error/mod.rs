@@ -1,3 +1,4 @@
+use crate::obid::ReaderDiagnostics;
 use std::fmt;
 use std::num::{ParseFloatError, ParseIntError};
 use std::string::FromUtf8Error;
@@ -15,8 +16,17 @@ pub enum InternalError {
     SerdeError(serde_json::error::Error),
     #[cfg(feature = "usb")]
     UsbError(libusb::Error),
+    #[cfg(feature = "serial")]
+    SerialError(serialport::Error),
     ZmqError(zmq::Error),
     HexError(hex::FromHexError),
+    RFWarning(Option<ReaderDiagnostics>),
+    /// The link to the reader broke, such as an unplugged cable or a closed socket
+    LinkLost(String),
+    /// No reader answered the request, which a silent link and a missing COM-ADR on a bus look alike
+    NoResponse(String),
+    /// The link to the reader is down while it is being reconnected
+    ReaderUnavailable(String),
 }
 
 impl fmt::Display for InternalError {
@@ -49,6 +59,11 @@ impl fmt::Display for InternalError {
                 log::error!("Encountered usb error: {}", e);
                 e.fmt(f)
             }
+            #[cfg(feature = "serial")]
+            InternalError::SerialError(ref e) => {
+                log::error!("Encountered serial error: {}", e);
+                e.fmt(f)
+            }
             InternalError::ZmqError(ref e) => {
                 log::error!("Encountered zmq error: {}", e);
                 e.fmt(f)
@@ -57,6 +72,22 @@ impl fmt::Display for InternalError {
                 log::error!("Encountered hex error: {}", e);
                 e.fmt(f)
             }
+            InternalError::RFWarning(ref diagnostics) => {
+                write!(
+                    f,
+                    "Generic Antenna Error: RF hardware monitor error status code 0x84"
+                )?;
+                match diagnostics {
+                    Some(diagnostics) => write!(f, ": {}", diagnostics),
+                    None => Ok(()),
+                }
+            }
+            InternalError::LinkLost(ref e) => {
+                log::error!("Lost the link to the reader: {}", e);
+                e.fmt(f)
+            }
+            InternalError::NoResponse(ref e) => e.fmt(f),
+            InternalError::ReaderUnavailable(ref e) => write!(f, "Reader unavailable: {}", e),
         }
     }
 }
@@ -116,6 +147,13 @@ impl From<libusb::Error> for InternalError {
     }
 }
 
+#[cfg(feature = "serial")]
+impl From<serialport::Error> for InternalError {
+    fn from(err: serialport::Error) -> InternalError {
+        InternalError::SerialError(err)
+    }
+}
+
 impl From<zmq::Error> for InternalError {
     fn from(err: zmq::Error) -> InternalError {
         InternalError::ZmqError(err)
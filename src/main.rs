@@ -1,16 +1,200 @@
 use protocol_host_lib::conn::common::*;
 #[cfg(feature = "ethernet")]
-use protocol_host_lib::conn::ethernet::EthernetContext;
+use protocol_host_lib::conn::ethernet::{EthernetContext, EthernetOptions};
 #[cfg(feature = "mock")]
 use protocol_host_lib::conn::mock::MockContext;
+use protocol_host_lib::conn::record::{RecordingContext, ReplayContext};
+#[cfg(feature = "serial")]
+use protocol_host_lib::conn::serial::{SerialContext, SerialOptions, SerialParity};
+#[cfg(feature = "mock")]
+use protocol_host_lib::conn::simulated::{SimulatedContext, VirtualTransponder};
+use protocol_host_lib::conn::supervisor::SupervisedConnection;
 #[cfg(feature = "usb")]
 use protocol_host_lib::conn::usb::UsbContext;
 use protocol_host_lib::error::*;
 use protocol_host_lib::network::{client, common::*, server};
-use protocol_host_lib::protocol::common::CommandMessage;
+use protocol_host_lib::obid::Framing;
+use protocol_host_lib::protocol::common::{CommandMessage, TransponderInfo};
+use protocol_host_lib::protocol::registry::{default_protocol, ProtocolRegistry};
+use serde::Deserialize;
+
+/// The options of `start`, which may also be given by a JSON --config file
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct StartConfig {
+    protocol_impl: String,
+    conn_type: String,
+    readers: Vec<String>,
+    reader_addr: String,
+    framing: String,
+    com_adr: u8,
+    connect_timeout_ms: u64,
+    read_timeout_ms: u64,
+    write_timeout_ms: u64,
+    discover_subnet: Option<String>,
+    baud_rate: u32,
+    data_bits: u8,
+    parity: String,
+    stop_bits: u8,
+    record: Option<String>,
+    state_file: Option<String>,
+    protocol: String,
+    hostname: String,
+    port: i16,
+}
+
+impl Default for StartConfig {
+    fn default() -> Self {
+        StartConfig {
+            protocol_impl: String::from(default_protocol()),
+            conn_type: String::from(default_conn_type()),
+            readers: vec![],
+            reader_addr: String::from("192.168.10.10:10001"),
+            framing: String::from("advanced"),
+            com_adr: 255,
+            connect_timeout_ms: 2000,
+            read_timeout_ms: 1000,
+            write_timeout_ms: 1000,
+            discover_subnet: None,
+            baud_rate: 38400,
+            data_bits: 8,
+            parity: String::from("even"),
+            stop_bits: 1,
+            record: None,
+            state_file: None,
+            protocol: String::from("tcp"),
+            hostname: String::from("*"),
+            port: 5555,
+        }
+    }
+}
+
+impl StartConfig {
+    /// Read the --config file and then apply the arguments given on the command line
+    fn from_matches(matches: &clap::ArgMatches) -> Result<StartConfig> {
+        let mut config: StartConfig = match matches.value_of("config") {
+            Some(path) => {
+                let file = std::fs::File::open(path)?;
+                serde_json::from_reader(std::io::BufReader::new(file))?
+            }
+            None => StartConfig::default(),
+        };
+
+        let given = |name| match matches.occurrences_of(name) {
+            0 => None,
+            _ => matches.value_of(name),
+        };
+        if let Some(protocol_impl) = given("protocol_impl") {
+            config.protocol_impl = String::from(protocol_impl);
+        }
+        if let Some(conn_type) = given("conn_type") {
+            config.conn_type = String::from(conn_type);
+        }
+        if let Some(readers) = matches.values_of("reader") {
+            config.readers = readers.map(String::from).collect();
+        }
+        if let Some(reader_addr) = given("reader_addr") {
+            config.reader_addr = String::from(reader_addr);
+        }
+        if let Some(framing) = given("framing") {
+            config.framing = String::from(framing);
+        }
+        if let Some(com_adr) = given("com_adr") {
+            config.com_adr = com_adr.parse()?;
+        }
+        if let Some(timeout) = given("connect_timeout") {
+            config.connect_timeout_ms = timeout.parse()?;
+        }
+        if let Some(timeout) = given("read_timeout") {
+            config.read_timeout_ms = timeout.parse()?;
+        }
+        if let Some(timeout) = given("write_timeout") {
+            config.write_timeout_ms = timeout.parse()?;
+        }
+        if let Some(subnet) = given("discover_subnet") {
+            config.discover_subnet = Some(String::from(subnet));
+        }
+        if let Some(baud_rate) = given("baud_rate") {
+            config.baud_rate = baud_rate.parse()?;
+        }
+        if let Some(data_bits) = given("data_bits") {
+            config.data_bits = data_bits.parse()?;
+        }
+        if let Some(parity) = given("parity") {
+            config.parity = String::from(parity);
+        }
+        if let Some(stop_bits) = given("stop_bits") {
+            config.stop_bits = stop_bits.parse()?;
+        }
+        if let Some(record) = given("record") {
+            config.record = Some(String::from(record));
+        }
+        if let Some(state_file) = given("state_file") {
+            config.state_file = Some(String::from(state_file));
+        }
+        if let Some(protocol) = given("protocol") {
+            config.protocol = String::from(protocol);
+        }
+        if let Some(hostname) = given("hostname") {
+            config.hostname = String::from(hostname);
+        }
+        if let Some(port) = given("port") {
+            config.port = port.parse().expect("Expected a small integer for port");
+        }
+        Ok(config)
+    }
+
+    /// The named readers, where --conn-type is the only reader unless --reader is given
+    fn readers(self: &Self) -> Result<Vec<(String, String)>> {
+        if self.readers.is_empty() {
+            return Ok(vec![(String::from("default"), self.conn_type.clone())]);
+        }
+        self.readers
+            .iter()
+            .map(|reader| parse_reader(reader))
+            .collect()
+    }
+
+    /// The capture file of the reader, where each of several readers gets its own
+    fn record_path(self: &Self, name: &str, readers: usize) -> Option<std::path::PathBuf> {
+        self.record.as_ref().map(|record| {
+            if readers == 1 {
+                std::path::PathBuf::from(record)
+            } else {
+                std::path::PathBuf::from(format!("{}.{}", record, name))
+            }
+        })
+    }
+
+    /// The line settings of a serial reader, which is on an RS-485 bus if it has a bus address
+    #[cfg(feature = "serial")]
+    fn serial_options(self: &Self, bus_address: Option<u8>) -> Result<SerialOptions> {
+        let parity: SerialParity = self.parity.parse()?;
+        Ok(SerialOptions {
+            baud_rate: self.baud_rate,
+            data_bits: self.data_bits,
+            parity,
+            stop_bits: self.stop_bits,
+            read_timeout_ms: self.read_timeout_ms,
+            bus_address,
+        })
+    }
+
+    #[cfg(feature = "ethernet")]
+    fn ethernet_options(self: &Self) -> EthernetOptions {
+        EthernetOptions {
+            connect_timeout_ms: self.connect_timeout_ms,
+            read_timeout_ms: self.read_timeout_ms,
+            write_timeout_ms: self.write_timeout_ms,
+            discover_subnet: self.discover_subnet.clone(),
+        }
+    }
+}
 
-fn start_server<'a>(conn_type: &str, protocol: &str, hostname: &str, port: i16) -> Result<()> {
-    let endpoint = NetworkContext::get_endpoint(protocol, hostname, port);
+fn start_server(config: &StartConfig) -> Result<()> {
+    let endpoint = NetworkContext::get_endpoint(&config.protocol, &config.hostname, config.port);
+    let framing: Framing = config.framing.parse()?;
+    let readers = config.readers()?;
 
     // Create various contexts needed for hardware interaction
     #[allow(unused_variables)]
@@ -18,53 +202,174 @@ fn start_server<'a>(conn_type: &str, protocol: &str, hostname: &str, port: i16)
     let libusb_context = libusb::Context::new()?;
     let server_context = server::ServerContext::new(endpoint)?;
 
-    match conn_type {
-        "mock" => {
-            let context = Box::new(MockContext::new());
-            let connection = context.connection()?;
-            start_server_with_connection(connection, &server_context)
-        }
-        #[cfg(feature = "usb")]
-        "usb" => {
-            let context = Box::new(UsbContext::new(&libusb_context)?);
-            let connection = context.connection()?;
-            start_server_with_connection(connection, &server_context)
-        }
-        #[cfg(feature = "ethernet")]
-        "ethernet" => {
-            let context = Box::new(EthernetContext::new("192.168.10.10:10001")?);
-            let connection = context.connection()?;
-            start_server_with_connection(connection, &server_context)
-        }
-        err => {
-            log::error!("Invalid connection type: {} not supported", err);
-            return Err(InternalError::from(String::from("Invalid connection Type")));
+    let mut contexts: Vec<(&String, Box<dyn Context>)> = vec![];
+    for (name, conn_type) in readers.iter() {
+        let mut conn_type = conn_type.splitn(2, ':');
+        let context: Box<dyn Context> = match (conn_type.next().unwrap(), conn_type.next()) {
+            #[cfg(feature = "mock")]
+            ("mock", None) => Box::new(MockContext::new(framing)),
+            #[cfg(feature = "mock")]
+            ("simulated", None) => Box::new(SimulatedContext::new(framing)),
+            #[cfg(feature = "mock")]
+            ("simulated", Some(uids)) => Box::new(SimulatedContext::with_transponders(
+                framing,
+                uids.split(',')
+                    .map(|uid| uid.parse::<VirtualTransponder>())
+                    .collect::<std::result::Result<_, _>>()?,
+            )),
+            #[cfg(feature = "usb")]
+            ("usb", device) => Box::new(UsbContext::for_device(
+                &libusb_context,
+                framing,
+                device.unwrap_or("").parse()?,
+            )?),
+            ("replay", Some(path)) => Box::new(ReplayContext::new(std::path::Path::new(path))?),
+            #[cfg(feature = "serial")]
+            ("serial", Some(port)) => {
+                let mut port = port.splitn(2, '@');
+                let path = port.next().unwrap();
+                let bus_address = match port.next() {
+                    Some(com_adr) => Some(com_adr.parse()?),
+                    None => None,
+                };
+                Box::new(SerialContext::with_options(
+                    path,
+                    framing,
+                    config.serial_options(bus_address)?,
+                ))
+            }
+            #[cfg(feature = "ethernet")]
+            ("ethernet", addr) => Box::new(EthernetContext::with_options(
+                addr.unwrap_or(&config.reader_addr),
+                framing,
+                config.ethernet_options(),
+            )?),
+            (err, _) => {
+                let message = format!(
+                    "Invalid connection type: {} isn't one of the compiled in types: {}",
+                    err,
+                    conn_types().join(", ")
+                );
+                log::error!("{}", message);
+                return Err(InternalError::from(message));
+            }
+        };
+        let context: Box<dyn Context> = match config.record_path(name, readers.len()) {
+            Some(path) => {
+                log::info!("Recording reader '{}' to {}", name, path.display());
+                Box::new(RecordingContext::new(context, &path)?)
+            }
+            None => context,
+        };
+        contexts.push((name, context));
+    }
+
+    let mut connections: Vec<(String, Box<dyn Connection>)> = vec![];
+    for (name, context) in contexts.into_iter() {
+        log::info!("Connecting to reader '{}' ...", name);
+        connections.push((name.clone(), Box::new(SupervisedConnection::new(context)?)));
+    }
+    start_server_with_connections(
+        connections,
+        config.com_adr,
+        &config.protocol_impl,
+        config.state_file.as_ref().map(std::path::Path::new),
+        &server_context,
+    )
+}
+
+/// The connection types compiled into this binary
+fn conn_types() -> Vec<&'static str> {
+    let mut conn_types = vec![];
+    if cfg!(feature = "usb") {
+        conn_types.push("usb");
+    }
+    if cfg!(feature = "ethernet") {
+        conn_types.push("ethernet");
+    }
+    if cfg!(feature = "serial") {
+        conn_types.push("serial");
+    }
+    if cfg!(feature = "mock") {
+        conn_types.push("mock");
+        conn_types.push("simulated");
+    }
+    conn_types.push("replay");
+    conn_types
+}
+
+fn default_conn_type() -> &'static str {
+    conn_types().first().copied().unwrap_or("mock")
+}
+
+/// Parses `NAME=CONN_TYPE` of a --reader
+fn parse_reader(value: &str) -> Result<(String, String)> {
+    let mut parts = value.splitn(2, '=');
+    match (parts.next(), parts.next()) {
+        (Some(name), Some(conn_type)) if !name.is_empty() => {
+            Ok((String::from(name), String::from(conn_type)))
         }
+        _ => Err(InternalError::from(format!(
+            "Expected NAME=CONN_TYPE for reader but found {}",
+            value
+        ))),
+    }
+}
+
+/// Whether the paths name the same file, such as a relative and an absolute path to it
+fn same_file(a: &std::path::Path, b: &std::path::Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// Prints one row per transponder of an inventory
+fn print_transponders(transponders: &[TransponderInfo]) {
+    println!(
+        "{:<16}  {:>6}  {:>7}  {:>5}  FABRIC",
+        "UID", "RF-TEC", "TYPE-NO", "DSFID"
+    );
+    for transponder in transponders {
+        println!(
+            "{:<16}  {:>#6x}  {:>#7x}  {:>#5x}  {}",
+            transponder.uid,
+            transponder.tr_type_rf_tec,
+            transponder.tr_type_type_no,
+            transponder.dsfid,
+            transponder.fabric_name.as_deref().unwrap_or("-")
+        );
     }
+    println!("{} transponders", transponders.len());
 }
 
-fn start_server_with_connection<'a, 'b>(
-    connection: Box<dyn Connection<'a> + 'a>,
+fn start_server_with_connections<'a, 'b>(
+    connections: Vec<(String, Box<dyn Connection<'a> + 'a>)>,
+    com_adr: u8,
+    protocol_impl: &str,
+    state_file: Option<&std::path::Path>,
     server_context: &'b server::ServerContext,
 ) -> Result<()> {
     let mut server =
-        server::Server::new(server_context, connection).expect("Failed to initialize server");
+        server::Server::with_protocol(server_context, connections, com_adr, protocol_impl)?;
+    if let Some(state_file) = state_file {
+        server.persist_to(state_file)?;
+    }
     match server.serve() {
-        Ok(reserve) => {
-            log::info!("Finished serving with Ok result.");
-            if !reserve {
-                return Ok(());
-            }
-        }
-        Err(err) => {
-            log::error!("Encountered error: {}", err);
-        }
+        Ok(()) => log::info!("Finished serving after a Stop."),
+        Err(err) => log::error!("Encountered error: {}", err),
     }
 
     Ok(())
 }
 
 fn main() -> Result<()> {
+    let protocol_impls = ProtocolRegistry::default().names();
+    let conn_type_help = format!(
+        "The type of connection that will be attempted, one of: {}",
+        conn_types().join(", ")
+    );
+
     // Define the acceptable user input behavior
     let matches = clap::App::new("VR Actuators")
         .version("v0.1")
@@ -79,13 +384,144 @@ fn main() -> Result<()> {
         .subcommand(
             clap::App::new("start")
                 .about("Starts the service that manages the connection to the VR Actuators")
+                .arg(
+                    clap::Arg::with_name("protocol_impl")
+                        .long("protocol-impl")
+                        .value_name("PROTOCOL_IMPL")
+                        .possible_values(&protocol_impls)
+                        .default_value(default_protocol())
+                        .help("The protocol used to handle commands for the readers")
+                        .takes_value(true),
+                )
                 .arg(
                     clap::Arg::with_name("conn_type")
                         .short("c")
                         .long("conn-type")
                         .value_name("CONN_TYPE")
-                        .default_value("usb")
-                        .help("The type of connection that will be attempted")
+                        .default_value(default_conn_type())
+                        .help(&conn_type_help)
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("reader")
+                        .short("r")
+                        .long("reader")
+                        .value_name("NAME=CONN_TYPE")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("A named reader to connect to instead of --conn-type, such as left=usb:1:4, right=usb:<serial number>, bench=ethernet:192.168.10.10:10001, bus2=serial:/dev/ttyUSB0@2 or field=replay:capture.jsonl")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("reader_addr")
+                        .long("reader-addr")
+                        .value_name("HOST:PORT")
+                        .default_value("192.168.10.10:10001")
+                        .help("The address of an ethernet reader")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("connect_timeout")
+                        .long("connect-timeout")
+                        .value_name("MS")
+                        .default_value("2000")
+                        .help("Milliseconds to wait while connecting to an ethernet reader")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("read_timeout")
+                        .long("read-timeout")
+                        .value_name("MS")
+                        .default_value("1000")
+                        .help("Milliseconds to wait for a response from an ethernet or serial reader, where 0 waits forever")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("write_timeout")
+                        .long("write-timeout")
+                        .value_name("MS")
+                        .default_value("1000")
+                        .help("Milliseconds to wait while sending to an ethernet reader, where 0 waits forever")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("baud_rate")
+                        .long("baud-rate")
+                        .value_name("BAUD")
+                        .default_value("38400")
+                        .help("The baud rate of a serial reader")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("data_bits")
+                        .long("data-bits")
+                        .value_name("BITS")
+                        .possible_values(&["5", "6", "7", "8"])
+                        .default_value("8")
+                        .help("The data bits of a serial reader")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("parity")
+                        .long("parity")
+                        .value_name("PARITY")
+                        .possible_values(&["none", "even", "odd"])
+                        .default_value("even")
+                        .help("The parity of a serial reader")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("stop_bits")
+                        .long("stop-bits")
+                        .value_name("BITS")
+                        .possible_values(&["1", "2"])
+                        .default_value("1")
+                        .help("The stop bits of a serial reader")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("record")
+                        .long("record")
+                        .value_name("CAPTURE_FILE")
+                        .help("Write every request and response of the readers to a capture file, which --conn-type replay:CAPTURE_FILE plays back")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("state_file")
+                        .long("state-file")
+                        .value_name("STATE_FILE")
+                        .help("Restore the fabrics from a JSON file at start and save every change to them there")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("discover_subnet")
+                        .long("discover-subnet")
+                        .value_name("SUBNET")
+                        .help("Scan the subnet, such as 192.168.10.0/24, for an ethernet reader that isn't at its address, up to a /22")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("config")
+                        .long("config")
+                        .value_name("CONFIG_FILE")
+                        .help("A JSON file of start options, where arguments given on the command line take precedence")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("framing")
+                        .long("framing")
+                        .value_name("FRAMING")
+                        .possible_values(&["advanced", "standard"])
+                        .default_value("advanced")
+                        .help("The Obid protocol framing used to talk to the reader")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("com_adr")
+                        .long("com-adr")
+                        .value_name("COM_ADR")
+                        .default_value("255")
+                        .help("The bus address of the reader, where 255 is broadcast")
                         .takes_value(true),
                 )
                 .arg(
@@ -175,14 +611,32 @@ fn main() -> Result<()> {
         log::trace!("Start Params: {:#?}", matches);
 
         // Start listening for connections
-        let conn_type = matches.value_of("conn_type").unwrap();
-        let protocol = matches.value_of("protocol").unwrap();
-        let hostname = matches.value_of("hostname").unwrap();
-        let port = matches.value_of("port").unwrap();
-        let port: i16 = port.parse().expect("Expected a small integer for port");
+        let config = StartConfig::from_matches(matches)?;
+        log::debug!("Start Config: {:#?}", config);
 
+        // A new capture for this run, which each restart of the server appends to
+        let readers = config.readers()?;
+        for (name, _) in readers.iter() {
+            if let Some(path) = config.record_path(name, readers.len()) {
+                if let Some(replay) = readers
+                    .iter()
+                    .filter_map(|(_, conn_type)| conn_type.strip_prefix("replay:"))
+                    .find(|replay| same_file(&path, std::path::Path::new(replay)))
+                {
+                    let message = format!(
+                        "Refusing to record reader '{}' over the capture {} that is replayed",
+                        name, replay
+                    );
+                    log::error!("{}", message);
+                    return Err(InternalError::from(message));
+                }
+                std::fs::File::create(path)?;
+            }
+        }
+
+        // Serve again after a Stop or an error while serving, but not if the readers can't be connected
         loop {
-            start_server(conn_type, protocol, hostname, port)?;
+            start_server(&config)?;
         }
     } else if let Some(matches) = matches.subcommand_matches("command") {
         log::info!("Running command: {}", "command");
@@ -204,7 +658,11 @@ fn main() -> Result<()> {
         let stream = serde_json::Deserializer::from_reader(reader).into_iter::<CommandMessage>();
         for command in stream {
             log::trace!("Found command: {:#?}", command);
-            client.request_message(command?)?
+            match client.request_message(command?)? {
+                CommandMessage::Success {} => {}
+                CommandMessage::Transponders { transponders } => print_transponders(&transponders),
+                reply => println!("{}", serde_json::to_string_pretty(&reply)?),
+            }
         }
     } else {
         log::error!("Unknown command. Exiting ...");
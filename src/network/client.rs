@@ -12,10 +12,11 @@ impl Client {
         })
     }
 
+    /// Sends the command to the server and returns its reply
     pub fn request_message(
         &mut self,
         command_message: CommandMessage,
-    ) -> Result<(), std::io::Error> {
+    ) -> Result<CommandMessage, std::io::Error> {
         // Serialze the message
         let msg = match serde_json::to_string(&command_message) {
             Ok(msg) => msg,
@@ -53,7 +54,7 @@ impl Client {
             }
             other => {
                 log::trace!("Received Response: {:#?}", other);
-                Ok(())
+                Ok(other)
             }
         }
     }
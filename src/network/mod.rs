@@ -1,3 +1,4 @@
 pub mod client;
 pub mod common;
+pub mod sequencer;
 pub mod server;
@@ -0,0 +1,406 @@
+use crate::error::*;
+use crate::protocol::common::PatternFrame;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+/// A frame of a pattern that is due, which the server writes to the fabric
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Step {
+    pub fabric_name: String,
+    /// Tells the steps of a pattern apart from those of an earlier pattern of the fabric
+    pub pattern_id: u64,
+    pub command: u8,
+    pub frame: PatternFrame,
+    /// Whether the pattern ends with this step, which turns the actuators off after the last frame
+    pub last: bool,
+}
+
+enum Control {
+    Play {
+        fabric_name: String,
+        pattern_id: u64,
+        command: u8,
+        frames: Vec<PatternFrame>,
+        repeat: u32,
+    },
+    Stop {
+        fabric_name: String,
+    },
+}
+
+/// A pattern being played, where the frame at index is due next, or the end once index is past the frames
+struct Playback {
+    pattern_id: u64,
+    command: u8,
+    frames: Vec<PatternFrame>,
+    /// The plays left after this one, or None to play until stopped
+    plays_left: Option<u32>,
+    index: usize,
+    due: Instant,
+}
+
+impl Playback {
+    /// The step that is due, moving on to the next frame
+    fn step(self: &mut Self, fabric_name: &str) -> Step {
+        if self.index == self.frames.len() {
+            // The last frame has played for its duration, so the pattern ends with all off
+            return Step {
+                fabric_name: String::from(fabric_name),
+                pattern_id: self.pattern_id,
+                command: 0,
+                frame: PatternFrame {
+                    actuators: vec![],
+                    timers: None,
+                    duration_ms: 0,
+                },
+                last: true,
+            };
+        }
+        let frame = self.frames[self.index].clone();
+        // Each frame is due after the one before it, so a late step doesn't delay the rest
+        self.due += Duration::from_millis(frame.duration_ms);
+        self.index += 1;
+        if self.index == self.frames.len() {
+            match self.plays_left {
+                // The index stays past the frames, so the end is due next
+                Some(0) => {}
+                Some(plays_left) => {
+                    self.plays_left = Some(plays_left - 1);
+                    self.index = 0;
+                }
+                None => self.index = 0,
+            }
+        }
+        Step {
+            fabric_name: String::from(fabric_name),
+            pattern_id: self.pattern_id,
+            command: self.command,
+            frame,
+            last: false,
+        }
+    }
+
+    /// The latest step that is due by now, skipping the frames that ended while it was late
+    fn latest_step(self: &mut Self, fabric_name: &str, now: Instant) -> Step {
+        let mut step = self.step(fabric_name);
+        while !step.last && self.due <= now {
+            step = self.step(fabric_name);
+        }
+        step
+    }
+}
+
+/**
+ * Plays the patterns of the fabrics on a thread of its own, which sends each
+ * frame as a Step to the server when it's due. The server owns the readers,
+ * so it polls the socket of the sequencer along with that of its clients and
+ * writes the frames itself.
+ */
+pub struct Sequencer {
+    control: mpsc::Sender<Control>,
+    socket: zmq::Socket,
+    next_pattern_id: u64,
+}
+
+impl Sequencer {
+    pub fn new(ctx: &zmq::Context) -> Result<Sequencer> {
+        let endpoint = format!("inproc://sequencer-{}", uuid::Uuid::new_v4());
+        let socket = ctx.socket(zmq::PAIR)?;
+        socket.bind(endpoint.as_str())?;
+        let steps = ctx.socket(zmq::PAIR)?;
+        steps.connect(endpoint.as_str())?;
+
+        let (control, controls) = mpsc::channel();
+        std::thread::Builder::new()
+            .name(String::from("sequencer"))
+            .spawn(move || Sequencer::run(controls, steps))?;
+        Ok(Sequencer {
+            control,
+            socket,
+            next_pattern_id: 0,
+        })
+    }
+
+    /// Start playing the frames on the fabric right away, replacing any pattern it was playing
+    /// Every frame must last, since a pattern of frames that take no time would never let the thread wait
+    pub fn play(
+        self: &mut Self,
+        fabric_name: &str,
+        command: u8,
+        frames: Vec<PatternFrame>,
+        repeat: u32,
+    ) -> Result<u64> {
+        if frames.is_empty() {
+            return Err(InternalError::from("Expected a pattern with frames"));
+        }
+        if let Some(index) = frames.iter().position(|frame| frame.duration_ms == 0) {
+            return Err(InternalError::from(format!(
+                "Expected frame {} of the pattern to last at least 1 ms",
+                index
+            )));
+        }
+        self.next_pattern_id += 1;
+        self.send(Control::Play {
+            fabric_name: String::from(fabric_name),
+            pattern_id: self.next_pattern_id,
+            command,
+            frames,
+            repeat,
+        })?;
+        Ok(self.next_pattern_id)
+    }
+
+    pub fn stop(self: &Self, fabric_name: &str) -> Result<()> {
+        self.send(Control::Stop {
+            fabric_name: String::from(fabric_name),
+        })
+    }
+
+    /// The socket the steps arrive on, which is readable when a step is due
+    pub fn socket(self: &Self) -> &zmq::Socket {
+        &self.socket
+    }
+
+    /// Receive the next step, waiting for it to be due
+    pub fn next_step(self: &Self) -> Result<Step> {
+        let msg = self.socket.recv_bytes(0)?;
+        Ok(serde_json::from_slice(msg.as_slice())?)
+    }
+
+    /// Receive the steps that have arrived without waiting, which pile up while the server is busy
+    pub fn arrived_steps(self: &Self) -> Result<Vec<Step>> {
+        let mut steps = vec![];
+        loop {
+            match self.socket.recv_bytes(zmq::DONTWAIT) {
+                Ok(msg) => steps.push(serde_json::from_slice(msg.as_slice())?),
+                Err(zmq::Error::EAGAIN) => return Ok(steps),
+                Err(err) => return Err(InternalError::from(err)),
+            }
+        }
+    }
+
+    fn send(self: &Self, control: Control) -> Result<()> {
+        self.control
+            .send(control)
+            .map_err(|_| InternalError::from("The sequencer has stopped"))
+    }
+
+    /// Send the steps as they are due until the sequencer is dropped
+    fn run(controls: mpsc::Receiver<Control>, steps: zmq::Socket) {
+        let mut playing: HashMap<String, Playback> = HashMap::new();
+        loop {
+            let next_due = playing.values().map(|playback| playback.due).min();
+            let control = match next_due {
+                Some(due) => controls.recv_timeout(due.saturating_duration_since(Instant::now())),
+                None => controls
+                    .recv()
+                    .map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+            };
+            match control {
+                Ok(Control::Play {
+                    fabric_name,
+                    pattern_id,
+                    command,
+                    frames,
+                    repeat,
+                }) => {
+                    log::debug!("Playing {} frames on {}", frames.len(), fabric_name);
+                    let playback = Playback {
+                        pattern_id,
+                        command,
+                        frames,
+                        plays_left: match repeat {
+                            0 => None,
+                            repeat => Some(repeat - 1),
+                        },
+                        index: 0,
+                        due: Instant::now(),
+                    };
+                    playing.insert(fabric_name, playback);
+                }
+                Ok(Control::Stop { fabric_name }) => {
+                    log::debug!("Stopping the pattern of {}", fabric_name);
+                    playing.remove(&fabric_name);
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => return,
+            }
+
+            let now = Instant::now();
+            let mut finished = vec![];
+            for (fabric_name, playback) in playing.iter_mut() {
+                if playback.due > now {
+                    continue;
+                }
+                let step = playback.latest_step(fabric_name, now);
+                if step.last {
+                    finished.push(fabric_name.clone());
+                }
+                let sent = serde_json::to_vec(&step)
+                    .map_err(InternalError::from)
+                    .and_then(|msg| Ok(steps.send(msg, 0)?));
+                if let Err(err) = sent {
+                    log::error!("Failed to send a step of {}: {}", fabric_name, err);
+                }
+            }
+            for fabric_name in finished {
+                playing.remove(&fabric_name);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(actuators: Vec<u16>, duration_ms: u64) -> PatternFrame {
+        PatternFrame {
+            actuators,
+            timers: None,
+            duration_ms,
+        }
+    }
+
+    #[test]
+    fn play_the_frames_on_time() {
+        let ctx = zmq::Context::new();
+        let mut sequencer = Sequencer::new(&ctx).unwrap();
+        let frames = vec![
+            frame(vec![0], 30),
+            frame(vec![1], 30),
+            frame(vec![2, 3], 40),
+        ];
+        let start = Instant::now();
+        let pattern_id = sequencer.play("Sleeve", 2, frames.clone(), 2).unwrap();
+
+        let mut elapsed = vec![];
+        for n in 0..6 {
+            let step = sequencer.next_step().unwrap();
+            elapsed.push(start.elapsed().as_millis() as u64);
+            assert_eq!(pattern_id, step.pattern_id);
+            assert_eq!(frames[n % 3], step.frame);
+            assert!(!step.last);
+        }
+
+        // The last frame turns actuators on, so they turn off once it has played
+        let step = sequencer.next_step().unwrap();
+        elapsed.push(start.elapsed().as_millis() as u64);
+        assert_eq!(pattern_id, step.pattern_id);
+        assert_eq!(0, step.command);
+        assert_eq!(frame(vec![], 0), step.frame);
+        assert!(step.last);
+
+        // Each frame is due at the sum of the durations before it
+        for (n, due) in [0, 30, 60, 100, 130, 160, 200].iter().enumerate() {
+            assert!(elapsed[n] >= *due, "Frame {} at {} ms", n, elapsed[n]);
+            assert!(elapsed[n] < due + 25, "Frame {} at {} ms", n, elapsed[n]);
+        }
+
+        // Nothing is played after the last frame
+        sequencer.socket().set_rcvtimeo(100).unwrap();
+        assert!(sequencer.next_step().is_err());
+    }
+
+    #[test]
+    fn skip_the_frames_that_ended_while_late() {
+        let frames = vec![frame(vec![0], 30), frame(vec![1], 30), frame(vec![2], 30)];
+        let mut playback = Playback {
+            pattern_id: 1,
+            command: 2,
+            frames: frames.clone(),
+            plays_left: Some(0),
+            index: 0,
+            due: Instant::now(),
+        };
+        let start = playback.due;
+        assert_eq!(frames[0], playback.latest_step("Sleeve", start).frame);
+
+        // Late by two frames, where the first of them ended already
+        let step = playback.latest_step("Sleeve", playback.due + Duration::from_millis(30));
+        assert_eq!(frames[2], step.frame);
+        assert!(!step.last);
+
+        // Late past the end of the last frame
+        let step = playback.latest_step("Sleeve", playback.due + Duration::from_millis(30));
+        assert_eq!(frame(vec![], 0), step.frame);
+        assert!(step.last);
+    }
+
+    #[test]
+    fn collect_the_steps_that_arrived() {
+        let ctx = zmq::Context::new();
+        let mut sequencer = Sequencer::new(&ctx).unwrap();
+        assert_eq!(Vec::<Step>::new(), sequencer.arrived_steps().unwrap());
+
+        let frames = vec![frame(vec![0], 10), frame(vec![1], 10)];
+        sequencer.play("Sleeve", 2, frames.clone(), 1).unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        let steps = sequencer.arrived_steps().unwrap();
+        assert_eq!(
+            vec![frames[0].clone(), frames[1].clone(), frame(vec![], 0)],
+            steps.into_iter().map(|step| step.frame).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn reject_frames_that_take_no_time() {
+        let ctx = zmq::Context::new();
+        let mut sequencer = Sequencer::new(&ctx).unwrap();
+        assert!(sequencer.play("Sleeve", 2, vec![], 1).is_err());
+        let err = sequencer
+            .play("Sleeve", 2, vec![frame(vec![0], 10), frame(vec![1], 0)], 0)
+            .unwrap_err();
+        assert_eq!(
+            "Expected frame 1 of the pattern to last at least 1 ms",
+            err.to_string()
+        );
+
+        // Nothing was played, and the thread still plays the next pattern
+        sequencer.socket().set_rcvtimeo(200).unwrap();
+        let pattern_id = sequencer
+            .play("Glove", 2, vec![frame(vec![2], 10)], 1)
+            .unwrap();
+        let step = sequencer.next_step().unwrap();
+        assert_eq!(pattern_id, step.pattern_id);
+        assert_eq!("Glove", step.fabric_name);
+    }
+
+    #[test]
+    fn stop_a_pattern_that_repeats() {
+        let ctx = zmq::Context::new();
+        let mut sequencer = Sequencer::new(&ctx).unwrap();
+        let first = sequencer
+            .play("Sleeve", 2, vec![frame(vec![0], 10)], 0)
+            .unwrap();
+        for _ in 0..5 {
+            let step = sequencer.next_step().unwrap();
+            assert_eq!(first, step.pattern_id);
+            assert!(!step.last);
+        }
+        sequencer.stop("Sleeve").unwrap();
+
+        // A new pattern replaces the one before
+        let second = sequencer
+            .play("Sleeve", 2, vec![frame(vec![1], 10)], 1)
+            .unwrap();
+        sequencer.socket().set_rcvtimeo(200).unwrap();
+        let mut steps = vec![];
+        while let Ok(step) = sequencer.next_step() {
+            if step.pattern_id == second {
+                steps.push(step);
+            } else {
+                assert_eq!(first, step.pattern_id);
+            }
+        }
+        assert_eq!(
+            vec![(frame(vec![1], 10), false), (frame(vec![], 0), true)],
+            steps
+                .into_iter()
+                .map(|step| (step.frame, step.last))
+                .collect::<Vec<_>>()
+        );
+    }
+}
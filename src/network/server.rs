@@ -1,8 +1,11 @@
 use crate::conn::common::*;
 use crate::error::*;
 use crate::network::common::*;
+use crate::network::sequencer::{Sequencer, Step};
 use crate::protocol::common::*;
-use crate::protocol::{haptic::v0::HapticV0Protocol, mock::MockProtocol};
+use crate::protocol::registry::{default_protocol, ProtocolRegistry};
+use crate::protocol::state::HostState;
+use std::collections::HashMap;
 
 pub struct ServerContext {
     net_ctx: NetworkContext,
@@ -19,43 +22,335 @@ impl ServerContext {
 
 pub struct Server<'a, 'b> {
     ctx: &'a ServerContext,
-    protocol: Box<dyn Protocol<'b> + 'b>,
+    /// The protocol of each named reader, where the first is the default
+    readers: Vec<(String, Box<dyn Protocol<'b> + 'b>)>,
+    /// The name of the reader that each fabric was discovered on
+    fabric_readers: HashMap<String, String>,
+    /// Where the fabrics are saved, and what was saved there last
+    state_file: Option<(std::path::PathBuf, HostState)>,
+    sequencer: Sequencer,
+    /// The pattern each fabric is playing
+    patterns: HashMap<String, u64>,
 }
 
 impl<'a, 'b> Server<'a, 'b> {
     pub fn new(
         ctx: &'a ServerContext,
         conn: Box<dyn Connection<'b> + 'b>,
+        com_adr: u8,
     ) -> Result<Server<'a, 'b>> {
-        if cfg![feature = "haptic_v0"] {
-            log::info!("Creating HapticV0Protocol instance ...");
-            Ok(Server {
-                ctx,
-                protocol: Box::new(HapticV0Protocol::new(conn)),
-            })
-        } else {
-            log::info!("Creating MockProtocol instance ...");
-            Ok(Server {
-                ctx,
-                protocol: Box::new(MockProtocol::new(conn)),
-            })
+        Server::with_readers(ctx, vec![(String::from("default"), conn)], com_adr)
+    }
+
+    pub fn with_readers(
+        ctx: &'a ServerContext,
+        conns: Vec<(String, Box<dyn Connection<'b> + 'b>)>,
+        com_adr: u8,
+    ) -> Result<Server<'a, 'b>> {
+        Server::with_protocol(ctx, conns, com_adr, default_protocol())
+    }
+
+    /// Serve the readers with the named protocol of the default registry
+    pub fn with_protocol(
+        ctx: &'a ServerContext,
+        conns: Vec<(String, Box<dyn Connection<'b> + 'b>)>,
+        com_adr: u8,
+        protocol_impl: &str,
+    ) -> Result<Server<'a, 'b>> {
+        if conns.is_empty() {
+            return Err(InternalError::from("No readers to serve"));
+        }
+
+        let registry = ProtocolRegistry::default();
+        let mut readers = vec![];
+        for (name, conn) in conns {
+            match conn.reader_info() {
+                Some(reader_info) => log::info!("Serving reader '{}' with {}", name, reader_info),
+                None => log::warn!("Serving reader '{}' without version information", name),
+            }
+
+            log::info!(
+                "Creating {} protocol instance for '{}' ...",
+                protocol_impl,
+                name
+            );
+            let protocol = registry.create(protocol_impl, conn, com_adr)?;
+            readers.push((name, protocol));
+        }
+
+        Ok(Server {
+            ctx,
+            readers,
+            fabric_readers: HashMap::new(),
+            state_file: None,
+            sequencer: Sequencer::new(&ctx.net_ctx._ctx)?,
+            patterns: HashMap::new(),
+        })
+    }
+
+    /// Restore the fabrics saved in the state file and save every change to them from now on
+    pub fn persist_to(self: &mut Self, path: &std::path::Path) -> Result<()> {
+        let state = HostState::load(path)?;
+        for (name, protocol) in self.readers.iter_mut() {
+            if let Some(fabrics) = state.readers.get(name) {
+                log::info!(
+                    "Restoring {} fabrics of reader '{}' from {}",
+                    fabrics.len(),
+                    name,
+                    path.display()
+                );
+                for fabric in fabrics.iter() {
+                    self.fabric_readers
+                        .insert(fabric.fabric_name.clone(), name.clone());
+                }
+                protocol.restore_fabrics(fabrics.clone())?;
+            }
+        }
+        self.state_file = Some((path.to_path_buf(), state));
+        self.save_state()
+    }
+
+    /// Write the fabrics of every reader to the state file if they changed
+    /// The fabrics of readers this server doesn't serve are kept as they were
+    fn save_state(self: &mut Self) -> Result<()> {
+        let (path, saved) = match &mut self.state_file {
+            Some(state_file) => state_file,
+            None => return Ok(()),
+        };
+        let mut state = saved.clone();
+        for (name, protocol) in self.readers.iter() {
+            state.readers.insert(name.clone(), protocol.fabrics());
+        }
+        if state != *saved {
+            log::debug!("Saving the fabrics to {}", path.display());
+            state.save(path)?;
+            *saved = state;
+        }
+        Ok(())
+    }
+
+    /// The request inside an Addressed message, which may not be addressed or routed again
+    fn unaddressed(message: &CommandMessage) -> Result<&CommandMessage> {
+        let request = match message {
+            CommandMessage::Addressed { message, .. } => message.as_ref(),
+            other => other,
+        };
+        match request {
+            CommandMessage::Addressed { .. } | CommandMessage::Routed { .. } => {
+                Err(InternalError::from(
+                    "Expected at most one Routed and one Addressed around a request",
+                ))
+            }
+            request => Ok(request),
+        }
+    }
+
+    /// Choose the reader for a request by name, by the fabric it commands, or the default
+    fn route(self: &Self, reader: &Option<String>, message: &CommandMessage) -> Result<usize> {
+        let message = Server::unaddressed(message)?;
+        let name = match reader {
+            Some(name) => name,
+            None => {
+                let fabric_name = match message {
+                    CommandMessage::RemoveFabric { fabric_name }
+                    | CommandMessage::FabricState { fabric_name }
+                    | CommandMessage::ReadActuatorState { fabric_name }
+                    | CommandMessage::SetActuators { fabric_name, .. }
+                    | CommandMessage::PlayPattern { fabric_name, .. }
+                    | CommandMessage::StopPattern { fabric_name }
+                    | CommandMessage::ActuatorsCommand { fabric_name, .. } => fabric_name,
+                    _ => return Ok(0),
+                };
+                match self.fabric_readers.get(fabric_name) {
+                    Some(name) => name,
+                    None => return Ok(0),
+                }
+            }
+        };
+        match self.readers.iter().position(|(n, _)| n == name) {
+            Some(index) => Ok(index),
+            None => Err(InternalError::from(format!("No reader named '{}'", name))),
+        }
+    }
+
+    /// Handle the message with the protocol of the routed reader
+    fn handle_message(
+        self: &mut Self,
+        reader: &Option<String>,
+        message: &CommandMessage,
+    ) -> Result<CommandMessage> {
+        let index = self.route(reader, message)?;
+        let request = Server::unaddressed(message)?;
+        if let CommandMessage::AddFabric { fabric_name, .. } = request {
+            match self.fabric_readers.get(fabric_name) {
+                Some(existing) if *existing != self.readers[index].0 => {
+                    return Err(InternalError::from(format!(
+                        "Fabric '{}' is already registered on reader '{}'",
+                        fabric_name, existing
+                    )));
+                }
+                _ => {}
+            }
+        }
+
+        let (name, protocol) = &mut self.readers[index];
+        log::debug!("Routing request to reader '{}'", name);
+        let reply = protocol.handle_message(message)?;
+        match request {
+            CommandMessage::AddFabric { fabric_name, .. } => {
+                self.fabric_readers
+                    .insert(fabric_name.clone(), name.clone());
+            }
+            CommandMessage::RemoveFabric { fabric_name } => {
+                self.fabric_readers.remove(fabric_name);
+                self.stop_playing(fabric_name)?;
+            }
+            _ => {}
+        }
+        Ok(reply)
+    }
+
+    /// Reset the routed reader and reconnect to it, keeping its protocol and fabrics
+    fn reset(
+        self: &mut Self,
+        reader: &Option<String>,
+        message: &CommandMessage,
+    ) -> Result<CommandMessage> {
+        let index = self.route(reader, message)?;
+        let (name, protocol) = &mut self.readers[index];
+        protocol.handle_message(message)?;
+
+        log::info!(
+            "Waiting for Feig Reader '{}' to reboot after system reset ...",
+            name
+        );
+        std::thread::sleep(protocol.reset_delay());
+        log::info!("Done waiting for reboot. Trying to reset connection ...");
+        protocol.reinitialize()?;
+        Ok(CommandMessage::Success {})
+    }
+
+    /// Check the pattern with the protocol of the fabric and start playing it
+    fn play_pattern(
+        self: &mut Self,
+        reader: &Option<String>,
+        message: &CommandMessage,
+    ) -> Result<CommandMessage> {
+        let reply = self.handle_message(reader, message)?;
+        if let CommandMessage::PlayPattern {
+            fabric_name,
+            command,
+            frames,
+            repeat,
+        } = Server::unaddressed(message)?
+        {
+            let pattern_id =
+                self.sequencer
+                    .play(fabric_name, *command, frames.clone(), repeat.unwrap_or(1))?;
+            self.patterns.insert(fabric_name.clone(), pattern_id);
+        }
+        Ok(reply)
+    }
+
+    /// Stop the pattern of the fabric before the protocol turns its actuators off
+    fn stop_pattern(
+        self: &mut Self,
+        reader: &Option<String>,
+        message: &CommandMessage,
+    ) -> Result<CommandMessage> {
+        if let CommandMessage::StopPattern { fabric_name } = Server::unaddressed(message)? {
+            self.stop_playing(fabric_name)?;
+        }
+        self.handle_message(reader, message)
+    }
+
+    fn stop_playing(self: &mut Self, fabric_name: &str) -> Result<()> {
+        if self.patterns.remove(fabric_name).is_some() {
+            self.sequencer.stop(fabric_name)?;
+        }
+        Ok(())
+    }
+
+    /// Write the latest frame that arrived for each fabric, unless its pattern was stopped or replaced
+    /// The frames that piled up while the server was busy are dropped, so they don't play back-to-back
+    /// The last step of a pattern has no actuators, so the fabric turns all off once the pattern ends
+    fn play_steps(self: &mut Self) -> Result<()> {
+        let mut latest: HashMap<String, Step> = HashMap::new();
+        for step in self.sequencer.arrived_steps()? {
+            if self.patterns.get(&step.fabric_name) != Some(&step.pattern_id) {
+                log::debug!(
+                    "Skipping a step of a stopped pattern of {}",
+                    step.fabric_name
+                );
+                continue;
+            }
+            if step.last {
+                self.patterns.remove(&step.fabric_name);
+            }
+            if let Some(late) = latest.insert(step.fabric_name.clone(), step) {
+                log::debug!("Dropping a late frame of {}", late.fabric_name);
+            }
         }
+        for (fabric_name, step) in latest {
+            let message = CommandMessage::SetActuators {
+                fabric_name: fabric_name.clone(),
+                on: step.frame.actuators,
+                command: step.command,
+                timing: step.frame.timers,
+                use_cache: Some(true),
+                verify: None,
+            };
+            if let Err(err) = self.handle_message(&None, &message) {
+                log::error!("Failed to play a frame on {}: {}", fabric_name, err);
+            }
+        }
+        if let Err(err) = self.save_state() {
+            log::error!("Failed to save the state file: {}", err);
+        }
+        Ok(())
     }
 
-    pub fn serve(&mut self) -> Result<bool> {
+    /// Wait for a request, writing the frames of patterns as they are due in the meantime
+    fn wait_for_request(self: &mut Self) -> Result<()> {
+        loop {
+            let (request, step) = {
+                let mut items = [
+                    self.ctx.net_ctx.socket.as_poll_item(zmq::POLLIN),
+                    self.sequencer.socket().as_poll_item(zmq::POLLIN),
+                ];
+                zmq::poll(&mut items, -1)?;
+                (items[0].is_readable(), items[1].is_readable())
+            };
+            if step {
+                self.play_steps()?;
+            }
+            if request {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Serve requests until a Stop
+    pub fn serve(&mut self) -> Result<()> {
         log::info!("Beginning serve() loop ...");
 
         assert_eq!(self.ctx.net_ctx.socket_type_name, "REP_DEALER");
         loop {
             // Receive a message
+            self.wait_for_request()?;
             let id = self.ctx.net_ctx.socket.recv_bytes(0)?; // Simulated REP: Connection Identity
             let _ = self.ctx.net_ctx.socket.recv_bytes(0)?; // Simulated REP: Empty Frame
             let msg = self.ctx.net_ctx.socket.recv_bytes(0)?; // Simulated REP: Message Content
 
             // Handle the message
             let request_message = serde_json::from_slice(msg.as_slice())?;
-            let result: Result<()> = match request_message {
-                CommandMessage::Stop {} => {
+            let (reader, request_message) = match request_message {
+                CommandMessage::Routed { reader, message } => (Some(reader), *message),
+                other => (None, other),
+            };
+            // The requests that need more than the protocol are dispatched whether or not they are addressed
+            let result: Result<CommandMessage> = match Server::unaddressed(&request_message) {
+                Ok(CommandMessage::Stop {}) => {
                     log::debug!("Received Stop.");
 
                     let success = serde_json::to_string(&CommandMessage::Success {})?;
@@ -63,43 +358,30 @@ impl<'a, 'b> Server<'a, 'b> {
                     self.ctx.net_ctx.socket.send(vec![], zmq::SNDMORE)?;
                     self.ctx.net_ctx.socket.send(success.as_bytes(), 0)?;
 
-                    return Ok(false);
+                    return Ok(());
                 }
-                CommandMessage::SystemReset {} => {
+                Ok(CommandMessage::SystemReset {}) => {
                     log::debug!("Received SystemReset.");
-                    let reset = self.protocol.handle_message(&request_message);
-
-                    log::info!("Waiting for Feig Reader to reboot after system reset ...");
-                    let timeout = if cfg!(any(feature = "haptic_v0")) {
-                        1000
-                    } else {
-                        1
-                    };
-                    std::thread::sleep(std::time::Duration::from_millis(timeout));
-                    log::info!("Done waiting for reboot. Trying to reset connection ...");
-
-                    let message = if reset.is_ok() {
-                        CommandMessage::Success {}
-                    } else {
-                        CommandMessage::Failure {
-                            message: String::from("Failed system reset"),
-                        }
-                    };
-                    let message = serde_json::to_string(&message)?;
-                    self.ctx.net_ctx.socket.send(id, zmq::SNDMORE)?;
-                    self.ctx.net_ctx.socket.send(vec![], zmq::SNDMORE)?;
-                    self.ctx.net_ctx.socket.send(message.as_bytes(), 0)?;
-
-                    return Ok(true);
+                    self.reset(&reader, &request_message)
+                }
+                Ok(CommandMessage::Success {}) => Ok(CommandMessage::Success {}),
+                Ok(CommandMessage::PlayPattern { .. }) => {
+                    self.play_pattern(&reader, &request_message)
+                }
+                Ok(CommandMessage::StopPattern { .. }) => {
+                    self.stop_pattern(&reader, &request_message)
                 }
-                CommandMessage::Success {} => Ok(()),
 
-                other => self.protocol.handle_message(&other),
+                _ => self.handle_message(&reader, &request_message),
             };
 
+            if let Err(err) = self.save_state() {
+                log::error!("Failed to save the state file: {}", err);
+            }
+
             // Send a response using the result of handling the request
             let response = match result {
-                Ok(_) => serde_json::to_string(&CommandMessage::Success {})?,
+                Ok(reply) => serde_json::to_string(&reply)?,
                 Err(err) => {
                     let failure_message = err.to_string();
                     serde_json::to_string(&CommandMessage::Failure {
@@ -125,3 +407,213 @@ impl<'a, 'b> Server<'a, 'b> {
             .unwrap()
     }
 }
+
+#[cfg(all(test, feature = "mock"))]
+mod tests {
+    use super::*;
+    use crate::conn::simulated::{SimulatedContext, VirtualTransponder};
+    use crate::obid::Framing;
+    use crate::protocol::common::PatternFrame;
+    use std::time::Duration;
+
+    fn server(ctx: &ServerContext) -> Server<'_, 'static> {
+        let connection = SimulatedContext::with_transponders(
+            Framing::Advanced,
+            vec![VirtualTransponder::new([0xE0, 0x04, 0, 0, 0, 0, 0, 1])],
+        )
+        .connection()
+        .unwrap();
+        let conns = vec![(String::from("default"), connection)];
+        let mut server = Server::with_protocol(ctx, conns, 0xFF, "haptic_v0").unwrap();
+        let add_fabric = CommandMessage::AddFabric {
+            fabric_name: String::from("Sleeve"),
+            uids: None,
+            check_uids: None,
+        };
+        server.handle_message(&None, &add_fabric).unwrap();
+        server
+    }
+
+    fn server_with_readers<'a>(ctx: &'a ServerContext, names: &[&str]) -> Server<'a, 'static> {
+        let conns = names
+            .iter()
+            .map(|name| {
+                let connection = SimulatedContext::new(Framing::Advanced)
+                    .connection()
+                    .unwrap();
+                (String::from(*name), connection)
+            })
+            .collect();
+        Server::with_protocol(ctx, conns, 0xFF, "haptic_v0").unwrap()
+    }
+
+    fn server_context() -> ServerContext {
+        ServerContext::new(format!("inproc://server-{}", uuid::Uuid::new_v4())).unwrap()
+    }
+
+    fn play_pattern(actuators: Vec<Vec<u16>>, duration_ms: u64, repeat: u32) -> CommandMessage {
+        CommandMessage::PlayPattern {
+            fabric_name: String::from("Sleeve"),
+            command: 2,
+            frames: actuators
+                .into_iter()
+                .map(|actuators| PatternFrame {
+                    actuators,
+                    timers: None,
+                    duration_ms,
+                })
+                .collect(),
+            repeat: Some(repeat),
+        }
+    }
+
+    #[test]
+    fn route_addressed_requests_by_fabric() {
+        let ctx = server_context();
+        let mut server = server_with_readers(&ctx, &["left", "right"]);
+        let addressed = |message| CommandMessage::Addressed {
+            com_adr: 0x00,
+            message: Box::new(message),
+        };
+        let add_fabric = || CommandMessage::AddFabric {
+            fabric_name: String::from("Sleeve"),
+            uids: None,
+            check_uids: None,
+        };
+        let right = Some(String::from("right"));
+        server
+            .handle_message(&right, &addressed(add_fabric()))
+            .unwrap();
+        assert_eq!(
+            Some(&String::from("right")),
+            server.fabric_readers.get("Sleeve")
+        );
+
+        // The addressed request for the fabric goes to its reader
+        let fabric_state = CommandMessage::FabricState {
+            fabric_name: String::from("Sleeve"),
+        };
+        assert_eq!(1, server.route(&None, &addressed(fabric_state)).unwrap());
+
+        // Another reader can't take the name of the fabric
+        let left = Some(String::from("left"));
+        let err = server.handle_message(&left, &add_fabric()).unwrap_err();
+        assert_eq!(
+            "Fabric 'Sleeve' is already registered on reader 'right'",
+            err.to_string()
+        );
+        assert_eq!(
+            Some(&String::from("right")),
+            server.fabric_readers.get("Sleeve")
+        );
+
+        let nested = addressed(addressed(add_fabric()));
+        assert!(server.handle_message(&None, &nested).is_err());
+        let routed = CommandMessage::Routed {
+            reader: String::from("left"),
+            message: Box::new(add_fabric()),
+        };
+        assert!(server.handle_message(&right, &routed).is_err());
+        assert!(server.handle_message(&None, &addressed(routed)).is_err());
+    }
+
+    #[test]
+    fn write_the_latest_frame_and_save_it() {
+        let path = std::env::temp_dir().join(format!("state-{}.json", uuid::Uuid::new_v4()));
+        let ctx = server_context();
+        let mut server = server(&ctx);
+        server.persist_to(&path).unwrap();
+
+        // The frames pile up while the server is busy, and only the last of them is written
+        let pattern = play_pattern(vec![vec![0], vec![1], vec![2]], 40, 1);
+        server.play_pattern(&None, &pattern).unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        server.play_steps().unwrap();
+        assert!(server.patterns.contains_key("Sleeve"));
+
+        let read = CommandMessage::ReadActuatorState {
+            fabric_name: String::from("Sleeve"),
+        };
+        match server.handle_message(&None, &read).unwrap() {
+            CommandMessage::ActuatorState { memory, .. } => {
+                assert_eq!(vec![2], memory.actuator_mode_blocks.unwrap().indices())
+            }
+            other => panic!("Expected the actuator state but got {:?}", other),
+        }
+
+        let state = HostState::load(&path).unwrap();
+        let fabric = &state.readers.get("default").unwrap()[0];
+        let blocks = fabric.state.as_ref().unwrap().actuator_mode_blocks.as_ref();
+        assert_eq!(vec![2], blocks.unwrap().indices());
+
+        // Once the last frame has played for its duration, the fabric turns all off
+        std::thread::sleep(Duration::from_millis(60));
+        server.play_steps().unwrap();
+        assert!(server.patterns.is_empty());
+        match server.handle_message(&None, &read).unwrap() {
+            CommandMessage::ActuatorState { data, .. } => assert_eq!("00002003", data),
+            other => panic!("Expected the actuator state but got {:?}", other),
+        }
+
+        let state = HostState::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let fabric = &state.readers.get("default").unwrap()[0];
+        let op_mode_block = fabric.state.as_ref().unwrap().op_mode_block.as_ref();
+        assert_eq!(0, op_mode_block.unwrap().command);
+    }
+
+    #[test]
+    fn play_and_stop_an_addressed_pattern() {
+        let ctx = server_context();
+        let mut server = server(&ctx);
+        let addressed = |message| CommandMessage::Addressed {
+            com_adr: 0x00,
+            message: Box::new(message),
+        };
+        let pattern = addressed(play_pattern(vec![vec![0], vec![1]], 10, 0));
+        server.play_pattern(&None, &pattern).unwrap();
+        assert!(server.patterns.contains_key("Sleeve"));
+
+        let stop_pattern = addressed(CommandMessage::StopPattern {
+            fabric_name: String::from("Sleeve"),
+        });
+        server.stop_pattern(&None, &stop_pattern).unwrap();
+        assert!(server.patterns.is_empty());
+    }
+
+    #[test]
+    fn reject_frames_that_take_no_time_whatever_the_protocol() {
+        let ctx = server_context();
+        let connection = SimulatedContext::new(Framing::Advanced)
+            .connection()
+            .unwrap();
+        let conns = vec![(String::from("default"), connection)];
+        let mut server = Server::with_protocol(&ctx, conns, 0xFF, "mock").unwrap();
+
+        // The mock protocol takes any pattern, but the sequencer doesn't play frames that take no time
+        let pattern = play_pattern(vec![vec![0]], 0, 0);
+        assert!(server.play_pattern(&None, &pattern).is_err());
+        assert!(server.patterns.is_empty());
+    }
+
+    #[test]
+    fn remove_fabric_stops_its_pattern() {
+        let ctx = server_context();
+        let mut server = server(&ctx);
+        let pattern = play_pattern(vec![vec![0], vec![1]], 10, 0);
+        server.play_pattern(&None, &pattern).unwrap();
+        assert!(server.patterns.contains_key("Sleeve"));
+
+        let remove_fabric = CommandMessage::RemoveFabric {
+            fabric_name: String::from("Sleeve"),
+        };
+        server.handle_message(&None, &remove_fabric).unwrap();
+        assert!(server.patterns.is_empty());
+
+        // The steps that were on their way are skipped, and no more follow
+        std::thread::sleep(Duration::from_millis(30));
+        server.play_steps().unwrap();
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(server.sequencer.arrived_steps().unwrap().is_empty());
+    }
+}
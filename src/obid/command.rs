@@ -0,0 +1,804 @@
+use crate::error::*;
+use crate::obid::config::CFG_BLOCK_SIZE;
+use crate::obid::serial::advanced_protocol;
+use crate::obid::status::Status;
+use byteorder::{BigEndian, ByteOrder};
+use serde::{Deserialize, Serialize};
+
+/// Feig reader commands are documented here
+/// http://www.sebeto.com/intranet/ftpscambio/RFID_FEIG/Readers/ID%20ISC%20LR2500/Documentation/H01112-0e-ID-B.pdf
+///
+/// Each command knows its control byte, how to marshal its DATA and how to interpret the
+/// DATA of the response from the reader.
+pub trait ObidCommand {
+    type Response;
+
+    fn control_byte(self: &Self) -> u8;
+
+    fn data(self: &Self) -> std::vec::Vec<u8>;
+
+    /// Indicates the message isn't sent if no device observed it
+    fn device_required(self: &Self) -> bool {
+        false
+    }
+
+    fn parse_response(
+        self: &Self,
+        response: &advanced_protocol::ReaderToHost,
+    ) -> Result<Self::Response>;
+
+    fn request(self: &Self, com_adr: u8) -> advanced_protocol::HostToReader {
+        advanced_protocol::HostToReader::new(
+            0,
+            com_adr,
+            self.control_byte(),
+            self.data().as_slice(),
+            0,
+            self.device_required(),
+        )
+    }
+}
+
+/// Send the command to any reader and parse its response, as the tests of the connections do
+#[cfg(test)]
+pub fn execute<'a, C: ObidCommand>(
+    connection: &mut dyn crate::conn::common::Connection<'a>,
+    command: &C,
+) -> Result<C::Response> {
+    let response =
+        connection.send_command(command.request(crate::obid::serial::BROADCAST_COM_ADR))?;
+    command.parse_response(&response)
+}
+
+/// Fails unless the reader executed the command without error
+pub fn check_status(response: &advanced_protocol::ReaderToHost) -> Result<()> {
+    let status = Status::from(response.status);
+    if status != Status::Ok {
+        let error_message = format!("Command failed with status code: {:?}.", status);
+        Err(InternalError::from(error_message))
+    } else {
+        Ok(())
+    }
+}
+
+fn check_length(response: &advanced_protocol::ReaderToHost, length: usize) -> Result<()> {
+    if response.data.len() < length {
+        return Err(InternalError::from(format!(
+            "Expected at least {} bytes in response to control byte {:#04X} but found {}",
+            length,
+            response.control_byte,
+            response.data.len()
+        )));
+    }
+    Ok(())
+}
+
+/// Where configuration is read from or written to
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ConfigLocation {
+    /// Takes effect immediately and is lost on reset
+    Ram,
+    /// Takes effect after a system reset
+    Eeprom,
+}
+
+/// Any control byte and DATA, such as for a CustomCommand
+pub struct RawCommand {
+    pub control_byte: u8,
+    pub data: std::vec::Vec<u8>,
+    pub device_required: bool,
+}
+
+impl ObidCommand for RawCommand {
+    type Response = advanced_protocol::ReaderToHost;
+
+    fn control_byte(self: &Self) -> u8 {
+        self.control_byte
+    }
+
+    fn data(self: &Self) -> std::vec::Vec<u8> {
+        self.data.clone()
+    }
+
+    fn device_required(self: &Self) -> bool {
+        self.device_required
+    }
+
+    fn parse_response(
+        self: &Self,
+        response: &advanced_protocol::ReaderToHost,
+    ) -> Result<Self::Response> {
+        Ok(response.clone())
+    }
+}
+
+/// [0x80] Read Configuration of a 14 byte CFG block
+pub struct ReadConfiguration {
+    pub location: ConfigLocation,
+    pub address: u8,
+}
+
+impl ObidCommand for ReadConfiguration {
+    type Response = [u8; CFG_BLOCK_SIZE];
+
+    fn control_byte(self: &Self) -> u8 {
+        0x80
+    }
+
+    fn data(self: &Self) -> std::vec::Vec<u8> {
+        // CFG-ADR: Bit 7 is the location and Bits 0-5 are the address
+        let loc = match self.location {
+            ConfigLocation::Ram => 0b0000_0000,
+            ConfigLocation::Eeprom => 0b1000_0000,
+        };
+        vec![loc | (self.address & 0b0011_1111)]
+    }
+
+    fn parse_response(
+        self: &Self,
+        response: &advanced_protocol::ReaderToHost,
+    ) -> Result<Self::Response> {
+        check_status(response)?;
+        check_length(response, CFG_BLOCK_SIZE)?;
+        let mut block = [0u8; CFG_BLOCK_SIZE];
+        block.copy_from_slice(&response.data[..CFG_BLOCK_SIZE]);
+        Ok(block)
+    }
+}
+
+/// [0x81] Write Configuration of a 14 byte CFG block
+pub struct WriteConfiguration {
+    pub location: ConfigLocation,
+    pub address: u8,
+    pub data: [u8; CFG_BLOCK_SIZE],
+}
+
+impl ObidCommand for WriteConfiguration {
+    type Response = ();
+
+    fn control_byte(self: &Self) -> u8 {
+        0x81
+    }
+
+    fn data(self: &Self) -> std::vec::Vec<u8> {
+        let loc = match self.location {
+            ConfigLocation::Ram => 0b0000_0000,
+            ConfigLocation::Eeprom => 0b1000_0000,
+        };
+        let mut data = vec![loc | (self.address & 0b0011_1111)];
+        data.extend_from_slice(&self.data);
+        data
+    }
+
+    fn parse_response(
+        self: &Self,
+        response: &advanced_protocol::ReaderToHost,
+    ) -> Result<Self::Response> {
+        check_status(response)
+    }
+}
+
+/// [0x8B] Write Configuration (Advanced Protocol) of CFG blocks of the same size
+pub struct WriteConfigurationAdvanced {
+    pub device: u8,
+    pub bank: u8,
+    pub location: ConfigLocation,
+    pub block_size: u8,
+    pub blocks: std::vec::Vec<(u16, std::vec::Vec<u8>)>, // CFG-ADR and CFG-DATA
+}
+
+impl ObidCommand for WriteConfigurationAdvanced {
+    type Response = ();
+
+    fn control_byte(self: &Self) -> u8 {
+        0x8B
+    }
+
+    fn data(self: &Self) -> std::vec::Vec<u8> {
+        let mode = match self.location {
+            ConfigLocation::Ram => 0x00,
+            ConfigLocation::Eeprom => 0x01,
+        };
+        let mut data = vec![
+            self.device,
+            self.bank,
+            mode,
+            self.blocks.len() as u8,
+            self.block_size,
+        ];
+        for (address, block) in self.blocks.iter() {
+            let mut encoded_address = [0u8; 2];
+            BigEndian::write_u16(&mut encoded_address, *address);
+            data.extend_from_slice(&encoded_address);
+            data.extend_from_slice(block.as_slice());
+        }
+        data
+    }
+
+    fn parse_response(
+        self: &Self,
+        response: &advanced_protocol::ReaderToHost,
+    ) -> Result<Self::Response> {
+        if Status::from(response.status) == Status::ParameterRangeError {
+            let error_message =
+                "A reasonableness check failed while writing the configuration to the reader";
+            return Err(InternalError::from(error_message));
+        }
+        check_status(response)
+    }
+}
+
+/// [0x64] System Reset
+pub struct SystemReset {}
+
+impl ObidCommand for SystemReset {
+    type Response = ();
+
+    fn control_byte(self: &Self) -> u8 {
+        0x64
+    }
+
+    fn data(self: &Self) -> std::vec::Vec<u8> {
+        vec![0x00] // MODE: RF controller
+    }
+
+    fn parse_response(
+        self: &Self,
+        response: &advanced_protocol::ReaderToHost,
+    ) -> Result<Self::Response> {
+        let status = Status::from(response.status);
+        if status != Status::Ok {
+            let error_message = format!("System reset failed with status code: {:?}.", status);
+            Err(InternalError::from(error_message))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// [0x6A] RF On/Off
+pub struct RfOnOff {
+    pub rf: u8, // Bit 0 enables the RF field
+}
+
+impl ObidCommand for RfOnOff {
+    type Response = ();
+
+    fn control_byte(self: &Self) -> u8 {
+        0x6A
+    }
+
+    fn data(self: &Self) -> std::vec::Vec<u8> {
+        vec![self.rf]
+    }
+
+    fn parse_response(
+        self: &Self,
+        response: &advanced_protocol::ReaderToHost,
+    ) -> Result<Self::Response> {
+        check_status(response)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SoftwareVersion {
+    pub sw_rev: u16,
+    pub d_rev: u8,
+    pub hw_type: u8,
+    pub sw_type: u8,
+    pub tr_type: u16,
+    pub rx_buf: u16,
+    pub tx_buf: u16,
+}
+
+/// [0x66] Get Software Version
+pub struct GetSoftwareVersion {}
+
+impl ObidCommand for GetSoftwareVersion {
+    type Response = SoftwareVersion;
+
+    fn control_byte(self: &Self) -> u8 {
+        0x66
+    }
+
+    fn data(self: &Self) -> std::vec::Vec<u8> {
+        vec![0x00] // MODE: Firmware version
+    }
+
+    fn parse_response(
+        self: &Self,
+        response: &advanced_protocol::ReaderToHost,
+    ) -> Result<Self::Response> {
+        check_status(response)?;
+        check_length(response, 11)?;
+        let data = response.data.as_slice();
+        Ok(SoftwareVersion {
+            sw_rev: BigEndian::read_u16(&data[0..2]),
+            d_rev: data[2],
+            hw_type: data[3],
+            sw_type: data[4],
+            tr_type: BigEndian::read_u16(&data[5..7]),
+            rx_buf: BigEndian::read_u16(&data[7..9]),
+            tx_buf: BigEndian::read_u16(&data[9..11]),
+        })
+    }
+}
+
+/// [0x66] Get Software Version in Mode 0x80 to read the Device-ID
+pub struct GetDeviceId {}
+
+impl ObidCommand for GetDeviceId {
+    type Response = u32;
+
+    fn control_byte(self: &Self) -> u8 {
+        0x66
+    }
+
+    fn data(self: &Self) -> std::vec::Vec<u8> {
+        vec![0x80] // MODE: Device-ID
+    }
+
+    fn parse_response(
+        self: &Self,
+        response: &advanced_protocol::ReaderToHost,
+    ) -> Result<Self::Response> {
+        check_status(response)?;
+        check_length(response, 4)?;
+        Ok(BigEndian::read_u32(&response.data[0..4]))
+    }
+}
+
+/// The RF hardware health reported by [0x6E] Reader Diagnostic in Mode 0x01
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ReaderDiagnostics {
+    pub flags_a: u8,
+    pub flags_b: u8,
+
+    /// The RF hardware isn't working
+    pub rf_hardware_error: bool,
+    /// The environment is too noisy
+    pub noise_level_too_high: bool,
+    /// The RF power doesn't have the configured value
+    pub rf_power_deviation: bool,
+    /// The antenna impedance is too low or too high. Check the antenna cables and the antenna matching.
+    pub antenna_mismatch: bool,
+    /// The reader is too hot
+    pub temperature_alarm: bool,
+}
+
+impl ReaderDiagnostics {
+    /// Decode FLAGS-A and FLAGS-B of the General RF Status
+    pub fn from_flags(flags_a: u8, flags_b: u8) -> ReaderDiagnostics {
+        ReaderDiagnostics {
+            flags_a,
+            flags_b,
+            rf_hardware_error: flags_a & 0b0000_0001 != 0,
+            noise_level_too_high: flags_a & 0b0000_0010 != 0,
+            rf_power_deviation: flags_a & 0b0000_0100 != 0,
+            antenna_mismatch: flags_a & 0b0011_0000 != 0, // PHASE-LEFT or PHASE-RIGHT
+            temperature_alarm: flags_a & 0b1000_0000 != 0,
+        }
+    }
+
+    pub fn is_healthy(self: &Self) -> bool {
+        !(self.rf_hardware_error
+            || self.noise_level_too_high
+            || self.rf_power_deviation
+            || self.antenna_mismatch
+            || self.temperature_alarm)
+    }
+}
+
+impl std::fmt::Display for ReaderDiagnostics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut problems = vec![];
+        if self.rf_hardware_error {
+            problems.push("RF hardware error");
+        }
+        if self.noise_level_too_high {
+            problems.push("noise level too high");
+        }
+        if self.rf_power_deviation {
+            problems.push("RF power deviation");
+        }
+        if self.antenna_mismatch {
+            problems.push("antenna mismatch");
+        }
+        if self.temperature_alarm {
+            problems.push("temperature alarm");
+        }
+        if problems.is_empty() {
+            problems.push("no RF problems reported");
+        }
+        write!(
+            f,
+            "{} (FLAGS-A {:#04X}, FLAGS-B {:#04X})",
+            problems.join(", "),
+            self.flags_a,
+            self.flags_b
+        )
+    }
+}
+
+/// [0x6E] Reader Diagnostic in Mode 0x01 for the General RF Status
+pub struct ReaderDiagnostic {}
+
+impl ReaderDiagnostic {
+    pub const CONTROL_BYTE: u8 = 0x6E;
+}
+
+impl ObidCommand for ReaderDiagnostic {
+    type Response = ReaderDiagnostics;
+
+    fn control_byte(self: &Self) -> u8 {
+        ReaderDiagnostic::CONTROL_BYTE
+    }
+
+    fn data(self: &Self) -> std::vec::Vec<u8> {
+        vec![0x01] // MODE: General RF Status
+    }
+
+    fn parse_response(
+        self: &Self,
+        response: &advanced_protocol::ReaderToHost,
+    ) -> Result<Self::Response> {
+        // The reader reports the details of an RF warning with the same status
+        if Status::from(response.status) != Status::RFWarning {
+            check_status(response)?;
+        }
+        check_length(response, 2)?;
+        Ok(ReaderDiagnostics::from_flags(
+            response.data[0],
+            response.data[1],
+        ))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObidTransponder {
+    pub uid: smallvec::SmallVec<[u8; 8]>, // 8-byte serial number
+    pub tr_type_rf_tec: u8,
+    pub tr_type_type_no: u8,
+    pub dsfid: u8,
+}
+
+/// MODE of the [0xB0] ISO Host Commands that address a transponder by UID
+const ISO_MODE_ADDRESSED: u8 = 0x01;
+
+/// [0xB0][0x01] Inventory
+/// This command reads the UID of all Transponders inside the antenna field.
+pub struct Inventory {
+    pub device_required: bool,
+}
+
+impl ObidCommand for Inventory {
+    type Response = smallvec::SmallVec<[ObidTransponder; 2]>;
+
+    fn control_byte(self: &Self) -> u8 {
+        0xB0
+    }
+
+    fn data(self: &Self) -> std::vec::Vec<u8> {
+        vec![0x01, 0x00] // Inventory, MODE
+    }
+
+    fn device_required(self: &Self) -> bool {
+        self.device_required
+    }
+
+    fn parse_response(
+        self: &Self,
+        response: &advanced_protocol::ReaderToHost,
+    ) -> Result<Self::Response> {
+        let status = Status::from(response.status);
+        if (status != Status::Ok && status != Status::MoreData) || response.data.len() == 0 {
+            return Ok(smallvec::smallvec![]);
+        }
+
+        let mut transponders = smallvec::smallvec![];
+        let encoded_transponders = response.data[0];
+        let bytes_per_transponder = 1 + 1 + 8; // tr_type, dsfid, uid
+        if response.data.len()
+            != 1 + (encoded_transponders as usize) * (bytes_per_transponder as usize)
+        {
+            return Err(InternalError::from(
+                "Unexpected data format in response to inventory request",
+            ));
+        }
+
+        for i in 0..encoded_transponders {
+            let begin = (1 + bytes_per_transponder * i) as usize;
+            let end = begin + bytes_per_transponder as usize;
+            let encoded_transponder_slice = &response.data[begin..end];
+            let tr_type = encoded_transponder_slice[0];
+            let tr_type_rf_tec = (tr_type & 0b1100_0000) >> 6;
+            let tr_type_type_no = tr_type & 0b0000_1111;
+            let dsfid = encoded_transponder_slice[1];
+            let uid = &encoded_transponder_slice[2..];
+            transponders.push(ObidTransponder {
+                uid: smallvec::SmallVec::from(uid),
+                tr_type_rf_tec,
+                tr_type_type_no,
+                dsfid,
+            });
+        }
+
+        Ok(transponders)
+    }
+}
+
+/// [0xB0][0x01] Inventory with MORE, which reads the transponders that didn't fit into the
+/// response to the last inventory while the reader answers with Status::MoreData
+pub struct InventoryMore {}
+
+impl ObidCommand for InventoryMore {
+    type Response = smallvec::SmallVec<[ObidTransponder; 2]>;
+
+    fn control_byte(self: &Self) -> u8 {
+        0xB0
+    }
+
+    fn data(self: &Self) -> std::vec::Vec<u8> {
+        vec![0x01, 0x80] // Inventory, MODE with MORE
+    }
+
+    fn parse_response(
+        self: &Self,
+        response: &advanced_protocol::ReaderToHost,
+    ) -> Result<Self::Response> {
+        Inventory {
+            device_required: false,
+        }
+        .parse_response(response)
+    }
+}
+
+/// [0xB0][0x23] Read Multiple Blocks from an addressed transponder
+pub struct ReadMultipleBlocks {
+    pub uid: [u8; 8],
+    pub db_adr: u8,
+    pub db_n: u8,
+}
+
+impl ObidCommand for ReadMultipleBlocks {
+    type Response = std::vec::Vec<std::vec::Vec<u8>>;
+
+    fn control_byte(self: &Self) -> u8 {
+        0xB0
+    }
+
+    fn data(self: &Self) -> std::vec::Vec<u8> {
+        let mut data = vec![0x23, ISO_MODE_ADDRESSED];
+        data.extend_from_slice(&self.uid);
+        data.push(self.db_adr);
+        data.push(self.db_n);
+        data
+    }
+
+    fn device_required(self: &Self) -> bool {
+        true
+    }
+
+    fn parse_response(
+        self: &Self,
+        response: &advanced_protocol::ReaderToHost,
+    ) -> Result<Self::Response> {
+        check_status(response)?;
+        check_length(response, 2)?;
+
+        // DB-N and DB-SIZE followed by the security status and data of each block
+        let db_n = response.data[0] as usize;
+        let db_size = response.data[1] as usize;
+        check_length(response, 2 + db_n * (1 + db_size))?;
+        Ok(response.data[2..]
+            .chunks(1 + db_size)
+            .take(db_n)
+            .map(|block| block[1..].to_vec())
+            .collect())
+    }
+}
+
+/// [0xB0][0x24] Write Multiple Blocks to an addressed transponder
+pub struct WriteMultipleBlocks {
+    pub uid: [u8; 8],
+    pub db_adr: u8,
+    pub db_n: u8,
+    pub db_size: u8,
+    pub data: std::vec::Vec<u8>,
+}
+
+impl ObidCommand for WriteMultipleBlocks {
+    type Response = ();
+
+    fn control_byte(self: &Self) -> u8 {
+        0xB0
+    }
+
+    fn data(self: &Self) -> std::vec::Vec<u8> {
+        let mut data = vec![0x24, ISO_MODE_ADDRESSED];
+        data.extend_from_slice(&self.uid);
+        data.push(self.db_adr);
+        data.push(self.db_n);
+        data.push(self.db_size);
+        data.extend_from_slice(self.data.as_slice());
+        data
+    }
+
+    fn device_required(self: &Self) -> bool {
+        true
+    }
+
+    fn parse_response(
+        self: &Self,
+        response: &advanced_protocol::ReaderToHost,
+    ) -> Result<Self::Response> {
+        check_status(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inventory_request() {
+        let request = Inventory {
+            device_required: true,
+        }
+        .request(0xFF);
+        assert_eq!(0xB0, request.control_byte);
+        assert_eq!(vec![0x01, 0x00], request.data);
+        assert!(request.device_required);
+    }
+
+    #[test]
+    fn inventory_more_data() {
+        let request = InventoryMore {}.request(0xFF);
+        assert_eq!(vec![0x01, 0x80], request.data);
+        assert!(!request.device_required);
+
+        let mut data = vec![1];
+        data.extend_from_slice(&[0x03, 0x00, 1, 2, 3, 4, 5, 6, 7, 8]);
+        let status = Status::MoreData as u8;
+        let response = advanced_protocol::ReaderToHost::new(0, 0, 0xB0, status, &data[..], 0);
+        assert_eq!(1, InventoryMore {}.parse_response(&response).unwrap().len());
+    }
+
+    #[test]
+    fn inventory_response() {
+        let mut data = vec![2];
+        data.extend_from_slice(&[0x03, 0x00, 1, 2, 3, 4, 5, 6, 7, 8]);
+        data.extend_from_slice(&[0x03, 0x00, 8, 7, 6, 5, 4, 3, 2, 1]);
+        let response = advanced_protocol::ReaderToHost::new(0, 0, 0xB0, 0, &data[..], 0);
+
+        let transponders = Inventory {
+            device_required: false,
+        }
+        .parse_response(&response)
+        .unwrap();
+        assert_eq!(2, transponders.len());
+        assert_eq!(&[1, 2, 3, 4, 5, 6, 7, 8], transponders[0].uid.as_slice());
+        assert_eq!(3, transponders[1].tr_type_type_no);
+    }
+
+    #[test]
+    fn inventory_no_transponder() {
+        let response = advanced_protocol::ReaderToHost::new(0, 0, 0xB0, 0x01, &[], 0);
+        let transponders = Inventory {
+            device_required: false,
+        }
+        .parse_response(&response)
+        .unwrap();
+        assert!(transponders.is_empty());
+    }
+
+    #[test]
+    fn write_configuration_advanced_data() {
+        let command = WriteConfigurationAdvanced {
+            device: 0x02,
+            bank: 0x01,
+            location: ConfigLocation::Eeprom,
+            block_size: 2,
+            blocks: vec![(0x0003, vec![0xAA, 0xBB])],
+        };
+        assert_eq!(
+            vec![0x02, 0x01, 0x01, 0x01, 0x02, 0x00, 0x03, 0xAA, 0xBB],
+            command.data()
+        );
+
+        let response = advanced_protocol::ReaderToHost::new(0, 0, 0x8B, 0, &[], 0);
+        assert!(command.parse_response(&response).is_ok());
+        let response = advanced_protocol::ReaderToHost::new(0, 0, 0x8B, 0x11, &[], 0);
+        assert!(command.parse_response(&response).is_err());
+        let response = advanced_protocol::ReaderToHost::new(0, 0, 0x8B, 0x10, &[], 0);
+        assert!(command.parse_response(&response).is_err());
+    }
+
+    #[test]
+    fn read_configuration_data() {
+        let command = ReadConfiguration {
+            location: ConfigLocation::Eeprom,
+            address: 3,
+        };
+        assert_eq!(vec![0x83], command.data());
+
+        let response = advanced_protocol::ReaderToHost::new(0, 0, 0x80, 0, &[7; 14], 0);
+        assert_eq!([7; 14], command.parse_response(&response).unwrap());
+
+        let response = advanced_protocol::ReaderToHost::new(0, 0, 0x80, 0, &[7; 13], 0);
+        assert!(command.parse_response(&response).is_err());
+    }
+
+    #[test]
+    fn write_configuration_data() {
+        let command = WriteConfiguration {
+            location: ConfigLocation::Ram,
+            address: 3,
+            data: [7; 14],
+        };
+        let mut expected = vec![0x03];
+        expected.extend_from_slice(&[7; 14]);
+        assert_eq!(expected, command.data());
+    }
+
+    #[test]
+    fn reader_diagnostic_response() {
+        let command = ReaderDiagnostic {};
+        assert_eq!(vec![0x01], command.request(0xFF).data);
+
+        let response = advanced_protocol::ReaderToHost::new(0, 0, 0x6E, 0, &[0x00, 0x00], 0);
+        let diagnostics = command.parse_response(&response).unwrap();
+        assert!(diagnostics.is_healthy());
+
+        let response =
+            advanced_protocol::ReaderToHost::new(0, 0, 0x6E, 0x84, &[0b1001_0010, 0x00], 0);
+        let diagnostics = command.parse_response(&response).unwrap();
+        assert!(!diagnostics.is_healthy());
+        assert!(diagnostics.noise_level_too_high);
+        assert!(diagnostics.antenna_mismatch);
+        assert!(diagnostics.temperature_alarm);
+        assert!(!diagnostics.rf_hardware_error);
+        assert!(!diagnostics.rf_power_deviation);
+
+        let response = advanced_protocol::ReaderToHost::new(0, 0, 0x6E, 0x82, &[], 0);
+        assert!(command.parse_response(&response).is_err());
+    }
+
+    #[test]
+    fn software_version_response() {
+        let data = [
+            0x01, 0x02, 0x03, 0x04, 0x05, 0x00, 0x08, 0x01, 0x00, 0x02, 0x00,
+        ];
+        let response = advanced_protocol::ReaderToHost::new(0, 0, 0x66, 0, &data[..], 0);
+        let version = GetSoftwareVersion {}.parse_response(&response).unwrap();
+        assert_eq!(0x0102, version.sw_rev);
+        assert_eq!(0x03, version.d_rev);
+        assert_eq!(0x0008, version.tr_type);
+        assert_eq!(0x0200, version.tx_buf);
+    }
+
+    #[test]
+    fn read_multiple_blocks_response() {
+        let command = ReadMultipleBlocks {
+            uid: [1, 2, 3, 4, 5, 6, 7, 8],
+            db_adr: 0,
+            db_n: 2,
+        };
+        assert_eq!(
+            vec![0x23, 0x01, 1, 2, 3, 4, 5, 6, 7, 8, 0, 2],
+            command.data()
+        );
+
+        let data = [2, 4, 0, 1, 2, 3, 4, 0, 5, 6, 7, 8];
+        let response = advanced_protocol::ReaderToHost::new(0, 0, 0xB0, 0, &data[..], 0);
+        let blocks = command.parse_response(&response).unwrap();
+        assert_eq!(vec![vec![1, 2, 3, 4], vec![5, 6, 7, 8]], blocks);
+    }
+
+    #[test]
+    fn failed_status() {
+        let response = advanced_protocol::ReaderToHost::new(0, 0, 0x64, 0x80, &[], 0);
+        assert!(SystemReset {}.parse_response(&response).is_err());
+        assert!(RfOnOff { rf: 1 }.parse_response(&response).is_err());
+    }
+}
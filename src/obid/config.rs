@@ -0,0 +1,118 @@
+use byteorder::{BigEndian, ByteOrder};
+use serde::{Deserialize, Serialize};
+
+/// Size of a CFG block read with [0x80] or written with [0x81]
+pub const CFG_BLOCK_SIZE: usize = 14;
+
+/// A typed view of one CFG block of the reader configuration
+///
+/// Bytes that aren't modeled are carried along so that writing a block back
+/// only changes the fields that were modified.
+pub trait ConfigBlock: Sized {
+    /// The CFG-ADR of the block
+    const ADDRESS: u8;
+
+    fn from_bytes(data: &[u8; CFG_BLOCK_SIZE]) -> Self;
+
+    fn to_bytes(self: &Self) -> [u8; CFG_BLOCK_SIZE];
+}
+
+/// CFG3: RF-Interface
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RfInterfaceConfig {
+    pub tag_drv: u16,      // Byte 0,1
+    pub rf_power: u8,      // Byte 2
+    pub eas_level: u8,     // Byte 3
+    pub reserved: [u8; 9], // Byte 4..=12
+    pub fu_com: u8,        // Byte 13
+}
+
+impl RfInterfaceConfig {
+    /// Encode a power level in Watts, where 0 is Low Power
+    ///
+    /// RF Power format: 0bX0111111
+    /// Supported Wattage is [Low Power] union [2W, 12W] in 0.25W steps
+    ///
+    /// If X is 1, then 0b00111111 is interpretted as 1/4 Watts.
+    /// Using 1/4 W, the boundaries are
+    ///   - 0x04 -> Low Power
+    ///   - 0x08 -> 2 W
+    ///   - 0x00111111 -> 12 W
+    ///
+    /// If X is 0, then 2 is the minimum and 12 is the max as 1 W steps
+    pub fn encode_rf_power(power_level: u8) -> u8 {
+        if power_level == 0 {
+            0b1000_0000 | 0x04
+        } else {
+            0b1000_0000 | (0b0011_1111 & (power_level * 4))
+        }
+    }
+
+    /// The RF power in Watts, where 0 is Low Power
+    pub fn rf_power_watts(self: &Self) -> f32 {
+        let value = self.rf_power & 0b0011_1111;
+        if self.rf_power & 0b1000_0000 == 0 {
+            value as f32
+        } else if value == 0x04 {
+            0.0
+        } else {
+            value as f32 / 4.0
+        }
+    }
+}
+
+impl ConfigBlock for RfInterfaceConfig {
+    const ADDRESS: u8 = 3;
+
+    fn from_bytes(data: &[u8; CFG_BLOCK_SIZE]) -> Self {
+        let mut reserved = [0u8; 9];
+        reserved.copy_from_slice(&data[4..13]);
+        RfInterfaceConfig {
+            tag_drv: BigEndian::read_u16(&data[0..2]),
+            rf_power: data[2],
+            eas_level: data[3],
+            reserved,
+            fu_com: data[13],
+        }
+    }
+
+    fn to_bytes(self: &Self) -> [u8; CFG_BLOCK_SIZE] {
+        let mut data = [0u8; CFG_BLOCK_SIZE];
+        BigEndian::write_u16(&mut data[0..2], self.tag_drv);
+        data[2] = self.rf_power;
+        data[3] = self.eas_level;
+        data[4..13].copy_from_slice(&self.reserved);
+        data[13] = self.fu_com;
+        data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rf_interface_round_trip() {
+        let data = [
+            0x00, 0x08, 0x88, 0x80, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x81,
+        ];
+        let cfg = RfInterfaceConfig::from_bytes(&data);
+        assert_eq!(0x0008, cfg.tag_drv);
+        assert_eq!(0x80, cfg.eas_level);
+        assert_eq!(0x81, cfg.fu_com);
+        assert_eq!(data, cfg.to_bytes());
+    }
+
+    #[test]
+    fn rf_power_encoding() {
+        let mut cfg = RfInterfaceConfig::from_bytes(&[0; CFG_BLOCK_SIZE]);
+        for power_level in [0, 2, 4, 12].iter() {
+            cfg.rf_power = RfInterfaceConfig::encode_rf_power(*power_level);
+            assert_eq!(*power_level as f32, cfg.rf_power_watts());
+        }
+        assert_eq!(0x84, RfInterfaceConfig::encode_rf_power(0));
+        assert_eq!(0x88, RfInterfaceConfig::encode_rf_power(2));
+        cfg.rf_power = 0x05;
+        assert_eq!(5.0, cfg.rf_power_watts());
+    }
+}
@@ -1,5 +1,9 @@
+mod command;
+mod config;
 mod serial;
 mod status;
 
+pub use self::command::*;
+pub use self::config::*;
 pub use self::serial::*;
 pub use self::status::*;
@@ -1,5 +1,9 @@
 #![allow(dead_code)]
 
+use byteorder::{BigEndian, ByteOrder};
+
+/// The COM-ADR that any reader on the link will answer
+pub const BROADCAST_COM_ADR: u8 = 0xFF;
 
 pub trait ObidSerialSendable {
     fn serialize(self: &mut Self) -> std::vec::Vec<u8>
@@ -49,21 +53,264 @@ fn calc_crc16(data: &[u8]) -> u16 {
 /// The reader will reply with standard protocol if sent standard protocol
 /// except if the response from the reader would be too big for standard protocol
 pub mod standard_protocol {
+    use super::{advanced_protocol, calc_crc16, ObidSerialReceivable, ObidSerialSendable};
+    use byteorder::{ByteOrder, LittleEndian};
+
+    /// The largest frame that can be described by the 1-byte LENGTH
+    pub const MAX_LENGTH: usize = 0xFF;
+
+    #[derive(Debug)]
     pub struct HostToReader {
-        length: u8,
-        com_adr: u8,
-        control_byte: u8,
-        data: std::vec::Vec<u8>,
-        crc16: u16,
+        pub length: u8,              // length includes length, and crc16
+        pub com_adr: u8,             // [0,254] address of device in bus mode
+        pub control_byte: u8,        // defines the command which the reader should operate
+        pub data: std::vec::Vec<u8>, // optional data, as MSB first
+        pub crc16: u16,              // CRC from bytes [1,n-2]
+
+        pub device_required: bool, // Indicates the message isn't sent if no device observed it
+    }
+
+    impl HostToReader {
+        pub fn new(
+            length: u8,
+            com_adr: u8,
+            control_byte: u8,
+            data: &[u8],
+            crc16: u16,
+            device_required: bool,
+        ) -> HostToReader {
+            HostToReader {
+                length,
+                com_adr,
+                control_byte,
+                data: data.to_vec(),
+                crc16,
+                device_required,
+            }
+        }
+
+        /// Returns true if the data fits within the 1-byte LENGTH of the standard protocol
+        pub fn fits(data: &[u8]) -> bool {
+            1 + 1 + 1 + data.len() + 2 <= MAX_LENGTH
+        }
+    }
+
+    impl From<&advanced_protocol::HostToReader> for HostToReader {
+        fn from(message: &advanced_protocol::HostToReader) -> HostToReader {
+            HostToReader::new(
+                0,
+                message.com_adr,
+                message.control_byte,
+                message.data.as_slice(),
+                0,
+                message.device_required,
+            )
+        }
+    }
+
+    impl PartialEq for HostToReader {
+        fn eq(&self, other: &Self) -> bool {
+            self.length == other.length
+                && self.com_adr == other.com_adr
+                && self.control_byte == other.control_byte
+                && self.data == other.data
+                && self.crc16 == other.crc16
+        }
+    }
+
+    impl ObidSerialSendable for HostToReader {
+        fn serialize(self: &mut Self) -> std::vec::Vec<u8> {
+            // Reserve a vector of exactly the correct size
+            self.length = (1 + 1 + 1 + self.data.len() + 2) as u8;
+            let mut msg = vec![0u8; self.length as usize];
+
+            // Encode the values up to crc16
+            msg[0] = self.length; // LENGTH
+            msg[1] = self.com_adr;
+            msg[2] = self.control_byte;
+            if self.data.len() > 0 {
+                msg[3..(3 + self.data.len())].clone_from_slice(self.data.as_slice());
+                // DATA
+            }
+
+            // Compute and encode crc16
+            let n_2 = (self.length - 2) as usize;
+            self.crc16 = calc_crc16(&msg[..n_2]);
+            LittleEndian::write_u16(&mut msg[n_2..], self.crc16);
+
+            log::trace!("Serialized Message: {:#X?}", msg);
+            msg
+        }
+
+        fn deserialize(
+            self: &Self,
+            data: &[u8],
+        ) -> Result<Box<dyn ObidSerialReceivable>, Box<dyn std::error::Error>>
+        where
+            Self: Sized,
+        {
+            Ok(Box::new(ReaderToHost::deserialize(data)?))
+        }
+
+        fn _deserialize(data: &[u8]) -> Result<Self, Box<dyn std::error::Error>>
+        where
+            Self: Sized,
+        {
+            if data.len() < 5 {
+                return Err(Box::new(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    "Invalid (short) length for HostToReader message",
+                )));
+            }
+
+            let n_2 = data.len() - 2;
+            let crc16 = LittleEndian::read_u16(&data[n_2..]);
+            if calc_crc16(&data[..n_2]) != crc16 {
+                return Err(Box::new(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    "Invalid CRC16 for HostToReader message",
+                )));
+            }
+
+            let length = data[0];
+            let com_adr = data[1];
+            let control_byte = data[2];
+            let data = &data[3..n_2];
+
+            Ok(Self::new(length, com_adr, control_byte, data, crc16, false))
+        }
     }
 
+    #[derive(Debug)]
     pub struct ReaderToHost {
-        length: u8,
-        com_adr: u8,
-        control_byte: u8,
-        status: u8,
-        data: std::vec::Vec<u8>,
-        crc16: u16,
+        pub length: u8,
+        pub com_adr: u8,
+        pub control_byte: u8,
+        pub status: u8,
+        pub data: std::vec::Vec<u8>,
+        pub crc16: u16,
+    }
+
+    impl ReaderToHost {
+        pub fn new(
+            length: u8,
+            com_adr: u8,
+            control_byte: u8,
+            status: u8,
+            data: &[u8],
+            crc16: u16,
+        ) -> ReaderToHost {
+            ReaderToHost {
+                length,
+                com_adr,
+                control_byte,
+                status,
+                data: data.to_vec(),
+                crc16,
+            }
+        }
+    }
+
+    impl From<ReaderToHost> for advanced_protocol::ReaderToHost {
+        fn from(message: ReaderToHost) -> advanced_protocol::ReaderToHost {
+            advanced_protocol::ReaderToHost::new(
+                message.length as u16 + 2, // STX and the extra byte of ALENGTH
+                message.com_adr,
+                message.control_byte,
+                message.status,
+                message.data.as_slice(),
+                message.crc16,
+            )
+        }
+    }
+
+    impl PartialEq for ReaderToHost {
+        fn eq(&self, other: &Self) -> bool {
+            self.length == other.length
+                && self.com_adr == other.com_adr
+                && self.control_byte == other.control_byte
+                && self.status == other.status
+                && self.data == other.data
+                && self.crc16 == other.crc16
+        }
+    }
+
+    impl ObidSerialReceivable for ReaderToHost {
+        fn _serialize(self: &mut Self) -> std::vec::Vec<u8> {
+            // Reserve a vector of exactly the correct size
+            self.length = (1 + 1 + 1 + 1 + self.data.len() + 2) as u8;
+            let mut msg = vec![0u8; self.length as usize];
+
+            // Encode the values up to crc16
+            msg[0] = self.length; // LENGTH
+            msg[1] = self.com_adr;
+            msg[2] = self.control_byte;
+            msg[3] = self.status;
+            if self.data.len() > 0 {
+                msg[4..(4 + self.data.len())].clone_from_slice(self.data.as_slice());
+                // DATA
+            }
+
+            // Compute and encode crc16
+            let n_2 = (self.length - 2) as usize;
+            self.crc16 = calc_crc16(&msg[..n_2]);
+            LittleEndian::write_u16(&mut msg[n_2..], self.crc16);
+
+            msg
+        }
+
+        fn deserialize(data: &[u8]) -> Result<Self, Box<dyn std::error::Error>>
+        where
+            Self: Sized,
+        {
+            if data.len() < 6 {
+                let error_message = String::from("Invalid (short) length for ReaderToHost message");
+                log::error!("Failed deserializing Obid message: {}", error_message);
+                return Err(Box::new(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    error_message,
+                )));
+            }
+
+            if data[0] as usize != data.len() {
+                let error_message = format!(
+                    "Invalid LENGTH {} for ReaderToHost message of {} bytes",
+                    data[0],
+                    data.len()
+                );
+                log::error!("Failed deserializing Obid message: {}", error_message);
+                return Err(Box::new(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    error_message,
+                )));
+            }
+
+            let n_2 = data.len() - 2;
+            let crc16 = LittleEndian::read_u16(&data[n_2..]);
+            if calc_crc16(&data[..n_2]) != crc16 {
+                let error_message = String::from("Invalid CRC16 for ReaderToHost message");
+                log::error!("Failed deserializing Obid message: {}", error_message);
+                return Err(Box::new(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    error_message,
+                )));
+            }
+
+            let length = data[0];
+            let com_adr = data[1];
+            let control_byte = data[2];
+            let status = data[3];
+            let data = &data[4..n_2];
+
+            Ok(Self::new(
+                length,
+                com_adr,
+                control_byte,
+                status,
+                data,
+                crc16,
+            ))
+        }
     }
 }
 
@@ -187,7 +434,7 @@ pub mod advanced_protocol {
         }
     }
 
-    #[derive(Debug)]
+    #[derive(Clone, Debug)]
     pub struct ReaderToHost {
         stx: std::marker::PhantomData<u8>,
         pub alength: u16,
@@ -217,6 +464,12 @@ pub mod advanced_protocol {
                 crc16,
             }
         }
+
+        /// Returns true if the response came from the reader addressed by the request
+        /// Any reader may answer a broadcast request
+        pub fn is_response_to(self: &Self, request: &HostToReader) -> bool {
+            request.com_adr == super::BROADCAST_COM_ADR || request.com_adr == self.com_adr
+        }
     }
 
     impl PartialEq for ReaderToHost {
@@ -297,10 +550,167 @@ pub mod advanced_protocol {
     }
 }
 
+/// The framing used on the wire for messages between the host and the reader
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Framing {
+    /// 1-byte LENGTH, which limits a frame to 255 bytes
+    Standard,
+    /// STX followed by a 2-byte ALENGTH
+    Advanced,
+}
+
+impl Framing {
+    /// Marshal the message in this framing
+    /// Messages too big for standard protocol are always sent with advanced protocol
+    pub fn serialize(
+        self: &Self,
+        message: &mut advanced_protocol::HostToReader,
+    ) -> std::vec::Vec<u8> {
+        match self {
+            Framing::Standard if standard_protocol::HostToReader::fits(message.data.as_slice()) => {
+                standard_protocol::HostToReader::from(&*message).serialize()
+            }
+            Framing::Standard => {
+                log::debug!("Message is too big for standard protocol, using advanced protocol");
+                message.serialize()
+            }
+            Framing::Advanced => message.serialize(),
+        }
+    }
+
+    /// Unmarshal a response in this framing
+    /// A standard LENGTH is never as small as STX, so a leading STX is the reader falling back
+    /// to advanced protocol for a response that is too big for standard protocol
+    pub fn deserialize(
+        self: &Self,
+        data: &[u8],
+    ) -> Result<advanced_protocol::ReaderToHost, Box<dyn std::error::Error>> {
+        match self {
+            Framing::Standard if data.first() != Some(&0x02) => {
+                Ok(standard_protocol::ReaderToHost::deserialize(data)?.into())
+            }
+            Framing::Standard => {
+                log::debug!(
+                    "Reader responded with advanced protocol to a standard protocol request"
+                );
+                advanced_protocol::ReaderToHost::deserialize(data)
+            }
+            Framing::Advanced => advanced_protocol::ReaderToHost::deserialize(data),
+        }
+    }
+}
+
+impl std::str::FromStr for Framing {
+    type Err = String;
+
+    fn from_str(framing: &str) -> Result<Self, Self::Err> {
+        match framing {
+            "standard" => Ok(Framing::Standard),
+            "advanced" => Ok(Framing::Advanced),
+            other => Err(format!("Invalid framing: {} not supported", other)),
+        }
+    }
+}
+
+/// Incrementally reassembles ReaderToHost frames from a byte stream
+///
+/// Reads from a stream may return part of a frame or several frames at once, so bytes are
+/// buffered until the length in the header has arrived. Bytes that cannot start a frame are
+/// discarded to resync on the next frame.
+pub struct ObidFrameDecoder {
+    framing: Framing,
+    buffer: std::vec::Vec<u8>,
+}
+
+impl ObidFrameDecoder {
+    pub fn new(framing: Framing) -> ObidFrameDecoder {
+        ObidFrameDecoder {
+            framing,
+            buffer: vec![],
+        }
+    }
+
+    /// Append bytes received from the reader
+    pub fn push(self: &mut Self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// The number of bytes waiting to complete a frame
+    pub fn buffered(self: &Self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Drop any buffered bytes, such as the remains of a stale response
+    pub fn clear(self: &mut Self) {
+        self.buffer.clear();
+    }
+
+    /// Returns the next complete frame, or None if more bytes are needed
+    /// A complete frame that fails validation is dropped and returned as an error
+    pub fn next_frame(
+        self: &mut Self,
+    ) -> Result<Option<advanced_protocol::ReaderToHost>, Box<dyn std::error::Error>> {
+        loop {
+            let frame_length = match self.frame_length() {
+                FrameLength::Complete(frame_length) => frame_length,
+                FrameLength::Partial => return Ok(None),
+                FrameLength::Invalid => {
+                    log::debug!("Discarding byte to resync: {:#X?}", self.buffer[0]);
+                    self.buffer.remove(0);
+                    continue;
+                }
+            };
+            if self.buffer.len() < frame_length {
+                return Ok(None);
+            }
+
+            let frame: std::vec::Vec<u8> = self.buffer.drain(..frame_length).collect();
+            return match self.framing.deserialize(frame.as_slice()) {
+                Ok(response) => Ok(Some(response)),
+                Err(err) => {
+                    // The header may have been garbage, so resync after its first byte
+                    self.buffer.splice(..0, frame[1..].iter().copied());
+                    Err(err)
+                }
+            };
+        }
+    }
+
+    fn frame_length(self: &Self) -> FrameLength {
+        let start = match self.buffer.first() {
+            Some(start) => *start,
+            None => return FrameLength::Partial,
+        };
+
+        if start == 0x02 {
+            // STX and ALENGTH of the advanced protocol
+            if self.buffer.len() < 3 {
+                FrameLength::Partial
+            } else {
+                match BigEndian::read_u16(&self.buffer[1..=2]) as usize {
+                    alength if alength < 8 => FrameLength::Invalid,
+                    alength => FrameLength::Complete(alength),
+                }
+            }
+        } else if self.framing == Framing::Standard && start >= 6 {
+            // LENGTH of the standard protocol
+            FrameLength::Complete(start as usize)
+        } else {
+            FrameLength::Invalid
+        }
+    }
+}
+
+enum FrameLength {
+    Partial,
+    Invalid,
+    Complete(usize),
+}
+
 #[cfg(test)]
 mod tests {
-    use super::advanced_protocol;
-    use super::{calc_crc16, ObidSerialReceivable, ObidSerialSendable};
+    use super::{advanced_protocol, standard_protocol};
+    use super::{calc_crc16, Framing, ObidFrameDecoder, ObidSerialReceivable, ObidSerialSendable};
 
     #[test]
     fn crc16_works() {
@@ -325,7 +735,7 @@ mod tests {
             .with_level(log::LevelFilter::Debug)
             .init();
 
-        let data = vec![];
+        let data: [u8; 0] = [];
         let mut expected_msg = advanced_protocol::HostToReader::new(0, 0, 0, &data[..], 0, false);
         let marshalled_data = expected_msg.serialize();
         log::trace!("Serial Message: {:#X?}", marshalled_data.as_slice());
@@ -341,7 +751,7 @@ mod tests {
             .with_level(log::LevelFilter::Debug)
             .init();
 
-        let data = vec![1; 10];
+        let data = [1; 10];
         let mut expected_msg = advanced_protocol::HostToReader::new(0, 0, 0, &data[..], 0, false);
         let marshalled_data = expected_msg.serialize();
         log::trace!("Serial Message: {:#X?}", marshalled_data.as_slice());
@@ -357,7 +767,7 @@ mod tests {
             .with_level(log::LevelFilter::Debug)
             .init();
 
-        let data = vec![];
+        let data: [u8; 0] = [];
         let mut expected_msg = advanced_protocol::ReaderToHost::new(8, 0, 0, 0, &data[..], 0);
         let marshalled_data = expected_msg._serialize();
         log::trace!("Serial Message: {:#X?}", marshalled_data.as_slice());
@@ -372,7 +782,7 @@ mod tests {
             .with_level(log::LevelFilter::Debug)
             .init();
 
-        let data = vec![1; 100];
+        let data = [1; 100];
         let mut expected_msg = advanced_protocol::ReaderToHost::new(8, 0, 0, 0, &data[..], 0);
         let marshalled_data = expected_msg._serialize();
         log::trace!("Serial Message: {:#X?}", marshalled_data.as_slice());
@@ -402,7 +812,7 @@ mod tests {
             .with_level(log::LevelFilter::Debug)
             .init();
 
-        let data = vec![1; 100];
+        let data = [1; 100];
         let mut expected_msg = advanced_protocol::ReaderToHost::new(8, 0, 0, 0, &data[..], 0);
         let mut marshalled_data = expected_msg._serialize();
         log::trace!("Serial Message: {:#X?}", marshalled_data.as_slice());
@@ -418,7 +828,7 @@ mod tests {
             .with_level(log::LevelFilter::Debug)
             .init();
 
-        let data = vec![1; 100];
+        let data = [1; 100];
         let mut expected_msg = advanced_protocol::ReaderToHost::new(8, 0, 0, 0, &data[..], 0);
         let marshalled_data = expected_msg._serialize();
         log::trace!("Serial Message: {:#X?}", marshalled_data.as_slice());
@@ -426,4 +836,271 @@ mod tests {
         let msg = advanced_protocol::ReaderToHost::deserialize(&marshalled_data[0..7]);
         assert!(msg.is_err());
     }
+
+    #[test]
+    fn standard_host_to_reader_length() {
+        let mut inventory_request = standard_protocol::HostToReader::new(
+            0,
+            0xFF,
+            0xB0,
+            vec![0x01, 0x00].as_slice(),
+            0,
+            false,
+        );
+        let inventory_request = inventory_request.serialize();
+        assert_eq!(7, inventory_request.len());
+        assert_eq!(7, inventory_request[0]);
+        assert_eq!(&[0xFF, 0xB0, 0x01, 0x00], &inventory_request[1..5]);
+    }
+
+    #[test]
+    fn standard_host_to_reader_no_data() {
+        let _ = simple_logger::SimpleLogger::new()
+            .with_level(log::LevelFilter::Debug)
+            .init();
+
+        let data: [u8; 0] = [];
+        let mut expected_msg = standard_protocol::HostToReader::new(0, 0, 0, &data[..], 0, false);
+        let marshalled_data = expected_msg.serialize();
+        log::trace!("Serial Message: {:#X?}", marshalled_data.as_slice());
+
+        let msg =
+            standard_protocol::HostToReader::_deserialize(marshalled_data.as_slice()).unwrap();
+        assert!(expected_msg == msg);
+    }
+
+    #[test]
+    fn standard_host_to_reader_some_data() {
+        let _ = simple_logger::SimpleLogger::new()
+            .with_level(log::LevelFilter::Debug)
+            .init();
+
+        let data = [1; 10];
+        let mut expected_msg = standard_protocol::HostToReader::new(0, 0, 0, &data[..], 0, false);
+        let marshalled_data = expected_msg.serialize();
+        log::trace!("Serial Message: {:#X?}", marshalled_data.as_slice());
+
+        let msg =
+            standard_protocol::HostToReader::_deserialize(marshalled_data.as_slice()).unwrap();
+        assert!(expected_msg == msg);
+    }
+
+    #[test]
+    fn standard_reader_to_host_no_data() {
+        let _ = simple_logger::SimpleLogger::new()
+            .with_level(log::LevelFilter::Debug)
+            .init();
+
+        let data: [u8; 0] = [];
+        let mut expected_msg = standard_protocol::ReaderToHost::new(6, 0, 0, 0, &data[..], 0);
+        let marshalled_data = expected_msg._serialize();
+        log::trace!("Serial Message: {:#X?}", marshalled_data.as_slice());
+
+        let msg = standard_protocol::ReaderToHost::deserialize(marshalled_data.as_slice()).unwrap();
+        assert!(expected_msg == msg);
+    }
+
+    #[test]
+    fn standard_reader_to_host_some_data() {
+        let _ = simple_logger::SimpleLogger::new()
+            .with_level(log::LevelFilter::Debug)
+            .init();
+
+        let data = [1; 100];
+        let mut expected_msg = standard_protocol::ReaderToHost::new(6, 0, 0, 0, &data[..], 0);
+        let marshalled_data = expected_msg._serialize();
+        log::trace!("Serial Message: {:#X?}", marshalled_data.as_slice());
+
+        let msg = standard_protocol::ReaderToHost::deserialize(marshalled_data.as_slice()).unwrap();
+        assert!(expected_msg == msg);
+    }
+
+    #[test]
+    fn standard_reader_to_host_bad_crc() {
+        let _ = simple_logger::SimpleLogger::new()
+            .with_level(log::LevelFilter::Debug)
+            .init();
+
+        let data = [1; 100];
+        let mut expected_msg = standard_protocol::ReaderToHost::new(6, 0, 0, 0, &data[..], 0);
+        let mut marshalled_data = expected_msg._serialize();
+        log::trace!("Serial Message: {:#X?}", marshalled_data.as_slice());
+
+        *marshalled_data.last_mut().unwrap() = *marshalled_data.last().unwrap() + 1;
+        let msg = standard_protocol::ReaderToHost::deserialize(marshalled_data.as_slice());
+        assert!(msg.is_err());
+    }
+
+    #[test]
+    fn standard_reader_to_host_bad_length() {
+        let _ = simple_logger::SimpleLogger::new()
+            .with_level(log::LevelFilter::Debug)
+            .init();
+
+        let data = [1; 100];
+        let mut expected_msg = standard_protocol::ReaderToHost::new(6, 0, 0, 0, &data[..], 0);
+        let marshalled_data = expected_msg._serialize();
+        log::trace!("Serial Message: {:#X?}", marshalled_data.as_slice());
+
+        let msg = standard_protocol::ReaderToHost::deserialize(&marshalled_data[0..50]);
+        assert!(msg.is_err());
+    }
+
+    #[test]
+    fn framing_standard_round_trip() {
+        let _ = simple_logger::SimpleLogger::new()
+            .with_level(log::LevelFilter::Debug)
+            .init();
+
+        let data = vec![1; 10];
+        let mut expected_msg = standard_protocol::ReaderToHost::new(6, 0, 0xB0, 0, &data[..], 0);
+        let marshalled_data = expected_msg._serialize();
+
+        let msg = Framing::Standard
+            .deserialize(marshalled_data.as_slice())
+            .unwrap();
+        assert_eq!(marshalled_data.len() + 2, msg.alength as usize);
+        assert_eq!(0xB0, msg.control_byte);
+        assert_eq!(data, msg.data);
+    }
+
+    #[test]
+    fn framing_standard_falls_back_to_advanced() {
+        let _ = simple_logger::SimpleLogger::new()
+            .with_level(log::LevelFilter::Debug)
+            .init();
+
+        // The request is too big for standard protocol
+        let data = vec![1; 251];
+        let mut request = advanced_protocol::HostToReader::new(0, 0xFF, 0xB0, &data[..], 0, false);
+        let marshalled_request = Framing::Standard.serialize(&mut request);
+        assert_eq!(0x02, marshalled_request[0]);
+        assert_eq!(1 + 2 + 1 + 1 + 251 + 2, marshalled_request.len());
+
+        // The response is too big for standard protocol
+        let data = vec![1; 1000];
+        let mut expected_msg = advanced_protocol::ReaderToHost::new(8, 0, 0, 0, &data[..], 0);
+        let marshalled_data = expected_msg._serialize();
+
+        let msg = Framing::Standard
+            .deserialize(marshalled_data.as_slice())
+            .unwrap();
+        assert!(expected_msg == msg);
+    }
+
+    #[test]
+    fn framing_standard_fits() {
+        let data = vec![1; 250];
+        let mut request = advanced_protocol::HostToReader::new(0, 0xFF, 0xB0, &data[..], 0, false);
+        let marshalled_request = Framing::Standard.serialize(&mut request);
+        assert_eq!(0xFF, marshalled_request[0]);
+        assert_eq!(0xFF, marshalled_request.len());
+    }
+
+    #[test]
+    fn decoder_partial_frame() {
+        let _ = simple_logger::SimpleLogger::new()
+            .with_level(log::LevelFilter::Debug)
+            .init();
+
+        let data = [1; 100];
+        let mut expected_msg = advanced_protocol::ReaderToHost::new(8, 0, 0xB0, 0, &data[..], 0);
+        let marshalled_data = expected_msg._serialize();
+
+        let mut decoder = ObidFrameDecoder::new(Framing::Advanced);
+        for chunk in marshalled_data.chunks(7) {
+            assert!(decoder.next_frame().unwrap().is_none());
+            decoder.push(chunk);
+        }
+        let msg = decoder.next_frame().unwrap().unwrap();
+        assert!(expected_msg == msg);
+        assert_eq!(0, decoder.buffered());
+    }
+
+    #[test]
+    fn decoder_coalesced_frames() {
+        let _ = simple_logger::SimpleLogger::new()
+            .with_level(log::LevelFilter::Debug)
+            .init();
+
+        let mut first_msg = advanced_protocol::ReaderToHost::new(8, 0, 0xB0, 0, &[1; 10], 0);
+        let mut second_msg = advanced_protocol::ReaderToHost::new(8, 0, 0x8B, 0, &[], 0);
+        let mut marshalled_data = first_msg._serialize();
+        marshalled_data.extend(second_msg._serialize());
+
+        let mut decoder = ObidFrameDecoder::new(Framing::Advanced);
+        decoder.push(marshalled_data.as_slice());
+        assert!(first_msg == decoder.next_frame().unwrap().unwrap());
+        assert!(second_msg == decoder.next_frame().unwrap().unwrap());
+        assert!(decoder.next_frame().unwrap().is_none());
+    }
+
+    #[test]
+    fn decoder_resyncs_after_garbage() {
+        let _ = simple_logger::SimpleLogger::new()
+            .with_level(log::LevelFilter::Debug)
+            .init();
+
+        let mut expected_msg = advanced_protocol::ReaderToHost::new(8, 0, 0xB0, 0, &[1; 10], 0);
+        let mut marshalled_data = vec![0xFF, 0x00, 0x02, 0x00, 0x01, 0x13];
+        marshalled_data.extend(expected_msg._serialize());
+
+        let mut decoder = ObidFrameDecoder::new(Framing::Advanced);
+        decoder.push(marshalled_data.as_slice());
+        let msg = decoder.next_frame().unwrap().unwrap();
+        assert!(expected_msg == msg);
+        assert_eq!(0, decoder.buffered());
+    }
+
+    #[test]
+    fn decoder_bad_crc_then_resync() {
+        let _ = simple_logger::SimpleLogger::new()
+            .with_level(log::LevelFilter::Debug)
+            .init();
+
+        let mut bad_msg = advanced_protocol::ReaderToHost::new(8, 0, 0xB0, 0, &[1; 10], 0);
+        let mut marshalled_data = bad_msg._serialize();
+        *marshalled_data.last_mut().unwrap() = *marshalled_data.last().unwrap() + 1;
+        let mut expected_msg = advanced_protocol::ReaderToHost::new(8, 0, 0x8B, 0, &[], 0);
+        marshalled_data.extend(expected_msg._serialize());
+
+        let mut decoder = ObidFrameDecoder::new(Framing::Advanced);
+        decoder.push(marshalled_data.as_slice());
+        assert!(decoder.next_frame().is_err());
+        let msg = decoder.next_frame().unwrap().unwrap();
+        assert!(expected_msg == msg);
+    }
+
+    #[test]
+    fn decoder_standard_frames() {
+        let _ = simple_logger::SimpleLogger::new()
+            .with_level(log::LevelFilter::Debug)
+            .init();
+
+        let mut first_msg = standard_protocol::ReaderToHost::new(6, 0, 0xB0, 0, &[1; 10], 0);
+        let mut second_msg = advanced_protocol::ReaderToHost::new(8, 0, 0xB0, 0, &[1; 300], 0);
+        let mut marshalled_data = first_msg._serialize();
+        marshalled_data.extend(second_msg._serialize());
+
+        let mut decoder = ObidFrameDecoder::new(Framing::Standard);
+        for chunk in marshalled_data.chunks(5) {
+            decoder.push(chunk);
+        }
+        let msg = decoder.next_frame().unwrap().unwrap();
+        assert_eq!(first_msg.data, msg.data);
+        assert!(second_msg == decoder.next_frame().unwrap().unwrap());
+    }
+
+    #[test]
+    fn response_com_adr_matches_request() {
+        let broadcast = advanced_protocol::HostToReader::new(0, 0xFF, 0x64, &[0], 0, false);
+        let addressed = advanced_protocol::HostToReader::new(0, 0x01, 0x64, &[0], 0, false);
+        let from_first = advanced_protocol::ReaderToHost::new(8, 0x01, 0x64, 0, &[], 0);
+        let from_second = advanced_protocol::ReaderToHost::new(8, 0x02, 0x64, 0, &[], 0);
+
+        assert!(from_first.is_response_to(&broadcast));
+        assert!(from_second.is_response_to(&broadcast));
+        assert!(from_first.is_response_to(&addressed));
+        assert!(!from_second.is_response_to(&addressed));
+    }
 }
@@ -1,4 +1,6 @@
+use crate::conn::common::ReaderInfo;
 use crate::error::*;
+use crate::obid::{ConfigLocation, ReaderDiagnostics, RfInterfaceConfig};
 use crate::protocol::haptic;
 use core::fmt::Debug;
 use serde::{Deserialize, Serialize};
@@ -15,39 +17,176 @@ pub enum CommandMessage {
     SystemReset {},
     SetRadioFreqPower {
         power_level: u8,
+        location: Option<ConfigLocation>,
+    },
+    ReadConfiguration {
+        address: u8,
+        location: Option<ConfigLocation>,
+    },
+    Configuration {
+        address: u8,
+        location: ConfigLocation,
+        data: String,
+        rf_interface: Option<RfInterfaceConfig>,
     },
     CustomCommand {
         control_byte: u8,
         data: String,
         device_required: bool,
     },
+    Response {
+        com_adr: u8,
+        control_byte: u8,
+        status: u8,
+        data: String,
+    },
 
     RfFieldState {
         state: u8,
     },
 
+    ReaderInfo {},
+    Info {
+        reader_info: Option<ReaderInfo>,
+    },
+
+    ReaderDiagnostics {},
+    Diagnostics {
+        diagnostics: ReaderDiagnostics,
+    },
+
+    Addressed {
+        com_adr: u8,
+        message: Box<CommandMessage>,
+    },
+    Routed {
+        reader: String,
+        message: Box<CommandMessage>,
+    },
+
+    Inventory {},
+    Transponders {
+        transponders: Vec<TransponderInfo>,
+    },
+
     AddFabric {
         fabric_name: String,
+        /// The hex UIDs of the transponders of the fabric instead of whatever answers an inventory
+        uids: Option<Vec<String>>,
+        /// Whether the UIDs must answer an inventory before they are registered, which is the default
+        check_uids: Option<bool>,
     },
     RemoveFabric {
         fabric_name: String,
     },
+    FabricState {
+        fabric_name: String,
+    },
+    Fabric {
+        fabric_name: String,
+        uids: Vec<String>,
+        state: Option<haptic::v0::ActuatorsCommand>,
+    },
     ActuatorsCommand {
         fabric_name: String,
         timer_mode_blocks: Option<haptic::v0::TimerModeBlocks>,
         actuator_mode_blocks: Option<haptic::v0::ActuatorModeBlocks>,
         op_mode_block: Option<haptic::v0::OpModeBlock>,
         use_cache: Option<bool>,
+        /// Whether to read the blocks back from the transponder and fail unless they match
+        verify: Option<bool>,
+    },
+    SetActuators {
+        fabric_name: String,
+        /// The indices 0 to 71 of the actuators to turn on, which one write can hold, where the rest are off
+        on: Vec<u16>,
+        /// The command for the actuators that are on
+        command: u8,
+        timing: Option<haptic::v0::timing::Timing>,
+        use_cache: Option<bool>,
+        verify: Option<bool>,
+    },
+    PlayPattern {
+        fabric_name: String,
+        /// The command for the actuators that are on in each frame
+        command: u8,
+        frames: Vec<PatternFrame>,
+        /// How many times to play the frames, where 0 plays them until a StopPattern and the default is once
+        repeat: Option<u32>,
+    },
+    StopPattern {
+        fabric_name: String,
+    },
+    ReadActuatorState {
+        fabric_name: String,
+    },
+    ActuatorState {
+        fabric_name: String,
+        /// The hex UID of the transponder the blocks were read from
+        uid: String,
+        /// The hex bytes of the blocks as stored in the transponder
+        data: String,
+        memory: haptic::v0::ActuatorMemory,
     },
 }
 
 pub trait Protocol<'a> {
-    fn handle_message(self: &mut Self, message: &CommandMessage) -> Result<()>;
+    /// Handles the request and returns the reply for the client
+    fn handle_message(self: &mut Self, message: &CommandMessage) -> Result<CommandMessage>;
+
+    /// How long the reader needs to reboot after a SystemReset
+    fn reset_delay(self: &Self) -> std::time::Duration {
+        std::time::Duration::from_millis(1)
+    }
+
+    /// Reconnect to the reader once it has rebooted, keeping the fabrics and their state
+    fn reinitialize(self: &mut Self) -> Result<()> {
+        Ok(())
+    }
+
+    /// The registered fabrics, which the server saves to the state file after every change
+    fn fabrics(self: &Self) -> Vec<FabricRecord> {
+        vec![]
+    }
+
+    /// Register the fabrics saved by an earlier host without looking for them in the antenna
+    fn restore_fabrics(self: &mut Self, _fabrics: Vec<FabricRecord>) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// A transponder that answered an inventory
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct TransponderInfo {
+    pub uid: String,
+    pub tr_type_rf_tec: u8,
+    pub tr_type_type_no: u8,
+    pub dsfid: u8,
+    /// The registered fabric the transponder belongs to, if any
+    pub fabric_name: Option<String>,
+}
+
+/// A step of a pattern, which turns on the actuators by index for duration_ms
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct PatternFrame {
+    pub actuators: Vec<u16>,
+    pub timers: Option<haptic::v0::timing::Timing>,
+    pub duration_ms: u64,
+}
+
+/// A fabric and the last actuator state written to it, as kept in the state file
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct FabricRecord {
+    pub fabric_name: String,
+    /// The hex UIDs of the transponders of the fabric
+    pub uids: Vec<String>,
+    pub state: Option<haptic::v0::ActuatorsCommand>,
 }
 
 pub trait Fabric {
     fn name(self: &Self) -> String;
     fn identifier(self: &Self) -> Result<std::vec::Vec<u8>>;
+    fn uids(self: &Self) -> std::vec::Vec<std::vec::Vec<u8>>;
 }
 
 impl Debug for dyn Fabric {
@@ -5,13 +5,10 @@ use crate::protocol::common::*;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
-#[derive(Debug)]
-pub struct ObidTransponder {
-    pub uid: smallvec::SmallVec<[u8; 8]>, // 8-byte serial number
-    pub tr_type_rf_tec: u8,
-    pub tr_type_type_no: u8,
-    pub dsfid: u8,
-}
+pub use crate::obid::ObidTransponder;
+
+pub mod codec;
+pub mod timing;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct CustomCommand {
@@ -20,7 +17,7 @@ pub struct CustomCommand {
     pub device_required: bool,
 }
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
+#[derive(PartialEq, Clone, Serialize, Deserialize, Debug)]
 pub struct OpModeBlock {
     pub act_cnt8: u8,
     pub cmd_op: u8,
@@ -35,7 +32,7 @@ pub struct ActuatorModeBlock {
     pub b3: u8,
 }
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
+#[derive(PartialEq, Clone, Serialize, Deserialize, Debug)]
 pub struct ActuatorModeBlocks {
     pub block0_31: Option<ActuatorModeBlock>,
     pub block32_63: Option<ActuatorModeBlock>,
@@ -43,21 +40,28 @@ pub struct ActuatorModeBlocks {
     pub block96_127: Option<ActuatorModeBlock>,
 }
 
+/**
+ * A timer as the firmware reads it: the time on, or the pulse, in b0 and
+ * b1 and the period, or the pause, in b2 and b3, each 16 bits LSB first.
+ */
 #[derive(PartialEq, Clone, Serialize, Deserialize, Debug)]
 pub struct TimerModeBlock {
     pub b0: u8,
     pub b1: u8,
     pub b2: u8,
+    /// The high byte of the period or pause, which is 0 when left out
+    #[serde(default)]
+    pub b3: u8,
 }
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
+#[derive(PartialEq, Clone, Serialize, Deserialize, Debug)]
 pub struct TimerModeBlocks {
     pub single_pulse_block: Option<TimerModeBlock>,
     pub hf_block: Option<TimerModeBlock>,
     pub lf_block: Option<TimerModeBlock>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(PartialEq, Clone, Serialize, Deserialize, Debug)]
 pub struct ActuatorsCommand {
     pub fabric_name: String,
     pub op_mode_block: Option<OpModeBlock>,
@@ -66,6 +70,169 @@ pub struct ActuatorsCommand {
     pub use_cache: Option<bool>,
 }
 
+/// The number of actuators each of the actuator mode blocks turns on or off
+pub const ACTUATORS_PER_BLOCK: u16 = 32;
+
+/// The actuators are numbered 0 to 127 across the four actuator mode blocks
+pub const MAX_ACTUATORS: u16 = 4 * ACTUATORS_PER_BLOCK;
+
+/// How many times an inventory asks for more transponders before giving up on a reader that keeps sending MoreData
+pub const MAX_INVENTORY_ROUNDS: usize = 16;
+
+impl ActuatorModeBlock {
+    fn from_bytes(b: [u8; 4]) -> ActuatorModeBlock {
+        ActuatorModeBlock {
+            b0: b[0],
+            b1: b[1],
+            b2: b[2],
+            b3: b[3],
+        }
+    }
+
+    fn bytes(self: &Self) -> [u8; 4] {
+        [self.b0, self.b1, self.b2, self.b3]
+    }
+}
+
+impl ActuatorModeBlocks {
+    /**
+     * Every block with the bits of the actuators that are on, where
+     * actuator n is bit n % 8 of byte n / 8, counting from b0 of block0_31.
+     */
+    pub fn from_indices(on: &[u16]) -> Result<ActuatorModeBlocks> {
+        let mut bytes = [0u8; (MAX_ACTUATORS / 8) as usize];
+        for index in on {
+            if *index >= MAX_ACTUATORS {
+                return Err(InternalError::from(format!(
+                    "Expected actuator indices from 0 to {} but found {}",
+                    MAX_ACTUATORS - 1,
+                    index
+                )));
+            }
+            bytes[(index / 8) as usize] |= 1 << (index % 8);
+        }
+        let mut blocks = bytes.chunks(4).map(|chunk| {
+            let mut b = [0u8; 4];
+            b.copy_from_slice(chunk);
+            ActuatorModeBlock::from_bytes(b)
+        });
+        Ok(ActuatorModeBlocks {
+            block0_31: blocks.next(),
+            block32_63: blocks.next(),
+            block64_95: blocks.next(),
+            block96_127: blocks.next(),
+        })
+    }
+
+    /// The indices of the actuators that are on, in order
+    pub fn indices(self: &Self) -> std::vec::Vec<u16> {
+        let blocks = [
+            &self.block0_31,
+            &self.block32_63,
+            &self.block64_95,
+            &self.block96_127,
+        ];
+        let mut on = vec![];
+        for (n, block) in blocks.iter().enumerate() {
+            if let Some(block) = block {
+                for (i, byte) in block.bytes().iter().enumerate() {
+                    for bit in 0..8 {
+                        if byte & (1 << bit) != 0 {
+                            on.push(n as u16 * ACTUATORS_PER_BLOCK + i as u16 * 8 + bit);
+                        }
+                    }
+                }
+            }
+        }
+        on
+    }
+}
+
+impl ActuatorsCommand {
+    /**
+     * Turn on the actuators by index and turn off the rest, where encoding
+     * lays out act_cnt8 and cmd_op for them. No actuators turns the fabric
+     * all off, so timing without actuators is rejected. One write holds the
+     * bytes of the first 72 actuators, so later ones are rejected rather
+     * than dropped, as is timing that doesn't fit after the actuators.
+     */
+    pub fn set_actuators(
+        fabric_name: &str,
+        on: &[u16],
+        command: u8,
+        timing: Option<&timing::Timing>,
+    ) -> Result<ActuatorsCommand> {
+        let max_actuators = (codec::MAX_ACTUATOR_BYTES * 8) as u16;
+        if let Some(index) = on.iter().find(|index| **index >= max_actuators) {
+            return Err(InternalError::from(format!(
+                "Expected actuators from 0 to {}, which one write can hold, but found {}",
+                max_actuators - 1,
+                index
+            )));
+        }
+        let actuator_mode_blocks = ActuatorModeBlocks::from_indices(on)?;
+        let op_mode_block = match on.first() {
+            None if timing.is_some() => {
+                return Err(InternalError::from(
+                    "Expected actuators to turn on with the timing, but found none, which turns every actuator off",
+                ))
+            }
+            None => OpModeBlock {
+                act_cnt8: 0,
+                cmd_op: 1,
+                command: 0,
+            },
+            Some(_) if command == 0 => {
+                return Err(InternalError::from(
+                    "Expected a command other than 0, which turns every actuator off",
+                ))
+            }
+            Some(_) => OpModeBlock {
+                act_cnt8: 0,
+                cmd_op: 2,
+                command,
+            },
+        };
+        let timer_mode_blocks = match timing {
+            Some(timing) => Some(timing.timer_mode_blocks()?),
+            None => None,
+        };
+        if let (Some(last), Some(blocks)) = (on.iter().max(), &timer_mode_blocks) {
+            // The timers share the blocks with the actuator bytes up to the last one that is on
+            let actuator_bytes = (last / 8 + 1) as usize;
+            let timer_bytes = codec::timer_bytes(blocks).len();
+            if timer_bytes > codec::timer_space(actuator_bytes) {
+                return Err(InternalError::from(format!(
+                    "The timing takes {} bytes but only {} fit after actuator {}",
+                    timer_bytes,
+                    codec::timer_space(actuator_bytes),
+                    last
+                )));
+            }
+        }
+        Ok(ActuatorsCommand {
+            fabric_name: String::from(fabric_name),
+            op_mode_block: Some(op_mode_block),
+            actuator_mode_blocks: Some(actuator_mode_blocks),
+            timer_mode_blocks,
+            use_cache: None,
+        })
+    }
+}
+
+/**
+ * The actuator configuration as laid out in the first memory blocks of the
+ * transponder, which hold num_bytes, op_mode and command followed by the
+ * actuator and timer bytes. Each block is stored LSB first.
+ */
+#[derive(PartialEq, Clone, Serialize, Deserialize, Debug)]
+pub struct ActuatorMemory {
+    pub num_bytes: u8,
+    pub op_mode_block: OpModeBlock,
+    pub actuator_mode_blocks: Option<ActuatorModeBlocks>,
+    pub timer_mode_blocks: Option<TimerModeBlocks>,
+}
+
 pub struct V0FabricState {
     pub state: ActuatorsCommand,
 }
@@ -111,16 +278,19 @@ impl V0FabricState {
                         b0: 0,
                         b1: 0,
                         b2: 0,
+                        b3: 0,
                     }),
                     hf_block: Some(TimerModeBlock {
                         b0: 0,
                         b1: 0,
                         b2: 0,
+                        b3: 0,
                     }),
                     lf_block: Some(TimerModeBlock {
                         b0: 0,
                         b1: 0,
                         b2: 0,
+                        b3: 0,
                     }),
                 }),
                 use_cache: Some(false),
@@ -202,8 +372,18 @@ impl V0FabricState {
 
     // Update the state of the fabric with the new state
     pub fn apply(self: &mut Self, new_state: ActuatorsCommand) {
+        self.state = self.merged(new_state);
+    }
+
+    /**
+     * The state of the fabric after the new actuators command, where the
+     * blocks it leaves out keep their state. That includes the op_mode, so a
+     * command with only actuator blocks is written with the cached command.
+     */
+    pub fn merged(self: &Self, new_state: ActuatorsCommand) -> ActuatorsCommand {
         let diff = self.diff(new_state);
 
+        let new_actuator_blocks = diff.actuator_mode_blocks.as_ref().unwrap();
         let curr_actuator_blocks = self.state.actuator_mode_blocks.as_ref().unwrap();
 
         let new_timer_blocks = &diff
@@ -211,14 +391,28 @@ impl V0FabricState {
             .unwrap_or(self.state.timer_mode_blocks.clone().unwrap());
         let curr_timer_blocks = self.state.timer_mode_blocks.as_ref().unwrap();
 
-        self.state = ActuatorsCommand {
+        ActuatorsCommand {
             fabric_name: self.state.fabric_name.clone(),
-            op_mode_block: diff.op_mode_block,
+            op_mode_block: diff
+                .op_mode_block
+                .or_else(|| self.state.op_mode_block.clone()),
             actuator_mode_blocks: Some(ActuatorModeBlocks {
-                block0_31: curr_actuator_blocks.block0_31.clone(),
-                block32_63: curr_actuator_blocks.block32_63.clone(),
-                block64_95: curr_actuator_blocks.block64_95.clone(),
-                block96_127: curr_actuator_blocks.block96_127.clone(),
+                block0_31: new_actuator_blocks
+                    .block0_31
+                    .clone()
+                    .or_else(|| curr_actuator_blocks.block0_31.clone()),
+                block32_63: new_actuator_blocks
+                    .block32_63
+                    .clone()
+                    .or_else(|| curr_actuator_blocks.block32_63.clone()),
+                block64_95: new_actuator_blocks
+                    .block64_95
+                    .clone()
+                    .or_else(|| curr_actuator_blocks.block64_95.clone()),
+                block96_127: new_actuator_blocks
+                    .block96_127
+                    .clone()
+                    .or_else(|| curr_actuator_blocks.block96_127.clone()),
             }),
             timer_mode_blocks: Some(TimerModeBlocks {
                 single_pulse_block: if new_timer_blocks.single_pulse_block.is_some() {
@@ -238,7 +432,7 @@ impl V0FabricState {
                 },
             }),
             use_cache: Some(true), // Use the cache once the cache starts applying on top of its own state
-        };
+        }
     }
 }
 
@@ -286,23 +480,57 @@ impl Fabric for V0Fabric {
             }
         }
     }
+
+    fn uids(self: &Self) -> std::vec::Vec<std::vec::Vec<u8>> {
+        self.transponders
+            .iter()
+            .map(|transponder| transponder.uid.to_vec())
+            .collect()
+    }
+}
+
+/// A transponder known only by its hex UID, such as one given by the client
+fn parse_transponder(uid: &str) -> Result<ObidTransponder> {
+    let uid = hex::decode(uid)?;
+    if uid.len() != 8 {
+        return Err(InternalError::from(format!(
+            "Expected UID, which is a serial number of 8 bytes, but found {} bytes",
+            uid.len()
+        )));
+    }
+    Ok(ObidTransponder {
+        uid: smallvec::SmallVec::from_vec(uid),
+        tr_type_rf_tec: 0,
+        tr_type_type_no: 0,
+        dsfid: 0,
+    })
 }
 
 pub struct HapticV0Protocol<'a> {
     conn: Box<dyn Connection<'a> + 'a>,
+    com_adr: u8, // Address of the reader on the bus for requests
     fabrics: HashMap<String, Box<dyn Fabric>>,
     states: HashMap<String, V0FabricState>,
 }
 
 impl<'a> HapticV0Protocol<'a> {
-    pub fn new(connection: Box<dyn Connection<'a> + 'a>) -> HapticV0Protocol<'a> {
+    pub fn new(connection: Box<dyn Connection<'a> + 'a>, com_adr: u8) -> HapticV0Protocol<'a> {
         HapticV0Protocol {
             conn: connection,
+            com_adr,
             fabrics: HashMap::new(),
             states: HashMap::new(),
         }
     }
 
+    /// Send the command to the reader and interpret its response
+    fn execute<C: ObidCommand>(self: &mut Self, command: &C) -> Result<C::Response> {
+        let request = command.request(self.com_adr);
+        let response = self.conn.send_command(request)?;
+        log::debug!("Received response: {:#?}", response);
+        command.parse_response(&response)
+    }
+
     /**
      * This command reads the UID of all Transponders inside the antenna field.
      * If the Reader has detected a new Transponder, that Transponder will be
@@ -316,147 +544,74 @@ impl<'a> HapticV0Protocol<'a> {
         expect_device: bool,
     ) -> Result<smallvec::SmallVec<[ObidTransponder; 2]>> {
         log::trace!("Requesting inventory ids ...");
-        let inventory_request = advanced_protocol::HostToReader::new(
-            0,
-            0xFF,
-            0xB0,
-            vec![0x01, 0x00].as_slice(),
-            0,
-            expect_device,
-        );
-        let inventory_response = self.conn.send_command(inventory_request)?;
-        log::debug!("Received inventory_response: {:#?}", inventory_response);
-
-        if inventory_response.status == 0 && inventory_response.data.len() > 0 {
-            let mut transponders = smallvec::smallvec![];
-            let encoded_transponders = inventory_response.data[0];
-            let bytes_per_transponder = 1 + 1 + 8; // tr_type, dsfid, uid
-            if inventory_response.data.len()
-                != 1 + (encoded_transponders as usize) * (bytes_per_transponder as usize)
-            {
-                return Err(InternalError::from(
-                    "Unexpected data format in response to inventory request",
-                ));
-            }
-
-            for i in 0..encoded_transponders {
-                let begin = (1 + bytes_per_transponder * i) as usize;
-                let end = begin + bytes_per_transponder as usize;
-                let encoded_transponder_slice = &inventory_response.data[begin..end];
-                let tr_type = encoded_transponder_slice[0];
-                let tr_type_rf_tec = (tr_type & 0b1100_0000) >> 6;
-                let tr_type_type_no = tr_type & 0b0000_1111;
-                let dsfid = encoded_transponder_slice[1];
-                let uid = &encoded_transponder_slice[2..];
-                transponders.push(ObidTransponder {
-                    uid: smallvec::SmallVec::from(uid),
-                    tr_type_rf_tec,
-                    tr_type_type_no,
-                    dsfid,
-                });
+        let command = Inventory {
+            device_required: expect_device,
+        };
+        let mut response = self.conn.send_command(command.request(self.com_adr))?;
+        let mut transponders = command.parse_response(&response)?;
+
+        // The reader sends the rest of the transponders when asked for more
+        let mut rounds = 0;
+        while Status::from(response.status) == Status::MoreData {
+            if rounds == MAX_INVENTORY_ROUNDS {
+                return Err(InternalError::from(format!(
+                    "Expected the inventory to end within {} requests for more transponders",
+                    MAX_INVENTORY_ROUNDS
+                )));
             }
+            rounds += 1;
+            log::trace!("Requesting more inventory ids ...");
+            let command = InventoryMore {};
+            response = self.conn.send_command(command.request(self.com_adr))?;
+            transponders.extend(command.parse_response(&response)?);
+        }
+        log::debug!("Found transponders: {:?}", transponders);
+        Ok(transponders)
+    }
 
-            log::debug!("Found transponders: {:?}", transponders);
+    /// Read a CFG block from the reader
+    pub fn read_configuration<B: ConfigBlock>(
+        self: &mut Self,
+        location: ConfigLocation,
+    ) -> Result<B> {
+        log::trace!("Requesting CFG{} from {:?} ...", B::ADDRESS, location);
+        let data = self.execute(&ReadConfiguration {
+            location,
+            address: B::ADDRESS,
+        })?;
+        Ok(B::from_bytes(&data))
+    }
 
-            Ok(transponders)
-        } else {
-            Ok(smallvec::smallvec![])
-        }
+    /// Write a CFG block to the reader
+    pub fn write_configuration<B: ConfigBlock>(
+        self: &mut Self,
+        location: ConfigLocation,
+        block: &B,
+    ) -> Result<()> {
+        log::trace!("Requesting CFG{} written to {:?} ...", B::ADDRESS, location);
+        self.execute(&WriteConfiguration {
+            location,
+            address: B::ADDRESS,
+            data: block.to_bytes(),
+        })
     }
 
-    /// Set the wattage for the RF power on the antenna
-    pub fn set_radio_freq_power(self: &mut Self, rf_power: u8) -> Result<()> {
+    /// Set the wattage for the RF power on the antenna without changing the rest of CFG3
+    pub fn set_radio_freq_power(
+        self: &mut Self,
+        rf_power: u8,
+        location: ConfigLocation,
+    ) -> Result<()> {
         log::trace!("Requesting RF power set to {} ...", rf_power);
-        /*
-         * RF Power format: 0bX0111111
-         * Supported Wattage is [Low Power] union [2W, 12W] in 0.25W steps
-         *
-         * If X is 1, then 0b00111111 is interpretting as 1/4 Watts.
-         * Using 1/4 W, the boundaries are
-         *   - 0x04 -> Low Power
-         *   - 0x08 -> 2 W
-         *   - 0x00111111 -> 12 W
-         *
-         * If X is 0, then 2 is th minimum and 12 is the max as 1 W steps
-         */
-        let encoded_rf_power;
-        if rf_power == 0 {
-            encoded_rf_power = 0b1000_0000 | 0x04;
-        // encoded_rf_power = 0;
-        } else {
-            encoded_rf_power = 0b1000_0000 | (0b0011_1111 & (rf_power * 4));
-            // encoded_rf_power = rf_power;
-        }
-
-        // Confusing command from python writes to more than CFG3 by 16 bytes as all 0s
-        // msg = [2,0,44,255,139,2,1,1,1,30,0,3,0,8,pow,128,0,0,0,0,0,0,0,0,0,128,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0]
-
-        let data = vec![
-            0x2,              // Device
-            0x1,              // Bank
-            0x1,              // Mode
-            0x1,              // CFG-N
-            30, // Block Size  // IDK why we need the extra 16 zeros that don't map to the CFG block
-            0x00, // MSB CFG-ADR
-            0x03, // LSB CFG-ADR
-            0x00, // CFG-Data :: CFG3 Byte 0 TAG-DRV
-            0x08, // CFG-Data :: CFG3 Byte 1 TAG-DRV
-            encoded_rf_power, // CFG-Data :: CFG3 Byte 2 RF-POWER
-            0x80, // CFG-Data :: CFG3 Byte 3 EAS-LEVEL
-            0,
-            0,
-            0, // CFG-Data :: CFG3 Byte 4,5,6 0x00
-            0,
-            0,
-            0,
-            0,
-            0,
-            0, // CFG-Data :: CFG3 Byte 7,8,9,10,11,12 0x00
-            0b1000_0001, // CFG-Data :: CFG3 Byte 13 FU_COM,
-            0,
-            0,
-            0,
-            0,
-            0,
-            0,
-            0,
-            0,
-            0,
-            0,
-            0,
-            0,
-            0,
-            0,
-            0,
-            0, // IDK WHY THIS IS REQUIRED
-        ];
-
-        let request =
-            advanced_protocol::HostToReader::new(0, 0xFF, 0x8B, data.as_slice(), 0, false);
-        let response = self.conn.send_command(request)?;
-        log::debug!("Received response: {:#?}", response);
-        if response.status == 0x11 {
-            let error_message =
-                "A reasonableness check failed while writing the RF power parameter to the reader";
-            Err(InternalError::from(error_message))
-        } else {
-            Ok(())
-        }
+        let mut cfg: RfInterfaceConfig = self.read_configuration(location)?;
+        log::debug!("Read RF-Interface configuration: {:?}", cfg);
+        cfg.rf_power = RfInterfaceConfig::encode_rf_power(rf_power);
+        self.write_configuration(location, &cfg)
     }
 
     pub fn system_reset(self: &mut Self) -> Result<()> {
         log::trace!("Requesting System Reset of RF controller ...");
-        let request =
-            advanced_protocol::HostToReader::new(0, 0xFF, 0x64, vec![0].as_slice(), 0, false);
-        let response = self.conn.send_command(request)?;
-
-        let status = Status::from(response.status);
-        if status != Status::Ok {
-            let error_message = format!("System reset failed with status code: {:?}.", status);
-            Err(InternalError::from(error_message))
-        } else {
-            Ok(())
-        }
+        self.execute(&SystemReset {})
     }
 
     pub fn custom_command(
@@ -464,23 +619,100 @@ impl<'a> HapticV0Protocol<'a> {
         control_byte: u8,
         data: &[u8],
         device_required: bool,
-    ) -> Result<()> {
+    ) -> Result<advanced_protocol::ReaderToHost> {
         log::trace!(
             "Requesting Custom Command with control_byte {:#X?} and data {:#X?} ...",
             control_byte,
             data
         );
 
-        let request =
-            advanced_protocol::HostToReader::new(0, 0xFF, control_byte, data, 0, device_required);
-        let response = self.conn.send_command(request)?;
+        // The status is left to the client, such as MoreData after a page of an inventory
+        self.execute(&RawCommand {
+            control_byte,
+            data: data.to_vec(),
+            device_required,
+        })
+    }
 
-        let status = Status::from(response.status);
-        if status != Status::Ok {
-            let error_message = format!("Command failed with status code: {:?}.", status);
-            Err(InternalError::from(error_message))
-        } else {
-            Ok(())
+    /// The transponders with the hex UIDs, which must answer an inventory if they are checked
+    fn find_transponders(
+        self: &mut Self,
+        uids: &[String],
+        check_uids: bool,
+    ) -> Result<smallvec::SmallVec<[ObidTransponder; 2]>> {
+        if uids.is_empty() {
+            return Err(InternalError::from(
+                "Expected the UID of at least one transponder for the fabric",
+            ));
+        }
+        let mut transponders: smallvec::SmallVec<[ObidTransponder; 2]> = uids
+            .iter()
+            .map(|uid| parse_transponder(uid))
+            .collect::<Result<_>>()?;
+        if !check_uids {
+            return Ok(transponders);
+        }
+
+        let inventory = self.get_inventory(false)?;
+        let mut missing = vec![];
+        for transponder in transponders.iter_mut() {
+            match inventory.iter().find(|found| found.uid == transponder.uid) {
+                Some(found) => *transponder = found.clone(),
+                None => missing.push(hex::encode(&transponder.uid)),
+            }
+        }
+        if !missing.is_empty() {
+            let message = format!(
+                "Transponders not found in the antenna: {}",
+                missing.join(", ")
+            );
+            log::error!("{}", message);
+            return Err(InternalError::from(message));
+        }
+        Ok(transponders)
+    }
+
+    /// Fails if a transponder already belongs to a fabric with another name
+    fn check_unclaimed(
+        self: &Self,
+        fabric_name: &str,
+        transponders: &[ObidTransponder],
+    ) -> Result<()> {
+        for transponder in transponders.iter() {
+            let uid = transponder.uid.to_vec();
+            if let Some(owner) = self
+                .fabrics
+                .values()
+                .find(|fabric| fabric.name() != fabric_name && fabric.uids().contains(&uid))
+            {
+                let message = format!(
+                    "Transponder {} already belongs to fabric {}",
+                    hex::encode(&uid),
+                    owner.name()
+                );
+                log::error!("{}", message);
+                return Err(InternalError::from(message));
+            }
+        }
+        Ok(())
+    }
+
+    /// The transponders and cached actuator state of the fabric
+    fn fabric_reply(self: &Self, fabric_name: &String) -> Result<CommandMessage> {
+        match self.fabrics.get(fabric_name) {
+            Some(fabric) => Ok(CommandMessage::Fabric {
+                fabric_name: fabric_name.clone(),
+                uids: fabric.uids().iter().map(hex::encode).collect(),
+                state: self
+                    .states
+                    .get(fabric_name)
+                    .map(|fabric_state| fabric_state.state.clone()),
+            }),
+            None => {
+                let message = format!("No existing fabric named {}", fabric_name);
+                log::error!("{}", message.as_str());
+                Err(InternalError::from(message.as_str()))
+            }
         }
     }
 
@@ -491,6 +723,7 @@ impl<'a> HapticV0Protocol<'a> {
         actuator_mode_blocks: &Option<ActuatorModeBlocks>,
         op_mode_block: &Option<OpModeBlock>,
         use_cache: &Option<bool>,
+        verify: bool,
     ) -> Result<()> {
         let fabric = match self.fabrics.get_mut(fabric_name) {
             Some(fabric) => {
@@ -518,7 +751,7 @@ impl<'a> HapticV0Protocol<'a> {
             )))?;
 
         let fabric_id = fabric.identifier()?;
-        let mut actuators_command = ActuatorsCommand {
+        let actuators_command = ActuatorsCommand {
             fabric_name: fabric_name.clone(),
             timer_mode_blocks: timer_mode_blocks.clone(),
             actuator_mode_blocks: actuator_mode_blocks.clone(),
@@ -526,37 +759,52 @@ impl<'a> HapticV0Protocol<'a> {
             use_cache: use_cache.clone(),
         };
 
-        let actuators_command = match use_cache {
-            Some(flag) => {
-                if *flag {
-                    if state.state.use_cache.unwrap() {
-                        actuators_command = state.diff(actuators_command);
-                        log::trace!("Writing using cached diff: {:#?}", &actuators_command);
-                    } else {
-                        log::debug!("Skipping cached diff to warm cache");
-                    }
-                } else {
-                    log::debug!("Command electing to bypass cache");
-                }
-                actuators_command
+        let cached = match use_cache {
+            Some(false) => {
+                log::debug!("Command electing to bypass cache");
+                false
             }
             _ => {
-                if state.state.use_cache.unwrap() {
-                    actuators_command = state.diff(actuators_command);
-                    log::trace!("Writing using cached diff: {:#?}", &actuators_command);
-                } else {
+                if !state.state.use_cache.unwrap() {
                     log::debug!("Skipping cached diff to warm cache");
                 }
-                actuators_command
+                state.state.use_cache.unwrap()
             }
         };
 
-        let result = self.actuators_command(
-            fabric_id.as_slice(),
-            &actuators_command.timer_mode_blocks,
-            &actuators_command.actuator_mode_blocks,
-            &actuators_command.op_mode_block,
-        );
+        // The actuator bytes are laid out by position, so a diff of single blocks would shift them
+        // Instead the cache writes the full merged state, or nothing when its bytes are unchanged
+        let actuators_command = if cached {
+            state.merged(actuators_command)
+        } else {
+            actuators_command
+        };
+        let data = codec::encode(&actuators_command);
+        if data.is_empty() {
+            // Nothing reaches the reader, so only the op_mode is kept for the next write
+            log::debug!("Nothing to write to the actuators of {}", fabric_name);
+            let state = self
+                .states
+                .get_mut(fabric_name)
+                .ok_or(InternalError::from("Missing fabric state"))?;
+            if let Some(op_mode_block) = actuators_command.op_mode_block {
+                state.apply(ActuatorsCommand {
+                    fabric_name: fabric_name.clone(),
+                    timer_mode_blocks: None,
+                    actuator_mode_blocks: None,
+                    op_mode_block: Some(op_mode_block),
+                    use_cache: None,
+                });
+            }
+            return Ok(());
+        }
+        if cached && data == codec::encode(&state.state) {
+            log::debug!("Skipping write of unchanged actuators for {}", fabric_name);
+            return Ok(());
+        }
+        log::trace!("Writing actuators: {:#?}", &actuators_command);
+
+        let result = self.actuators_command(fabric_id.as_slice(), &actuators_command, verify);
         if result.is_ok() {
             let state = self
                 .states
@@ -570,9 +818,8 @@ impl<'a> HapticV0Protocol<'a> {
     pub fn actuators_command(
         self: &mut Self,
         uid: &[u8],
-        timer_mode_blocks: &Option<TimerModeBlocks>,
-        actuator_mode_blocks: &Option<ActuatorModeBlocks>,
-        op_mode_block: &Option<OpModeBlock>,
+        actuators_command: &ActuatorsCommand,
+        verify: bool,
     ) -> Result<()> {
         log::trace!("Requesting write to actuators' configuration ...");
 
@@ -583,245 +830,234 @@ impl<'a> HapticV0Protocol<'a> {
             )));
         }
 
-        // Construct the feig command
-        let control_byte = 0xB0; // Control Byte for manipulating transponder
-        let command_id = 0x24; // Command Id for Control Byte to write blocks to transponder's RF blocks
-        let mode = 0x01; // addressed
-        let db_n = 0x01;
-        let db_size = 0x04;
-        let addr = 0x00;
-        let mut write_blocks = false;
-
-        let mut data: smallvec::SmallVec<[u8; 32]> = smallvec::smallvec![
-            command_id, mode, uid[0], uid[1], uid[2], uid[3], uid[4], uid[5], uid[6], uid[7], addr,
-            db_n, db_size
-        ];
-        let mut num_bytes = 3;
-        let mut cmd_op;
-        let mut act_cnt8;
-        let mut is_actuators = false;
-        let mut mem_blk1 = vec![0, 0];
-        let mut mem_blk2: Vec<u8> = vec![];
-        let mut mem_blk3: Vec<u8> = vec![];
-
-        let command;
-        if op_mode_block.is_some() {
-            let bl = op_mode_block.as_ref().unwrap();
-            log::debug!(
-                "Num act blks: {:#?}, cmd_op: {:#?}, command: {:#?} ",
-                bl.act_cnt8,
-                bl.cmd_op,
-                bl.command
-            );
-            command = bl.command;
-            cmd_op = bl.cmd_op;
-            act_cnt8 = bl.act_cnt8;
-            if command != 0 {
-                //Not all off command
-                if actuator_mode_blocks.is_some() && cmd_op != 0 {
-                    let bl = actuator_mode_blocks.as_ref().unwrap();
-                    let mut blks = vec![];
-                    if bl.block0_31.is_some() {
-                        mem_blk1.append(&mut vec![0, bl.block0_31.as_ref().unwrap().b0]);
-                        blks.extend(
-                            [
-                                bl.block0_31.as_ref().unwrap().b1,
-                                bl.block0_31.as_ref().unwrap().b2,
-                                bl.block0_31.as_ref().unwrap().b3,
-                            ]
-                            .iter()
-                            .copied(),
-                        );
-                    }
-                    if bl.block32_63.is_some() {
-                        blks.extend(
-                            [
-                                bl.block32_63.as_ref().unwrap().b0,
-                                bl.block32_63.as_ref().unwrap().b1,
-                                bl.block32_63.as_ref().unwrap().b2,
-                                bl.block32_63.as_ref().unwrap().b3,
-                            ]
-                            .iter()
-                            .copied(),
-                        );
-                    }
-                    if bl.block64_95.is_some() {
-                        blks.extend(
-                            [
-                                bl.block64_95.as_ref().unwrap().b0,
-                                bl.block64_95.as_ref().unwrap().b1,
-                                bl.block64_95.as_ref().unwrap().b2,
-                                bl.block64_95.as_ref().unwrap().b3,
-                            ]
-                            .iter()
-                            .copied(),
-                        );
-                    }
-                    if bl.block96_127.is_some() {
-                        blks.extend(
-                            [
-                                bl.block96_127.as_ref().unwrap().b0,
-                                bl.block96_127.as_ref().unwrap().b1,
-                                bl.block96_127.as_ref().unwrap().b2,
-                                bl.block96_127.as_ref().unwrap().b3,
-                            ]
-                            .iter()
-                            .copied(),
-                        );
-                    }
-                    if blks.len() != 0 {
-                        is_actuators = true;
-                        write_blocks = true;
-                        let mut last_int = 0;
-                        let mut cnt = 0;
-                        for blk in blks.iter_mut() {
-                            //find last relavant byte
-                            cnt += 1;
-                            if *blk != 0 {
-                                last_int = cnt;
-                            }
-                        }
-                        blks.truncate(last_int); //remove unneeded bytes (trailing zeros)
-                        for chunk in blks.chunks(db_size as usize) {
-                            //populate other memory blocks if possible
-                            if mem_blk2.len() == 0 {
-                                mem_blk2.extend(chunk)
-                            } else if mem_blk3.len() == 0 {
-                                mem_blk3.extend(chunk)
-                            }
-                        }
-                        act_cnt8 = 5; //1 + blks.len() as u8;
-                        cmd_op = 2; //Command without timing config. Overwritten if timing is added.
-                    } else {
-                        act_cnt8 = 0;
-                    }
-                }
-                if timer_mode_blocks.is_some() {
-                    let bl = timer_mode_blocks.as_ref().unwrap();
-                    let mut blks = vec![];
-                    if bl.single_pulse_block.is_some() {
-                        blks.extend(
-                            [
-                                bl.single_pulse_block.as_ref().unwrap().b0,
-                                bl.single_pulse_block.as_ref().unwrap().b1,
-                                bl.single_pulse_block.as_ref().unwrap().b2,
-                            ]
-                            .iter()
-                            .copied(),
-                        );
-                    }
-                    if bl.hf_block.is_some() {
-                        blks.extend(
-                            [
-                                bl.hf_block.as_ref().unwrap().b0,
-                                bl.hf_block.as_ref().unwrap().b1,
-                                bl.hf_block.as_ref().unwrap().b2,
-                            ]
-                            .iter()
-                            .copied(),
-                        );
-                    }
-                    if bl.lf_block.is_some() {
-                        blks.extend(
-                            [
-                                bl.lf_block.as_ref().unwrap().b0,
-                                bl.lf_block.as_ref().unwrap().b1,
-                                bl.lf_block.as_ref().unwrap().b2,
-                            ]
-                            .iter()
-                            .copied(),
-                        );
-                    }
-                    if blks.len() != 0 {
-                        write_blocks = true;
-                        if is_actuators {
-                            cmd_op = 3; //Actuator command with timing config
-                                        //Fill memory blocks with space
-                            while mem_blk2.len() < 4 && blks.len() != 0 {
-                                mem_blk2.push(blks.remove(0));
-                            }
-                            while mem_blk3.len() < 4 && blks.len() != 0 {
-                                mem_blk3.push(blks.remove(0));
-                            }
-                        } else {
-                            //Only setting timing blocks
-                            for _ in 0..2 {
-                                mem_blk1.push(blks.remove(0));
-                            } //push first two bytes into mem_blk1
-                            for chunk in blks.chunks(db_size as usize) {
-                                //populate other memory blocks if possible
-                                if mem_blk2.len() == 0 {
-                                    mem_blk2.extend(chunk)
-                                } else if mem_blk3.len() == 0 {
-                                    mem_blk3.extend(chunk)
-                                }
-                            }
-                        }
-                    }
-                }
-                while mem_blk2.len() > 0 && mem_blk2.len() < 4 {
-                    //Need to fill memory blocks if not full
-                    mem_blk2.push(0);
-                }
-                if mem_blk2.len() == 0 && is_actuators {
-                    mem_blk2 = vec![0, 0, 0, 0];
-                }
-                while mem_blk3.len() > 0 && mem_blk3.len() < 4 {
-                    mem_blk3.push(0);
-                }
-                let op_mode = cmd_op << 5 | act_cnt8;
-                mem_blk1[1] = op_mode;
-
-                if is_actuators {
-                    mem_blk1[2] = command;
-                } //command not used when only setting timing
-
-                num_bytes = mem_blk1.len() + mem_blk2.len() + mem_blk3.len();
-                mem_blk1[0] = num_bytes as u8;
-                data[11] = ((num_bytes as f32 / 4f32).ceil()) as u8; //calcuate number of memeory blocks to write to
-                mem_blk1.reverse();
-                mem_blk2.reverse();
-                mem_blk3.reverse();
-                data.extend_from_slice(mem_blk1.as_slice());
-                data.extend_from_slice(mem_blk2.as_slice());
-                data.extend_from_slice(mem_blk3.as_slice());
-            } else {
-                //all off
-                write_blocks = true;
-                act_cnt8 = bl.act_cnt8;
-                let op_mode = cmd_op << 5 | act_cnt8;
-                // LSB first
-                data.push(0x00); //first byte is empty when cmd_op is 1
-                data.push(command);
-                data.push(op_mode);
-                data.push(num_bytes as u8); //num_bytes
-            }
-        }
-        if write_blocks {
-            match self.custom_command(control_byte, data.as_slice(), true) {
+        // Lay out the blocks to write to the transponder's RF blocks
+        let data = codec::encode(actuators_command);
+        if !data.is_empty() {
+            let mut transponder_uid = [0u8; 8];
+            transponder_uid.copy_from_slice(uid);
+            let write_request = WriteMultipleBlocks {
+                uid: transponder_uid,
+                db_adr: 0x00,
+                db_n: codec::block_count(&data),
+                db_size: codec::BLOCK_SIZE as u8,
+                data,
+            };
+            match self.execute(&write_request) {
                 Ok(_) => {}
                 Err(err) => {
                     log::error!("Failed to write actuators command: {}", err);
                     return Err(err);
                 }
             }
+            if verify {
+                self.verify_blocks(&write_request)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the written blocks back from the transponder, failing unless they hold the written data
+    fn verify_blocks(self: &mut Self, write_request: &WriteMultipleBlocks) -> Result<()> {
+        let blocks = self.execute(&ReadMultipleBlocks {
+            uid: write_request.uid,
+            db_adr: write_request.db_adr,
+            db_n: write_request.db_n,
+        })?;
+        let data = blocks.concat();
+        if data != write_request.data {
+            let message = format!(
+                "Read back {} from the transponder after writing {}",
+                hex::encode(&data),
+                hex::encode(&write_request.data)
+            );
+            log::error!("{}", message);
+            return Err(InternalError::from(message));
         }
+        log::debug!("Verified {} blocks of the transponder", write_request.db_n);
         Ok(())
     }
+
+    /// Read the blocks that hold the actuator configuration, as many as their num_bytes needs
+    pub fn read_actuator_blocks(self: &mut Self, uid: &[u8]) -> Result<std::vec::Vec<u8>> {
+        if uid.len() != 8 {
+            return Err(InternalError::from(format!(
+                "Expected UID, which is a serial number of 8 bytes, but found {} bytes",
+                uid.len()
+            )));
+        }
+        let mut transponder_uid = [0u8; 8];
+        transponder_uid.copy_from_slice(uid);
+        let mut data = self
+            .execute(&ReadMultipleBlocks {
+                uid: transponder_uid,
+                db_adr: 0x00,
+                db_n: 0x01,
+            })?
+            .concat();
+
+        // The first block is stored LSB first, so num_bytes is its last byte
+        let num_bytes = data.last().copied().unwrap_or(0) as usize;
+        let db_n = num_bytes.div_ceil(codec::BLOCK_SIZE);
+        if db_n > 1 {
+            let blocks = self.execute(&ReadMultipleBlocks {
+                uid: transponder_uid,
+                db_adr: 0x01,
+                db_n: (db_n - 1).min(codec::MAX_BLOCKS - 1) as u8,
+            })?;
+            data.extend(blocks.concat());
+        }
+        Ok(data)
+    }
 }
 
 impl<'a> Protocol<'a> for HapticV0Protocol<'a> {
-    fn handle_message(self: &mut Self, message: &CommandMessage) -> Result<()> {
+    fn reset_delay(self: &Self) -> std::time::Duration {
+        std::time::Duration::from_millis(1000)
+    }
+
+    /// The fabrics are kept, but each must still be in the antenna after the reset
+    fn reinitialize(self: &mut Self) -> Result<()> {
+        self.conn.reconnect()?;
+        if self.fabrics.is_empty() {
+            return Ok(());
+        }
+
+        let uids: std::vec::Vec<std::vec::Vec<u8>> = self
+            .get_inventory(false)?
+            .iter()
+            .map(|transponder| transponder.uid.to_vec())
+            .collect();
+        let mut missing: std::vec::Vec<String> = self
+            .fabrics
+            .values()
+            .filter(|fabric| fabric.uids().iter().any(|uid| !uids.contains(uid)))
+            .map(|fabric| fabric.name())
+            .collect();
+        if missing.is_empty() {
+            log::info!("Found all {} fabrics after the reset", self.fabrics.len());
+            return Ok(());
+        }
+        missing.sort();
+        let error_message = format!(
+            "Fabrics missing from the antenna after the reset: {}",
+            missing.join(", ")
+        );
+        log::error!("{}", error_message);
+        Err(InternalError::from(error_message))
+    }
+
+    fn fabrics(self: &Self) -> Vec<FabricRecord> {
+        let mut fabrics: Vec<FabricRecord> = self
+            .fabrics
+            .values()
+            .map(|fabric| FabricRecord {
+                fabric_name: fabric.name(),
+                uids: fabric.uids().iter().map(hex::encode).collect(),
+                state: self
+                    .states
+                    .get(&fabric.name())
+                    .map(|fabric_state| fabric_state.state.clone()),
+            })
+            .collect();
+        fabrics.sort_by(|a, b| a.fabric_name.cmp(&b.fabric_name));
+        fabrics
+    }
+
+    /// The cached state is restored as well, so the next ActuatorsCommand is diffed against it
+    fn restore_fabrics(self: &mut Self, fabrics: Vec<FabricRecord>) -> Result<()> {
+        for record in fabrics {
+            let transponders: smallvec::SmallVec<[ObidTransponder; 2]> = record
+                .uids
+                .iter()
+                .map(|uid| parse_transponder(uid))
+                .collect::<Result<_>>()?;
+            let fabric_name = record.fabric_name;
+            let mut fabric_state = V0FabricState::new(fabric_name.as_str());
+            match record.state {
+                // The cache diffs against every block, so a partial state starts the cache cold
+                Some(state)
+                    if state.actuator_mode_blocks.is_some()
+                        && state.timer_mode_blocks.is_some()
+                        && state.use_cache.is_some() =>
+                {
+                    fabric_state.state = state;
+                }
+                Some(_) => log::warn!("Ignoring the incomplete state of fabric {}", fabric_name),
+                None => {}
+            }
+            log::info!(
+                "Restored fabric {} with {} transponders",
+                fabric_name,
+                transponders.len()
+            );
+            self.fabrics.insert(
+                fabric_name.clone(),
+                Box::new(V0Fabric::new(fabric_name.as_str(), transponders)),
+            );
+            self.states.insert(fabric_name, fabric_state);
+        }
+        Ok(())
+    }
+
+    fn handle_message(self: &mut Self, message: &CommandMessage) -> Result<CommandMessage> {
         match message {
+            CommandMessage::Addressed { com_adr, message } => {
+                log::debug!("Received command for reader {:#04X}", com_adr);
+                let default_com_adr = self.com_adr;
+                self.com_adr = *com_adr;
+                let result = self.handle_message(message);
+                self.com_adr = default_com_adr;
+                result
+            }
             CommandMessage::RfFieldState { state } => {
-                self.custom_command(0x6A, vec![*state].as_slice(), false)
+                self.execute(&RfOnOff { rf: *state })?;
+                Ok(CommandMessage::Success {})
+            }
+            CommandMessage::ReaderInfo {} => Ok(CommandMessage::Info {
+                reader_info: self.conn.reader_info().cloned(),
+            }),
+            CommandMessage::SystemReset {} => {
+                self.system_reset()?;
+                Ok(CommandMessage::Success {})
+            }
+            CommandMessage::ReaderDiagnostics {} => {
+                let diagnostics = self.execute(&ReaderDiagnostic {})?;
+                log::info!("Reader diagnostics: {}", diagnostics);
+                Ok(CommandMessage::Diagnostics { diagnostics })
             }
-            CommandMessage::AddFabric { fabric_name } => {
-                let uid = match self.get_inventory(true) {
-                    Ok(uid) => uid,
-                    Err(err) => return Err(err),
+            CommandMessage::Inventory {} => {
+                let transponders = self
+                    .get_inventory(false)?
+                    .iter()
+                    .map(|transponder| {
+                        let uid = transponder.uid.to_vec();
+                        TransponderInfo {
+                            uid: hex::encode(&uid),
+                            tr_type_rf_tec: transponder.tr_type_rf_tec,
+                            tr_type_type_no: transponder.tr_type_type_no,
+                            dsfid: transponder.dsfid,
+                            fabric_name: self
+                                .fabrics
+                                .values()
+                                .find(|fabric| fabric.uids().contains(&uid))
+                                .map(|fabric| fabric.name()),
+                        }
+                    })
+                    .collect();
+                Ok(CommandMessage::Transponders { transponders })
+            }
+            CommandMessage::AddFabric {
+                fabric_name,
+                uids,
+                check_uids,
+            } => {
+                let transponders = match uids {
+                    Some(uids) => self.find_transponders(uids, check_uids.unwrap_or(true))?,
+                    None => self.get_inventory(true)?,
                 };
-                let fabric: Box<dyn Fabric> = Box::new(V0Fabric::new(fabric_name.as_str(), uid));
+                self.check_unclaimed(fabric_name, &transponders)?;
+                let fabric: Box<dyn Fabric> =
+                    Box::new(V0Fabric::new(fabric_name.as_str(), transponders));
                 self.fabrics.insert(fabric_name.clone(), fabric);
                 self.states.insert(
                     fabric_name.clone(),
@@ -829,15 +1065,16 @@ impl<'a> Protocol<'a> for HapticV0Protocol<'a> {
                 );
                 log::info!("Added new fabric to command for AddFabric command");
                 log::trace!("Active Fabrics: {:#?}", self.fabrics);
-                Ok(())
+                self.fabric_reply(fabric_name)
             }
+            CommandMessage::FabricState { fabric_name } => self.fabric_reply(fabric_name),
             CommandMessage::RemoveFabric { fabric_name } => {
                 match self.fabrics.remove(fabric_name) {
                     Some(fabric) => {
                         log::info!("Removed existing fabric to command for AddFabric command");
                         log::trace!("Active Fabrics:  {:#?}", self.fabrics);
                         log::trace!("Removed Fabric:  {:#?}", fabric);
-                        Ok(())
+                        Ok(CommandMessage::Success {})
                     }
                     None => {
                         let message =
@@ -847,13 +1084,20 @@ impl<'a> Protocol<'a> for HapticV0Protocol<'a> {
                     }
                 }
             }
-            CommandMessage::SetRadioFreqPower { power_level } => {
+            CommandMessage::SetRadioFreqPower {
+                power_level,
+                location,
+            } => {
                 log::debug!(
                     "Received SetRadioFreqPower command for power_level {:?}.",
                     power_level
                 );
                 match power_level {
-                    pl if *pl == 0 || (*pl >= 2 && *pl <= 12) => self.set_radio_freq_power(*pl),
+                    pl if *pl == 0 || (*pl >= 2 && *pl <= 12) => {
+                        let location = location.unwrap_or(ConfigLocation::Eeprom);
+                        self.set_radio_freq_power(*pl, location)?;
+                        Ok(CommandMessage::Success {})
+                    }
                     _ => {
                         let message = format!("Value for power level ({}) is outside acceptable range Low Power (0) or [2,12].", power_level);
                         log::error!("{}", message.as_str());
@@ -861,6 +1105,25 @@ impl<'a> Protocol<'a> for HapticV0Protocol<'a> {
                     }
                 }
             }
+            CommandMessage::ReadConfiguration { address, location } => {
+                log::debug!("Received ReadConfiguration command for CFG{}.", address);
+                let location = location.unwrap_or(ConfigLocation::Eeprom);
+                let data = self.execute(&ReadConfiguration {
+                    location,
+                    address: *address,
+                })?;
+                let rf_interface = if *address == RfInterfaceConfig::ADDRESS {
+                    Some(RfInterfaceConfig::from_bytes(&data))
+                } else {
+                    None
+                };
+                Ok(CommandMessage::Configuration {
+                    address: *address,
+                    location,
+                    data: hex::encode(data),
+                    rf_interface,
+                })
+            }
             CommandMessage::CustomCommand {
                 control_byte,
                 data,
@@ -872,11 +1135,17 @@ impl<'a> Protocol<'a> for HapticV0Protocol<'a> {
                     hex::encode(data.as_bytes())
                 );
                 let decoded_data = hex::decode(&data)?;
-                self.custom_command(
+                let response = self.custom_command(
                     control_byte.clone(),
                     decoded_data.as_slice(),
                     device_required.clone(),
-                )
+                )?;
+                Ok(CommandMessage::Response {
+                    com_adr: response.com_adr,
+                    control_byte: response.control_byte,
+                    status: response.status,
+                    data: hex::encode(&response.data),
+                })
             }
             CommandMessage::ActuatorsCommand {
                 fabric_name,
@@ -884,6 +1153,7 @@ impl<'a> Protocol<'a> for HapticV0Protocol<'a> {
                 actuator_mode_blocks,
                 op_mode_block,
                 use_cache,
+                verify,
             } => {
                 log::trace!(
                     "Received ActuatorsCommand: {:#?} {:#?} {:#?} {:#?}",
@@ -898,12 +1168,627 @@ impl<'a> Protocol<'a> for HapticV0Protocol<'a> {
                     actuator_mode_blocks,
                     op_mode_block,
                     use_cache,
-                )
+                    verify.unwrap_or(false),
+                )?;
+                Ok(CommandMessage::Success {})
+            }
+            CommandMessage::SetActuators {
+                fabric_name,
+                on,
+                command,
+                timing,
+                use_cache,
+                verify,
+            } => {
+                log::trace!("Received SetActuators: {:?} {:?}", fabric_name, on);
+                let actuators_command =
+                    ActuatorsCommand::set_actuators(fabric_name, on, *command, timing.as_ref())?;
+                self.handle_actuators_command(
+                    fabric_name,
+                    &actuators_command.timer_mode_blocks,
+                    &actuators_command.actuator_mode_blocks,
+                    &actuators_command.op_mode_block,
+                    use_cache,
+                    verify.unwrap_or(false),
+                )?;
+                Ok(CommandMessage::Success {})
+            }
+            CommandMessage::PlayPattern {
+                fabric_name,
+                command,
+                frames,
+                ..
+            } => {
+                // The frames are checked before the server plays any of them
+                if !self.fabrics.contains_key(fabric_name) {
+                    return Err(InternalError::from(format!(
+                        "No existing fabric named {}",
+                        fabric_name
+                    )));
+                }
+                if frames.is_empty() {
+                    return Err(InternalError::from("Expected a pattern with frames"));
+                }
+                for (index, frame) in frames.iter().enumerate() {
+                    if frame.duration_ms == 0 {
+                        return Err(InternalError::from(format!(
+                            "Expected frame {} of the pattern to last at least 1 ms",
+                            index
+                        )));
+                    }
+                    ActuatorsCommand::set_actuators(
+                        fabric_name,
+                        &frame.actuators,
+                        *command,
+                        frame.timers.as_ref(),
+                    )
+                    .map_err(|err| {
+                        InternalError::from(format!("Frame {} of the pattern: {}", index, err))
+                    })?;
+                }
+                Ok(CommandMessage::Success {})
+            }
+            CommandMessage::StopPattern { fabric_name } => {
+                // Leave the fabric all off
+                let all_off = ActuatorsCommand::set_actuators(fabric_name, &[], 0, None)?;
+                self.handle_actuators_command(
+                    fabric_name,
+                    &all_off.timer_mode_blocks,
+                    &all_off.actuator_mode_blocks,
+                    &all_off.op_mode_block,
+                    &Some(false),
+                    false,
+                )?;
+                Ok(CommandMessage::Success {})
+            }
+            CommandMessage::ReadActuatorState { fabric_name } => {
+                let uid = match self.fabrics.get(fabric_name) {
+                    Some(fabric) => fabric.identifier()?,
+                    None => {
+                        return Err(InternalError::from(format!(
+                            "No existing fabric named {}",
+                            fabric_name
+                        )))
+                    }
+                };
+                let data = self.read_actuator_blocks(uid.as_slice())?;
+                Ok(CommandMessage::ActuatorState {
+                    fabric_name: fabric_name.clone(),
+                    uid: hex::encode(&uid),
+                    memory: codec::decode(data.as_slice())?,
+                    data: hex::encode(&data),
+                })
             }
             _ => {
                 log::debug!("Haptic V0 ignoring: {:?}", message);
-                Ok(())
+                Ok(CommandMessage::Success {})
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "mock"))]
+mod tests {
+    use super::*;
+    use crate::conn::common::Context;
+    use crate::conn::mock::{Fault, FaultPlan, FaultyContext};
+    use crate::conn::simulated::{
+        SimulatedContext, SimulatedReader, SimulatedReaderConnection, VirtualTransponder,
+    };
+    use std::sync::{Arc, Mutex};
+
+    fn protocol() -> HapticV0Protocol<'static> {
+        let reader = SimulatedReader::new(vec![VirtualTransponder::new([
+            0xE0, 0x04, 0, 0, 0, 0, 0, 1,
+        ])]);
+        let connection = SimulatedReaderConnection::new(Framing::Advanced, reader);
+        let mut protocol = HapticV0Protocol::new(Box::new(connection), BROADCAST_COM_ADR);
+        protocol
+            .handle_message(&CommandMessage::AddFabric {
+                fabric_name: String::from("Sleeve"),
+                uids: None,
+                check_uids: None,
+            })
+            .unwrap();
+        protocol
+    }
+
+    /// The protocol with a plan that can drop the replies to tell whether a command was sent
+    fn protocol_with_faults() -> (HapticV0Protocol<'static>, Arc<Mutex<FaultPlan>>) {
+        let reader = SimulatedContext::with_transponders(
+            Framing::Advanced,
+            vec![VirtualTransponder::new([0xE0, 0x04, 0, 0, 0, 0, 0, 1])],
+        );
+        let context = FaultyContext::new(
+            Box::new(reader),
+            Framing::Advanced,
+            FaultPlan::script(vec![]),
+        );
+        let connection = context.connection().unwrap();
+        let mut protocol = HapticV0Protocol::new(connection, BROADCAST_COM_ADR);
+        protocol
+            .handle_message(&CommandMessage::AddFabric {
+                fabric_name: String::from("Sleeve"),
+                uids: None,
+                check_uids: None,
+            })
+            .unwrap();
+        (protocol, context.plan.clone())
+    }
+
+    /// Whether the message writes to the reader, which fails while its replies are dropped
+    fn writes(
+        protocol: &mut HapticV0Protocol<'static>,
+        plan: &Arc<Mutex<FaultPlan>>,
+        message: &CommandMessage,
+    ) -> bool {
+        *plan.lock().unwrap() = FaultPlan::script(vec![Some(Fault::DropReply); 5]);
+        let written = protocol.handle_message(message).is_err();
+        *plan.lock().unwrap() = FaultPlan::script(vec![]);
+        written
+    }
+
+    fn actuators_command(
+        op_mode_block: Option<OpModeBlock>,
+        block0_31: Option<u8>,
+        block32_63: Option<u8>,
+        use_cache: Option<bool>,
+    ) -> CommandMessage {
+        let block = |b0| ActuatorModeBlock {
+            b0,
+            b1: 0,
+            b2: 0,
+            b3: 0,
+        };
+        CommandMessage::ActuatorsCommand {
+            fabric_name: String::from("Sleeve"),
+            timer_mode_blocks: None,
+            actuator_mode_blocks: Some(ActuatorModeBlocks {
+                block0_31: block0_31.map(block),
+                block32_63: block32_63.map(block),
+                block64_95: None,
+                block96_127: None,
+            }),
+            op_mode_block,
+            use_cache,
+            verify: None,
+        }
+    }
+
+    fn read_actuator_state(protocol: &mut HapticV0Protocol<'static>) -> ActuatorMemory {
+        let read = CommandMessage::ReadActuatorState {
+            fabric_name: String::from("Sleeve"),
+        };
+        match protocol.handle_message(&read).unwrap() {
+            CommandMessage::ActuatorState { memory, .. } => memory,
+            other => panic!("Expected the actuator state but got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn cache_skips_writes_of_unchanged_actuators() {
+        let (mut protocol, plan) = protocol_with_faults();
+        let set_actuators = |on: Vec<u16>, use_cache| CommandMessage::SetActuators {
+            fabric_name: String::from("Sleeve"),
+            on,
+            command: 2,
+            timing: None,
+            use_cache,
+            verify: None,
+        };
+
+        // The first write warms the cache
+        assert!(writes(
+            &mut protocol,
+            &plan,
+            &set_actuators(vec![0, 1], None)
+        ));
+        protocol
+            .handle_message(&set_actuators(vec![0, 1], None))
+            .unwrap();
+        assert!(!writes(
+            &mut protocol,
+            &plan,
+            &set_actuators(vec![0, 1], None)
+        ));
+        assert!(writes(&mut protocol, &plan, &set_actuators(vec![2], None)));
+
+        // Bypassing the cache always writes
+        protocol
+            .handle_message(&set_actuators(vec![2], None))
+            .unwrap();
+        assert!(writes(
+            &mut protocol,
+            &plan,
+            &set_actuators(vec![2], Some(false))
+        ));
+    }
+
+    #[test]
+    fn cache_writes_the_full_merged_state() {
+        let (mut protocol, _) = protocol_with_faults();
+        let op_mode_block = Some(OpModeBlock {
+            act_cnt8: 0,
+            cmd_op: 2,
+            command: 1,
+        });
+        protocol
+            .handle_message(&actuators_command(
+                op_mode_block.clone(),
+                Some(0x0F),
+                Some(0x01),
+                None,
+            ))
+            .unwrap();
+        protocol
+            .handle_message(&actuators_command(op_mode_block, None, Some(0x02), None))
+            .unwrap();
+
+        // The left out block0_31 is written from the cache, so block32_63 stays in its place
+        let memory = read_actuator_state(&mut protocol);
+        assert_eq!(
+            vec![0, 1, 2, 3, 33],
+            memory.actuator_mode_blocks.unwrap().indices()
+        );
+    }
+
+    #[test]
+    fn cache_fills_in_the_op_mode() {
+        let (mut protocol, plan) = protocol_with_faults();
+        let op_mode_block = Some(OpModeBlock {
+            act_cnt8: 0,
+            cmd_op: 2,
+            command: 1,
+        });
+        protocol
+            .handle_message(&actuators_command(op_mode_block, Some(0x0F), None, None))
+            .unwrap();
+
+        // Without the cache, a command without op_mode has nothing to write and isn't cached
+        let command = actuators_command(None, Some(0x30), None, Some(false));
+        assert!(!writes(&mut protocol, &plan, &command));
+        assert_eq!(
+            vec![0, 1, 2, 3],
+            read_actuator_state(&mut protocol)
+                .actuator_mode_blocks
+                .unwrap()
+                .indices()
+        );
+
+        // With the cache, it is written with the cached op_mode
+        protocol
+            .handle_message(&actuators_command(None, Some(0x30), None, None))
+            .unwrap();
+        let memory = read_actuator_state(&mut protocol);
+        assert_eq!(1, memory.op_mode_block.command);
+        assert_eq!(vec![4, 5], memory.actuator_mode_blocks.unwrap().indices());
+    }
+
+    #[test]
+    fn verify_and_read_back_actuators() {
+        let mut protocol = protocol();
+        let actuators_command = CommandMessage::ActuatorsCommand {
+            fabric_name: String::from("Sleeve"),
+            timer_mode_blocks: None,
+            actuator_mode_blocks: Some(ActuatorModeBlocks {
+                block0_31: Some(ActuatorModeBlock {
+                    b0: 0x0F,
+                    b1: 0x01,
+                    b2: 0x00,
+                    b3: 0x00,
+                }),
+                block32_63: None,
+                block64_95: None,
+                block96_127: None,
+            }),
+            op_mode_block: Some(OpModeBlock {
+                act_cnt8: 0,
+                cmd_op: 2,
+                command: 1,
+            }),
+            use_cache: Some(false),
+            verify: Some(true),
+        };
+        protocol.handle_message(&actuators_command).unwrap();
+
+        let read = CommandMessage::ReadActuatorState {
+            fabric_name: String::from("Sleeve"),
+        };
+        match protocol.handle_message(&read).unwrap() {
+            CommandMessage::ActuatorState {
+                uid, data, memory, ..
+            } => {
+                assert_eq!("e004000000000001", uid);
+                assert_eq!("0f01450800000001", data);
+                assert_eq!(8, memory.num_bytes);
+                assert_eq!(
+                    OpModeBlock {
+                        act_cnt8: 5,
+                        cmd_op: 2,
+                        command: 1,
+                    },
+                    memory.op_mode_block
+                );
+                let actuators = memory.actuator_mode_blocks.unwrap();
+                assert_eq!(0x0F, actuators.block0_31.unwrap().b0);
+                assert_eq!(None, memory.timer_mode_blocks);
+            }
+            other => panic!("Expected the actuator state but got {:?}", other),
+        }
+
+        let missing = CommandMessage::ReadActuatorState {
+            fabric_name: String::from("Glove"),
+        };
+        assert!(protocol.handle_message(&missing).is_err());
+    }
+
+    #[test]
+    fn read_back_actuators_set_with_timing() {
+        let mut protocol = protocol();
+        let set_actuators = serde_json::from_str::<CommandMessage>(include_str!(
+            "../../../commands/set-actuators.txt"
+        ))
+        .unwrap();
+        let set_actuators = match set_actuators {
+            CommandMessage::SetActuators {
+                on,
+                command,
+                timing,
+                ..
+            } => CommandMessage::SetActuators {
+                fabric_name: String::from("Sleeve"),
+                on,
+                command,
+                timing,
+                use_cache: Some(false),
+                verify: None,
+            },
+            other => panic!("Expected SetActuators but got {:?}", other),
+        };
+        protocol.handle_message(&set_actuators).unwrap();
+
+        // One actuator byte, then the single pulse and HF timers
+        let memory = read_actuator_state(&mut protocol);
+        assert_eq!(
+            OpModeBlock {
+                act_cnt8: 1,
+                cmd_op: 3,
+                command: 2,
+            },
+            memory.op_mode_block
+        );
+        assert_eq!(
+            vec![0, 1, 2, 3],
+            memory.actuator_mode_blocks.unwrap().indices()
+        );
+        assert_eq!(
+            Some(TimerModeBlocks {
+                single_pulse_block: Some(TimerModeBlock {
+                    b0: 0,
+                    b1: 0,
+                    b2: 0,
+                    b3: 0,
+                }),
+                hf_block: Some(TimerModeBlock {
+                    b0: 30,
+                    b1: 0,
+                    b2: 150,
+                    b3: 0,
+                }),
+                lf_block: None,
+            }),
+            memory.timer_mode_blocks
+        );
+
+        // Three actuator bytes, then the LF timer of 2000 ms on in 4000 ms
+        let set_actuators = CommandMessage::SetActuators {
+            fabric_name: String::from("Sleeve"),
+            on: vec![0, 1, 20],
+            command: 1,
+            timing: Some(timing::Timing {
+                single_pulse: None,
+                hf: None,
+                lf: Some(timing::PulsedSignal {
+                    period_ms: 4000,
+                    duty_cycle: 50.0,
+                }),
+            }),
+            use_cache: Some(false),
+            verify: Some(true),
+        };
+        protocol.handle_message(&set_actuators).unwrap();
+        let memory = read_actuator_state(&mut protocol);
+        assert_eq!(3, memory.op_mode_block.act_cnt8);
+        assert_eq!(1, memory.op_mode_block.command);
+        assert_eq!(
+            vec![0, 1, 20],
+            memory.actuator_mode_blocks.unwrap().indices()
+        );
+        let timers = memory.timer_mode_blocks.unwrap();
+        assert_eq!(vec![0xD0, 0x07, 0xA0, 0x0F], codec::timer_bytes(&timers));
+    }
+
+    #[test]
+    fn actuator_indices() {
+        let blocks = ActuatorModeBlocks::from_indices(&[0, 3, 9, 35, 127]).unwrap();
+        assert_eq!(
+            Some(ActuatorModeBlock {
+                b0: 0b0000_1001,
+                b1: 0b0000_0010,
+                b2: 0,
+                b3: 0,
+            }),
+            blocks.block0_31
+        );
+        assert_eq!(Some(0b0000_1000), blocks.block32_63.as_ref().map(|b| b.b0));
+        assert_eq!(Some(0b1000_0000), blocks.block96_127.as_ref().map(|b| b.b3));
+        assert_eq!(vec![0, 3, 9, 35, 127], blocks.indices());
+
+        assert!(ActuatorModeBlocks::from_indices(&[128]).is_err());
+    }
+
+    #[test]
+    fn set_actuators_lays_out_the_op_mode() {
+        // What reaches the transponder is laid out by the encoder
+        let command = ActuatorsCommand::set_actuators("Sleeve", &[0, 35], 2, None).unwrap();
+        let memory = codec::decode(&codec::encode(&command)).unwrap();
+        assert_eq!(
+            OpModeBlock {
+                act_cnt8: 5,
+                cmd_op: 2,
+                command: 2,
+            },
+            memory.op_mode_block
+        );
+        assert_eq!(vec![0, 35], memory.actuator_mode_blocks.unwrap().indices());
+
+        // No actuators turns the fabric all off
+        let command = ActuatorsCommand::set_actuators("Sleeve", &[], 2, None).unwrap();
+        assert_eq!(vec![0x00, 0x00, 0x20, 0x03], codec::encode(&command));
+
+        assert!(ActuatorsCommand::set_actuators("Sleeve", &[1], 0, None).is_err());
+        assert!(ActuatorsCommand::set_actuators("Sleeve", &[71], 2, None).is_ok());
+        let err = ActuatorsCommand::set_actuators("Sleeve", &[128], 2, None).unwrap_err();
+        assert_eq!(
+            "Expected actuators from 0 to 71, which one write can hold, but found 128",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn set_actuators_by_index() {
+        let mut protocol = protocol();
+        let set_actuators = CommandMessage::SetActuators {
+            fabric_name: String::from("Sleeve"),
+            on: vec![0, 1, 2, 3, 8],
+            command: 1,
+            timing: None,
+            use_cache: Some(false),
+            verify: Some(true),
+        };
+        protocol.handle_message(&set_actuators).unwrap();
+
+        let read = CommandMessage::ReadActuatorState {
+            fabric_name: String::from("Sleeve"),
+        };
+        match protocol.handle_message(&read).unwrap() {
+            CommandMessage::ActuatorState { memory, .. } => {
+                assert_eq!(1, memory.op_mode_block.command);
+                assert_eq!(
+                    vec![0, 1, 2, 3, 8],
+                    memory.actuator_mode_blocks.unwrap().indices()
+                );
+            }
+            other => panic!("Expected the actuator state but got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn custom_command_replies_with_any_status() {
+        let mut reader = SimulatedReader::new(
+            (1..=3)
+                .map(|id| VirtualTransponder::new([0xE0, 0x04, 0, 0, 0, 0, 0, id]))
+                .collect(),
+        );
+        reader.inventory_page = 2;
+        let connection = SimulatedReaderConnection::new(Framing::Advanced, reader);
+        let mut protocol = HapticV0Protocol::new(Box::new(connection), BROADCAST_COM_ADR);
+        let custom_command = |control_byte, data: &str| CommandMessage::CustomCommand {
+            control_byte,
+            data: String::from(data),
+            device_required: false,
+        };
+
+        match protocol
+            .handle_message(&custom_command(0xB0, "0100"))
+            .unwrap()
+        {
+            CommandMessage::Response {
+                control_byte,
+                status,
+                data,
+                ..
+            } => {
+                assert_eq!(0xB0, control_byte);
+                assert_eq!(Status::MoreData as u8, status);
+                assert_eq!("02", &data[..2]);
+            }
+            other => panic!("Expected the raw response but got {:?}", other),
+        }
+
+        match protocol.handle_message(&custom_command(0x42, "")).unwrap() {
+            CommandMessage::Response { status, data, .. } => {
+                assert_eq!(Status::UnknownCommand as u8, status);
+                assert_eq!("", data);
+            }
+            other => panic!("Expected the raw response but got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn inventory_follows_more_data() {
+        let mut reader = SimulatedReader::new(
+            (1..=5)
+                .map(|id| VirtualTransponder::new([0xE0, 0x04, 0, 0, 0, 0, 0, id]))
+                .collect(),
+        );
+        reader.inventory_page = 2;
+        let connection = SimulatedReaderConnection::new(Framing::Advanced, reader);
+        let mut protocol = HapticV0Protocol::new(Box::new(connection), BROADCAST_COM_ADR);
+        protocol
+            .handle_message(&CommandMessage::AddFabric {
+                fabric_name: String::from("Glove"),
+                uids: Some(vec![String::from("e004000000000004")]),
+                check_uids: None,
+            })
+            .unwrap();
+
+        match protocol
+            .handle_message(&CommandMessage::Inventory {})
+            .unwrap()
+        {
+            CommandMessage::Transponders { transponders } => {
+                assert_eq!(5, transponders.len());
+                assert_eq!("e004000000000005", transponders[4].uid);
+                assert_eq!(None, transponders[0].fabric_name);
+                assert_eq!(Some(String::from("Glove")), transponders[3].fabric_name);
+            }
+            other => panic!("Expected the transponders but got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn inventory_gives_up_on_endless_more_data() {
+        let count = MAX_INVENTORY_ROUNDS as u8 + 2;
+        let mut reader = SimulatedReader::new(
+            (1..=count)
+                .map(|id| VirtualTransponder::new([0xE0, 0x04, 0, 0, 0, 0, 0, id]))
+                .collect(),
+        );
+        reader.inventory_page = 1;
+        let connection = SimulatedReaderConnection::new(Framing::Advanced, reader);
+        let mut protocol = HapticV0Protocol::new(Box::new(connection), BROADCAST_COM_ADR);
+        assert!(protocol
+            .handle_message(&CommandMessage::Inventory {})
+            .is_err());
+
+        // One page fewer ends on the last request for more
+        let mut reader = SimulatedReader::new(
+            (1..count)
+                .map(|id| VirtualTransponder::new([0xE0, 0x04, 0, 0, 0, 0, 0, id]))
+                .collect(),
+        );
+        reader.inventory_page = 1;
+        let connection = SimulatedReaderConnection::new(Framing::Advanced, reader);
+        let mut protocol = HapticV0Protocol::new(Box::new(connection), BROADCAST_COM_ADR);
+        match protocol
+            .handle_message(&CommandMessage::Inventory {})
+            .unwrap()
+        {
+            CommandMessage::Transponders { transponders } => {
+                assert_eq!(count as usize - 1, transponders.len())
             }
+            other => panic!("Expected the transponders but got {:?}", other),
         }
     }
 }
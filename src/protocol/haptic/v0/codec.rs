@@ -0,0 +1,611 @@
+use super::*;
+
+/// The bytes in each memory block of the transponder written by an actuators command
+pub const BLOCK_SIZE: usize = 4;
+
+/// An actuators command fills at most the first three memory blocks
+pub const MAX_BLOCKS: usize = 3;
+
+/// The actuator bytes that fit: one in the first block and a full second and third block
+pub const MAX_ACTUATOR_BYTES: usize = 1 + 2 * BLOCK_SIZE;
+
+/// The bytes of each timer block
+pub const TIMER_SIZE: usize = 4;
+
+/// The op_mode byte, where cmd_op takes the top 3 bits
+fn op_mode(cmd_op: u8, act_cnt8: u8) -> u8 {
+    cmd_op << 5 | act_cnt8
+}
+
+/// The actuator bytes from b0 of block0_31 up to the last actuator that is on, if any blocks are set
+fn actuator_bytes(blocks: &ActuatorModeBlocks) -> Option<std::vec::Vec<u8>> {
+    let later: std::vec::Vec<u8> = [&blocks.block32_63, &blocks.block64_95, &blocks.block96_127]
+        .iter()
+        .filter_map(|block| block.as_ref())
+        .flat_map(|block| vec![block.b0, block.b1, block.b2, block.b3])
+        .collect();
+    let mut bytes = match &blocks.block0_31 {
+        Some(block) => vec![block.b0, block.b1, block.b2, block.b3],
+        // Without block0_31, the first actuator byte is off and the later blocks follow it
+        None if !later.is_empty() => vec![0],
+        None => return None,
+    };
+    bytes.extend(later);
+    let len = bytes.iter().rposition(|b| *b != 0).map_or(1, |i| i + 1);
+    bytes.truncate(len.min(MAX_ACTUATOR_BYTES));
+    Some(bytes)
+}
+
+/// The bytes of the timers that are set, packed in the order single pulse, HF and LF
+pub fn timer_bytes(blocks: &TimerModeBlocks) -> std::vec::Vec<u8> {
+    [
+        &blocks.single_pulse_block,
+        &blocks.hf_block,
+        &blocks.lf_block,
+    ]
+    .iter()
+    .filter_map(|block| block.as_ref())
+    .flat_map(|block| vec![block.b0, block.b1, block.b2, block.b3])
+    .collect()
+}
+
+/// The timer bytes that fit after the actuator bytes, which is what set_actuators checks
+pub fn timer_space(actuator_bytes: usize) -> usize {
+    MAX_ACTUATOR_BYTES - actuator_bytes.min(MAX_ACTUATOR_BYTES)
+}
+
+/**
+ * Lay out the actuators command in the memory blocks of the transponder,
+ * which is empty when there is nothing to write: no op_mode_block, or a
+ * command without actuators or timing.
+ *
+ * The first block holds num_bytes, op_mode and command followed by the
+ * first actuator byte, or by two timer bytes when only the timing is set.
+ * The other actuator bytes, without trailing zeros, and then the timer
+ * bytes fill up to two more blocks, where a timer that doesn't fit in the
+ * second block continues in the third and bytes past the third are left
+ * out. Each block is stored LSB first.
+ *
+ * With actuators, cmd_op is 2 with act_cnt8 5, or 3 when timing follows,
+ * where act_cnt8 counts the actuator bytes before the timers.
+ */
+pub fn encode(actuators_command: &ActuatorsCommand) -> std::vec::Vec<u8> {
+    let op_mode_block = match &actuators_command.op_mode_block {
+        Some(op_mode_block) => op_mode_block,
+        None => return vec![],
+    };
+    let command = op_mode_block.command;
+    if command == 0 {
+        // All off, where the first byte is empty
+        let op_mode = op_mode(op_mode_block.cmd_op, op_mode_block.act_cnt8);
+        return vec![0x00, command, op_mode, 3];
+    }
+
+    let mut act_cnt8 = op_mode_block.act_cnt8;
+    let actuators = match &actuators_command.actuator_mode_blocks {
+        Some(blocks) if op_mode_block.cmd_op != 0 => {
+            let actuators = actuator_bytes(blocks);
+            // Actuator blocks that are all left out count as no actuators
+            if actuators.is_none() {
+                act_cnt8 = 0;
+            }
+            actuators
+        }
+        _ => None,
+    };
+    let timers = match &actuators_command.timer_mode_blocks {
+        Some(blocks) => timer_bytes(blocks),
+        None => vec![],
+    };
+
+    let mut data = match actuators {
+        None if timers.is_empty() => return vec![],
+        None => {
+            // Only the timing, where the first two timer bytes take the place of the unused command
+            let mut data = vec![0, op_mode(op_mode_block.cmd_op, act_cnt8)];
+            data.extend(timers);
+            data
+        }
+        Some(actuators) => {
+            // Command without timing config, unless timing follows in the space left
+            let op_mode = if timers.is_empty() {
+                op_mode(2, 5)
+            } else {
+                op_mode(3, actuators.len() as u8)
+            };
+            let mut data = vec![0, op_mode, command];
+            data.extend(actuators);
+            data.extend(timers);
+            // The second block is written even when only the first actuators are on
+            data.resize(data.len().max(2 * BLOCK_SIZE), 0);
+            data
+        }
+    };
+
+    data.truncate(MAX_BLOCKS * BLOCK_SIZE);
+    let len = data.len().div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+    data.resize(len, 0);
+    data[0] = data.len() as u8;
+    for block in data.chunks_mut(BLOCK_SIZE) {
+        block.reverse();
+    }
+    data
+}
+
+/// The number of memory blocks the encoded data is written to
+pub fn block_count(data: &[u8]) -> u8 {
+    data.len().div_ceil(BLOCK_SIZE) as u8
+}
+
+/// Decode the memory blocks the way the firmware reads them
+pub fn decode(data: &[u8]) -> Result<ActuatorMemory> {
+    let mut bytes = vec![];
+    for block in data.chunks(BLOCK_SIZE) {
+        bytes.extend(block.iter().rev());
+    }
+    let num_bytes = bytes.first().copied().unwrap_or(0) as usize;
+    if num_bytes < 3 || num_bytes > bytes.len() {
+        return Err(InternalError::from(format!(
+            "Expected between 3 and {} bytes of actuator memory but found num_bytes {}",
+            bytes.len(),
+            num_bytes
+        )));
+    }
+    let bytes = &bytes[..num_bytes];
+    let op_mode = bytes[1];
+    let cmd_op = op_mode >> 5;
+    let act_cnt8 = op_mode & 0b0001_1111;
+
+    // Actuator bytes follow the command, where act_cnt8 of them come before the timer bytes
+    let (command, actuator_bytes, timer_bytes) = match cmd_op {
+        _ if num_bytes == 3 => (bytes[2], &bytes[3..], &bytes[3..]),
+        2 => (bytes[2], &bytes[3..], &bytes[num_bytes..]),
+        3 => {
+            let split = num_bytes.min(3 + act_cnt8 as usize);
+            (bytes[2], &bytes[3..split], &bytes[split..])
+        }
+        // Only timer bytes, which take the place of the command
+        _ => (0, &bytes[2..2], &bytes[2..]),
+    };
+
+    let mut actuators = actuator_bytes.chunks(4).map(|chunk| {
+        let mut b = [0u8; 4];
+        b[..chunk.len()].copy_from_slice(chunk);
+        ActuatorModeBlock {
+            b0: b[0],
+            b1: b[1],
+            b2: b[2],
+            b3: b[3],
+        }
+    });
+    let actuator_mode_blocks = if actuator_bytes.is_empty() {
+        None
+    } else {
+        Some(ActuatorModeBlocks {
+            block0_31: actuators.next(),
+            block32_63: actuators.next(),
+            block64_95: actuators.next(),
+            block96_127: actuators.next(),
+        })
+    };
+
+    // The timers fill the slots in order, and the rest of the last block is padding
+    let mut timers = timer_bytes
+        .chunks_exact(TIMER_SIZE)
+        .map(|b| TimerModeBlock {
+            b0: b[0],
+            b1: b[1],
+            b2: b[2],
+            b3: b[3],
+        });
+    let timer_mode_blocks = if timer_bytes.len() < TIMER_SIZE {
+        None
+    } else {
+        Some(TimerModeBlocks {
+            single_pulse_block: timers.next(),
+            hf_block: timers.next(),
+            lf_block: timers.next(),
+        })
+    };
+
+    Ok(ActuatorMemory {
+        num_bytes: num_bytes as u8,
+        op_mode_block: OpModeBlock {
+            act_cnt8,
+            cmd_op,
+            command,
+        },
+        actuator_mode_blocks,
+        timer_mode_blocks,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn actuators_command(
+        op_mode_block: Option<(u8, u8, u8)>,
+        actuator_mode_blocks: Option<[Option<[u8; 4]>; 4]>,
+        timer_mode_blocks: Option<[Option<[u8; 4]>; 3]>,
+    ) -> ActuatorsCommand {
+        let actuator = |block: Option<[u8; 4]>| {
+            block.map(|b| ActuatorModeBlock {
+                b0: b[0],
+                b1: b[1],
+                b2: b[2],
+                b3: b[3],
+            })
+        };
+        let timer = |block: Option<[u8; 4]>| {
+            block.map(|b| TimerModeBlock {
+                b0: b[0],
+                b1: b[1],
+                b2: b[2],
+                b3: b[3],
+            })
+        };
+        ActuatorsCommand {
+            fabric_name: String::from("Sleeve"),
+            op_mode_block: op_mode_block.map(|(act_cnt8, cmd_op, command)| OpModeBlock {
+                act_cnt8,
+                cmd_op,
+                command,
+            }),
+            actuator_mode_blocks: actuator_mode_blocks.map(|blocks| ActuatorModeBlocks {
+                block0_31: actuator(blocks[0]),
+                block32_63: actuator(blocks[1]),
+                block64_95: actuator(blocks[2]),
+                block96_127: actuator(blocks[3]),
+            }),
+            timer_mode_blocks: timer_mode_blocks.map(|blocks| TimerModeBlocks {
+                single_pulse_block: timer(blocks[0]),
+                hf_block: timer(blocks[1]),
+                lf_block: timer(blocks[2]),
+            }),
+            use_cache: None,
+        }
+    }
+
+    #[test]
+    fn encode_nothing_without_op_mode() {
+        let command = actuators_command(None, Some([Some([1, 2, 3, 4]), None, None, None]), None);
+        assert!(encode(&command).is_empty());
+    }
+
+    #[test]
+    fn encode_all_off() {
+        let command = actuators_command(Some((0, 1, 0)), None, None);
+        assert_eq!(vec![0x00, 0x00, 0x20, 0x03], encode(&command));
+
+        // The actuators and timers are ignored
+        let command = actuators_command(
+            Some((4, 1, 0)),
+            Some([Some([0xFF; 4]), None, None, None]),
+            Some([Some([1, 2, 3, 0]), None, None]),
+        );
+        assert_eq!(vec![0x00, 0x00, 0x24, 0x03], encode(&command));
+        assert_eq!(1, block_count(&encode(&command)));
+    }
+
+    #[test]
+    fn encode_cmd_op_0_ignores_actuators() {
+        let command = actuators_command(
+            Some((2, 0, 1)),
+            Some([Some([0x0F, 0, 0, 0]), None, None, None]),
+            None,
+        );
+        assert!(encode(&command).is_empty());
+    }
+
+    #[test]
+    fn encode_cmd_op_0_timing() {
+        // The timing of the e2e pulsing test, where the last two bytes of the LF timer don't fit
+        let command = actuators_command(
+            Some((2, 0, 2)),
+            Some([
+                Some([0x0F, 0, 0, 0]),
+                Some([0; 4]),
+                Some([0; 4]),
+                Some([0; 4]),
+            ]),
+            Some([
+                Some([0, 0, 0, 0]),
+                Some([30, 0, 150, 0]),
+                Some([0xFF, 0xFF, 0xFF, 0xFF]),
+            ]),
+        );
+        let data = encode(&command);
+        assert_eq!(
+            vec![0x00, 0x00, 0x02, 0x0C, 0x00, 0x1E, 0x00, 0x00, 0xFF, 0xFF, 0x00, 0x96],
+            data
+        );
+        assert_eq!(3, block_count(&data));
+    }
+
+    #[test]
+    fn encode_the_e2e_pulsing_commands() {
+        let commands = include_str!("../../../../commands/e2e-pulsing.txt");
+        match serde_json::from_str(commands).unwrap() {
+            CommandMessage::ActuatorsCommand {
+                fabric_name,
+                timer_mode_blocks,
+                actuator_mode_blocks,
+                op_mode_block,
+                ..
+            } => {
+                let command = ActuatorsCommand {
+                    fabric_name,
+                    timer_mode_blocks,
+                    actuator_mode_blocks,
+                    op_mode_block,
+                    use_cache: None,
+                };
+                assert_eq!(
+                    vec![0x00, 0x00, 0x02, 0x0C, 0x00, 0x1E, 0x00, 0x00, 0xFF, 0xFF, 0x00, 0x96],
+                    encode(&command)
+                );
+            }
+            other => panic!("Expected an ActuatorsCommand but got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn encode_cmd_op_1_timing() {
+        let command = actuators_command(
+            Some((0, 1, 1)),
+            None,
+            Some([None, None, Some([1, 2, 3, 4])]),
+        );
+        // The timers that are set are packed, so the LF timer starts in the first block
+        assert_eq!(
+            vec![0x02, 0x01, 0x20, 0x08, 0x00, 0x00, 0x04, 0x03],
+            encode(&command)
+        );
+
+        let command = actuators_command(
+            Some((0, 1, 1)),
+            None,
+            Some([Some([1, 2, 3, 4]), Some([5, 6, 7, 8]), None]),
+        );
+        assert_eq!(
+            vec![0x02, 0x01, 0x20, 0x0C, 0x06, 0x05, 0x04, 0x03, 0x00, 0x00, 0x08, 0x07],
+            encode(&command)
+        );
+    }
+
+    #[test]
+    fn encode_cmd_op_2_actuators() {
+        let command = actuators_command(
+            Some((0, 2, 1)),
+            Some([Some([0x0F, 0x01, 0, 0]), None, None, None]),
+            None,
+        );
+        let data = encode(&command);
+        assert_eq!(vec![0x0F, 0x01, 0x45, 0x08, 0x00, 0x00, 0x00, 0x01], data);
+        assert_eq!(2, block_count(&data));
+
+        // The second block is written even when only the first actuators are on
+        let command = actuators_command(
+            Some((0, 2, 3)),
+            Some([Some([0x0F, 0, 0, 0]), Some([0; 4]), None, None]),
+            None,
+        );
+        assert_eq!(
+            vec![0x0F, 0x03, 0x45, 0x08, 0x00, 0x00, 0x00, 0x00],
+            encode(&command)
+        );
+
+        // Nothing to turn on
+        let command = actuators_command(Some((0, 2, 1)), Some([None, None, None, None]), None);
+        assert!(encode(&command).is_empty());
+
+        // Only the timing, where act_cnt8 counts no actuators
+        let command = actuators_command(
+            Some((4, 2, 1)),
+            Some([None, None, None, None]),
+            Some([None, None, Some([1, 2, 3, 4])]),
+        );
+        assert_eq!(
+            vec![0x02, 0x01, 0x40, 0x08, 0x00, 0x00, 0x04, 0x03],
+            encode(&command)
+        );
+    }
+
+    #[test]
+    fn encode_cmd_op_2_drops_bytes_past_the_third_block() {
+        let command = actuators_command(
+            Some((0, 1, 2)),
+            Some([
+                Some([1, 2, 3, 4]),
+                Some([5, 6, 7, 8]),
+                Some([9, 0, 0, 0]),
+                Some([0x0A, 0, 0, 0]),
+            ]),
+            None,
+        );
+        assert_eq!(
+            vec![0x01, 0x02, 0x45, 0x0C, 0x05, 0x04, 0x03, 0x02, 0x09, 0x08, 0x07, 0x06],
+            encode(&command)
+        );
+    }
+
+    #[test]
+    fn encode_cmd_op_2_without_the_first_actuators() {
+        let command = actuators_command(
+            Some((0, 2, 1)),
+            Some([None, Some([0x80, 0, 0, 0]), None, None]),
+            None,
+        );
+        assert_eq!(
+            vec![0x00, 0x01, 0x45, 0x08, 0x00, 0x00, 0x00, 0x80],
+            encode(&command)
+        );
+    }
+
+    #[test]
+    fn encode_cmd_op_3_actuators_and_timing() {
+        // act_cnt8 counts the 2 actuator bytes, and the HF timer of 300 ms follows the last that is on
+        let command = actuators_command(
+            Some((0, 2, 2)),
+            Some([Some([0x0F, 0x01, 0, 0]), None, None, None]),
+            Some([None, Some([30, 0, 0x2C, 0x01]), None]),
+        );
+        assert_eq!(
+            vec![0x0F, 0x02, 0x62, 0x0C, 0x2C, 0x00, 0x1E, 0x01, 0x00, 0x00, 0x00, 0x01],
+            encode(&command)
+        );
+
+        // One actuator byte leaves room for two timers
+        let command = actuators_command(
+            Some((0, 2, 2)),
+            Some([Some([0x0F, 0, 0, 0]), None, None, None]),
+            Some([Some([0x10, 0x27, 0x64, 0]), Some([30, 0, 150, 0]), None]),
+        );
+        assert_eq!(8, timer_space(1));
+        assert_eq!(
+            vec![0x0F, 0x02, 0x61, 0x0C, 0x00, 0x64, 0x27, 0x10, 0x00, 0x96, 0x00, 0x1E],
+            encode(&command)
+        );
+    }
+
+    #[test]
+    fn encode_cmd_op_3_splits_timers_across_blocks() {
+        // The HF timer starts in the second block and ends in the third
+        let command = actuators_command(
+            Some((0, 2, 2)),
+            Some([Some([0x0F, 0x01, 0x02, 0]), None, None, None]),
+            Some([None, Some([30, 0, 150, 0]), None]),
+        );
+        assert_eq!(
+            vec![0x0F, 0x02, 0x63, 0x0C, 0x00, 0x1E, 0x02, 0x01, 0x00, 0x00, 0x00, 0x96],
+            encode(&command)
+        );
+
+        // Bytes past the third block are left out, here the LF timer after two others
+        let command = actuators_command(
+            Some((0, 2, 2)),
+            Some([Some([0x0F, 0, 0, 0]), None, None, None]),
+            Some([
+                Some([0, 0, 0, 0]),
+                Some([30, 0, 150, 0]),
+                Some([0xFF, 0, 0xFF, 0]),
+            ]),
+        );
+        assert_eq!(
+            vec![0x0F, 0x02, 0x61, 0x0C, 0x00, 0x00, 0x00, 0x00, 0x00, 0x96, 0x00, 0x1E],
+            encode(&command)
+        );
+
+        // Five actuator bytes leave room for only the single pulse
+        let command = actuators_command(
+            Some((0, 2, 2)),
+            Some([Some([0x0F, 1, 2, 3]), Some([4, 0, 0, 0]), None, None]),
+            Some([Some([0x10, 0x27, 0, 0]), Some([30, 0, 150, 0]), None]),
+        );
+        assert_eq!(4, timer_space(5));
+        assert_eq!(
+            vec![0x0F, 0x02, 0x65, 0x0C, 0x04, 0x03, 0x02, 0x01, 0x00, 0x00, 0x27, 0x10],
+            encode(&command)
+        );
+    }
+
+    #[test]
+    fn decode_what_was_encoded() {
+        let command = actuators_command(
+            Some((0, 2, 1)),
+            Some([Some([0x0F, 0x01, 0, 0]), None, None, None]),
+            None,
+        );
+        let memory = decode(&encode(&command)).unwrap();
+        assert_eq!(8, memory.num_bytes);
+        assert_eq!(
+            OpModeBlock {
+                act_cnt8: 5,
+                cmd_op: 2,
+                command: 1,
+            },
+            memory.op_mode_block
+        );
+        assert_eq!(
+            command.actuator_mode_blocks.unwrap().block0_31,
+            memory.actuator_mode_blocks.unwrap().block0_31
+        );
+        assert_eq!(None, memory.timer_mode_blocks);
+
+        let command = actuators_command(
+            Some((0, 1, 1)),
+            None,
+            Some([Some([1, 2, 3, 4]), Some([5, 6, 7, 8]), None]),
+        );
+        let memory = decode(&encode(&command)).unwrap();
+        assert_eq!(1, memory.op_mode_block.cmd_op);
+        assert_eq!(None, memory.actuator_mode_blocks);
+        let timers = memory.timer_mode_blocks.unwrap();
+        let expected = command.timer_mode_blocks.unwrap();
+        assert_eq!(expected.single_pulse_block, timers.single_pulse_block);
+        assert_eq!(expected.hf_block, timers.hf_block);
+        assert_eq!(None, timers.lf_block);
+    }
+
+    #[test]
+    fn decode_all_off() {
+        let memory = decode(&[0x00, 0x00, 0x20, 0x03]).unwrap();
+        assert_eq!(3, memory.num_bytes);
+        assert_eq!(
+            OpModeBlock {
+                act_cnt8: 0,
+                cmd_op: 1,
+                command: 0,
+            },
+            memory.op_mode_block
+        );
+        assert_eq!(None, memory.actuator_mode_blocks);
+        assert_eq!(None, memory.timer_mode_blocks);
+    }
+
+    #[test]
+    fn decode_what_set_actuators_encoded() {
+        let single_pulse = timing::SinglePulse {
+            pulse_ms: 0,
+            pause_ms: 0,
+        };
+        let hf = timing::PulsedSignal {
+            period_ms: 150,
+            duty_cycle: 20.0,
+        };
+        // The timers of commands/set-actuators.txt after 1 actuator byte, and the HF timer after 3 and 5
+        let cases = [
+            (vec![0, 1, 2, 3], Some(single_pulse), 1),
+            (vec![0, 1, 2, 3, 20], None, 3),
+            (vec![0, 1, 2, 3, 39], None, 5),
+        ];
+        for (on, single_pulse, act_cnt8) in cases.iter() {
+            let timing = timing::Timing {
+                single_pulse: single_pulse.clone(),
+                hf: Some(hf.clone()),
+                lf: None,
+            };
+            let command = ActuatorsCommand::set_actuators("Sleeve", on, 2, Some(&timing)).unwrap();
+            let memory = decode(&encode(&command)).unwrap();
+            assert_eq!(
+                OpModeBlock {
+                    act_cnt8: *act_cnt8,
+                    cmd_op: 3,
+                    command: 2,
+                },
+                memory.op_mode_block
+            );
+            assert_eq!(*on, memory.actuator_mode_blocks.unwrap().indices());
+            // The timers are packed, so they read back in the order they were written
+            let written = command.timer_mode_blocks.unwrap();
+            let read = memory.timer_mode_blocks.unwrap();
+            assert_eq!(timer_bytes(&written), timer_bytes(&read));
+            if single_pulse.is_some() {
+                assert_eq!(written, read);
+            }
+        }
+
+        assert!(decode(&[0x00, 0x00, 0x45, 0x08]).is_err());
+    }
+}
@@ -0,0 +1,303 @@
+use super::*;
+
+/// The longest period of the HF and LF signals and the longest pause, which the timer block keeps in two bytes
+pub const MAX_PERIOD_MS: u32 = 0xFFFF;
+
+/// The longest pulse, which the timer block keeps in two bytes
+pub const MAX_PULSE_MS: u32 = 0xFFFF;
+
+/// How long a single pulse stays on, and the pause between pulses in gestures
+#[derive(PartialEq, Clone, Serialize, Deserialize, Debug)]
+pub struct SinglePulse {
+    pub pulse_ms: u32,
+    pub pause_ms: u32,
+}
+
+/// A signal that is on for the duty cycle of each period
+#[derive(PartialEq, Clone, Serialize, Deserialize, Debug)]
+pub struct PulsedSignal {
+    pub period_ms: u32,
+    /// The percent of the period the signal is on, where 100 keeps it on
+    pub duty_cycle: f32,
+}
+
+/**
+ * The timing of the actuators in ms and percent, which encodes into the
+ * TimerModeBlocks of an actuators command. Each block holds the time on in
+ * ms in b0 and b1 and the period or pause in ms in b2 and b3, LSB first.
+ */
+#[derive(PartialEq, Clone, Serialize, Deserialize, Debug)]
+pub struct Timing {
+    pub single_pulse: Option<SinglePulse>,
+    /// The high frequency carrier used in single pulse and continuous mode
+    pub hf: Option<PulsedSignal>,
+    /// The low frequency signal of continuous pulsed mode
+    pub lf: Option<PulsedSignal>,
+}
+
+fn timer_mode_block(time_on_ms: u16, period_ms: u16) -> TimerModeBlock {
+    TimerModeBlock {
+        b0: (time_on_ms & 0x00FF) as u8,
+        b1: ((time_on_ms & 0xFF00) >> 8) as u8,
+        b2: (period_ms & 0x00FF) as u8,
+        b3: ((period_ms & 0xFF00) >> 8) as u8,
+    }
+}
+
+impl SinglePulse {
+    pub fn timer_mode_block(self: &Self) -> Result<TimerModeBlock> {
+        if self.pulse_ms > MAX_PULSE_MS {
+            return Err(InternalError::from(format!(
+                "Expected a single pulse of at most {} ms but found {} ms",
+                MAX_PULSE_MS, self.pulse_ms
+            )));
+        }
+        if self.pause_ms > MAX_PERIOD_MS {
+            return Err(InternalError::from(format!(
+                "Expected a pause of at most {} ms but found {} ms",
+                MAX_PERIOD_MS, self.pause_ms
+            )));
+        }
+        Ok(timer_mode_block(self.pulse_ms as u16, self.pause_ms as u16))
+    }
+}
+
+impl PulsedSignal {
+    /**
+     * The time on in whole ms, rounded to the nearest ms. The firmware keeps
+     * the signal on when the time on equals the period and off when it is 0,
+     * so a duty cycle between 0 and 100 stays at least 1 ms on and 1 ms off.
+     */
+    pub fn time_on_ms(self: &Self) -> Result<u32> {
+        if self.period_ms == 0 || self.period_ms > MAX_PERIOD_MS {
+            return Err(InternalError::from(format!(
+                "Expected a period from 1 to {} ms but found {} ms",
+                MAX_PERIOD_MS, self.period_ms
+            )));
+        }
+        if !(0.0..=100.0).contains(&self.duty_cycle) {
+            return Err(InternalError::from(format!(
+                "Expected a duty cycle from 0 to 100 % but found {} %",
+                self.duty_cycle
+            )));
+        }
+        if self.duty_cycle == 0.0 {
+            return Ok(0);
+        }
+        if self.duty_cycle == 100.0 {
+            return Ok(self.period_ms);
+        }
+        let time_on_ms = (self.period_ms as f32 * self.duty_cycle / 100.0).round() as u32;
+        Ok(time_on_ms.max(1).min(self.period_ms - 1))
+    }
+
+    pub fn timer_mode_block(self: &Self) -> Result<TimerModeBlock> {
+        let time_on_ms = self.time_on_ms()?;
+        Ok(timer_mode_block(time_on_ms as u16, self.period_ms as u16))
+    }
+}
+
+impl Timing {
+    /// Validate each timing and lay it out in its timer block
+    pub fn timer_mode_blocks(self: &Self) -> Result<TimerModeBlocks> {
+        Ok(TimerModeBlocks {
+            single_pulse_block: match &self.single_pulse {
+                Some(single_pulse) => Some(single_pulse.timer_mode_block()?),
+                None => None,
+            },
+            hf_block: match &self.hf {
+                Some(hf) => Some(hf.timer_mode_block()?),
+                None => None,
+            },
+            lf_block: match &self.lf {
+                Some(lf) => Some(lf.timer_mode_block()?),
+                None => None,
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signal(period_ms: u32, duty_cycle: f32) -> PulsedSignal {
+        PulsedSignal {
+            period_ms,
+            duty_cycle,
+        }
+    }
+
+    #[test]
+    fn encode_the_timing_of_the_e2e_test() {
+        let timing = Timing {
+            single_pulse: Some(SinglePulse {
+                pulse_ms: 0,
+                pause_ms: 0,
+            }),
+            hf: Some(signal(150, 20.0)),
+            lf: None,
+        };
+        assert_eq!(
+            TimerModeBlocks {
+                single_pulse_block: Some(TimerModeBlock {
+                    b0: 0,
+                    b1: 0,
+                    b2: 0,
+                    b3: 0,
+                }),
+                hf_block: Some(TimerModeBlock {
+                    b0: 30,
+                    b1: 0,
+                    b2: 150,
+                    b3: 0,
+                }),
+                lf_block: None,
+            },
+            timing.timer_mode_blocks().unwrap()
+        );
+    }
+
+    #[test]
+    fn encode_a_long_single_pulse() {
+        let single_pulse = SinglePulse {
+            pulse_ms: 4000,
+            pause_ms: 250,
+        };
+        assert_eq!(
+            TimerModeBlock {
+                b0: 0xA0,
+                b1: 0x0F,
+                b2: 250,
+                b3: 0,
+            },
+            single_pulse.timer_mode_block().unwrap()
+        );
+    }
+
+    #[test]
+    fn encode_the_lf_period_in_two_bytes() {
+        // The LF example of 4000 ms in the firmware notes of the e2e test
+        assert_eq!(
+            TimerModeBlock {
+                b0: 0xD0,
+                b1: 0x07,
+                b2: 0xA0,
+                b3: 0x0F,
+            },
+            signal(4000, 50.0).timer_mode_block().unwrap()
+        );
+
+        let timing = Timing {
+            single_pulse: None,
+            hf: Some(signal(150, 20.0)),
+            lf: Some(signal(1000, 50.0)),
+        };
+        let blocks = timing.timer_mode_blocks().unwrap();
+        assert_eq!(
+            Some(TimerModeBlock {
+                b0: 0xF4,
+                b1: 0x01,
+                b2: 0xE8,
+                b3: 0x03,
+            }),
+            blocks.lf_block
+        );
+        assert_eq!(65535, signal(65535, 100.0).time_on_ms().unwrap());
+    }
+
+    #[test]
+    fn round_the_time_on() {
+        assert_eq!(0, signal(100, 0.0).time_on_ms().unwrap());
+        assert_eq!(100, signal(100, 100.0).time_on_ms().unwrap());
+        assert_eq!(33, signal(100, 33.4).time_on_ms().unwrap());
+        assert_eq!(34, signal(100, 33.5).time_on_ms().unwrap());
+
+        // Neither a short pulse nor a long one turns into off or always on
+        assert_eq!(1, signal(10, 1.0).time_on_ms().unwrap());
+        assert_eq!(9, signal(10, 99.0).time_on_ms().unwrap());
+    }
+
+    #[test]
+    fn parse_the_set_actuators_commands() {
+        let commands = include_str!("../../../../commands/set-actuators.txt");
+        match serde_json::from_str(commands).unwrap() {
+            CommandMessage::SetActuators {
+                on,
+                command,
+                timing: Some(timing),
+                ..
+            } => {
+                assert!(
+                    ActuatorsCommand::set_actuators("fabric0", &on, command, Some(&timing)).is_ok()
+                );
+                let blocks = timing.timer_mode_blocks().unwrap();
+                assert_eq!(
+                    Some(TimerModeBlock {
+                        b0: 30,
+                        b1: 0,
+                        b2: 150,
+                        b3: 0,
+                    }),
+                    blocks.hf_block
+                );
+            }
+            other => panic!("Expected SetActuators with timing but got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reject_timings_out_of_range() {
+        assert!(signal(0, 50.0).timer_mode_block().is_err());
+        assert!(signal(65536, 50.0).timer_mode_block().is_err());
+        assert!(signal(100, -1.0).timer_mode_block().is_err());
+        assert!(signal(100, 100.5).timer_mode_block().is_err());
+        assert!(signal(100, f32::NAN).timer_mode_block().is_err());
+
+        let single_pulse = SinglePulse {
+            pulse_ms: 65536,
+            pause_ms: 0,
+        };
+        assert!(single_pulse.timer_mode_block().is_err());
+        let single_pulse = SinglePulse {
+            pulse_ms: 0,
+            pause_ms: 65536,
+        };
+        assert!(single_pulse.timer_mode_block().is_err());
+    }
+
+    #[test]
+    fn reject_timings_that_dont_fit_after_the_actuators() {
+        let timing = Timing {
+            single_pulse: Some(SinglePulse {
+                pulse_ms: 100,
+                pause_ms: 100,
+            }),
+            hf: Some(signal(150, 20.0)),
+            lf: Some(signal(255, 100.0)),
+        };
+        let err =
+            ActuatorsCommand::set_actuators("Sleeve", &[0, 1, 2, 3], 2, Some(&timing)).unwrap_err();
+        assert_eq!(
+            "The timing takes 12 bytes but only 8 fit after actuator 3",
+            err.to_string()
+        );
+
+        // Without the single pulse, the HF and LF timers fit after one byte of actuators
+        let timing = Timing {
+            single_pulse: None,
+            ..timing
+        };
+        assert!(ActuatorsCommand::set_actuators("Sleeve", &[0, 7], 2, Some(&timing)).is_ok());
+        assert!(ActuatorsCommand::set_actuators("Sleeve", &[8], 2, Some(&timing)).is_err());
+
+        // The LF timer alone fits after up to five bytes of actuators
+        let timing = Timing { hf: None, ..timing };
+        assert!(ActuatorsCommand::set_actuators("Sleeve", &[39], 2, Some(&timing)).is_ok());
+        assert!(ActuatorsCommand::set_actuators("Sleeve", &[40], 2, Some(&timing)).is_err());
+
+        // All off has no actuators to time
+        assert!(ActuatorsCommand::set_actuators("Sleeve", &[], 0, Some(&timing)).is_err());
+        assert!(ActuatorsCommand::set_actuators("Sleeve", &[], 0, None).is_ok());
+    }
+}
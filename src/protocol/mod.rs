@@ -1,3 +1,5 @@
 pub mod common;
 pub mod haptic;
 pub mod mock;
+pub mod registry;
+pub mod state;
@@ -0,0 +1,93 @@
+use crate::conn::common::Connection;
+use crate::error::*;
+use crate::protocol::common::Protocol;
+use crate::protocol::{haptic::v0::HapticV0Protocol, mock::MockProtocol};
+
+/// Creates a protocol that talks over the connection to the reader at the COM-ADR
+pub type ProtocolFactory =
+    for<'a> fn(Box<dyn Connection<'a> + 'a>, u8) -> Box<dyn Protocol<'a> + 'a>;
+
+/// The protocols that a server may be started with, by name
+pub struct ProtocolRegistry {
+    factories: Vec<(&'static str, ProtocolFactory)>,
+}
+
+impl ProtocolRegistry {
+    pub fn new() -> ProtocolRegistry {
+        ProtocolRegistry { factories: vec![] }
+    }
+
+    /// Add the protocol or replace an existing one with the same name
+    pub fn register(self: &mut Self, name: &'static str, factory: ProtocolFactory) {
+        self.factories.retain(|(existing, _)| *existing != name);
+        self.factories.push((name, factory));
+    }
+
+    pub fn names(self: &Self) -> Vec<&'static str> {
+        self.factories.iter().map(|(name, _)| *name).collect()
+    }
+
+    pub fn create<'a>(
+        self: &Self,
+        name: &str,
+        connection: Box<dyn Connection<'a> + 'a>,
+        com_adr: u8,
+    ) -> Result<Box<dyn Protocol<'a> + 'a>> {
+        match self
+            .factories
+            .iter()
+            .find(|(existing, _)| *existing == name)
+        {
+            Some((_, factory)) => Ok(factory(connection, com_adr)),
+            None => Err(InternalError::from(format!(
+                "Unknown protocol '{}', expected one of: {}",
+                name,
+                self.names().join(", ")
+            ))),
+        }
+    }
+}
+
+impl Default for ProtocolRegistry {
+    /// All of the protocols in this crate
+    fn default() -> Self {
+        let mut registry = ProtocolRegistry::new();
+        registry.register("haptic_v0", |connection, com_adr| {
+            Box::new(HapticV0Protocol::new(connection, com_adr))
+        });
+        registry.register("mock", |connection, _| {
+            Box::new(MockProtocol::new(connection))
+        });
+        registry
+    }
+}
+
+/// The protocol used when none is chosen, which the haptic_v0 feature makes haptic_v0
+pub fn default_protocol() -> &'static str {
+    if cfg!(feature = "haptic_v0") {
+        "haptic_v0"
+    } else {
+        "mock"
+    }
+}
+
+#[cfg(all(test, feature = "mock"))]
+mod tests {
+    use super::*;
+    use crate::conn::mock::MockConnection;
+    use crate::obid::Framing;
+    use crate::protocol::common::CommandMessage;
+
+    #[test]
+    fn create_registered_protocols() {
+        let registry = ProtocolRegistry::default();
+        assert_eq!(vec!["haptic_v0", "mock"], registry.names());
+
+        let connection = Box::new(MockConnection::new(Framing::Advanced));
+        let mut protocol = registry.create("mock", connection, 0xFF).unwrap();
+        assert!(protocol.handle_message(&CommandMessage::Success {}).is_ok());
+
+        let connection = Box::new(MockConnection::new(Framing::Advanced));
+        assert!(registry.create("haptic_v9", connection, 0xFF).is_err());
+    }
+}
@@ -0,0 +1,93 @@
+use crate::error::*;
+use crate::protocol::common::FabricRecord;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// The fabrics of each reader, kept in a JSON file so a host can restart without losing them
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct HostState {
+    /// The fabrics registered with each reader by the name of the reader
+    pub readers: BTreeMap<String, Vec<FabricRecord>>,
+}
+
+impl HostState {
+    /// A missing state file is an empty state, as on the first start of a host
+    pub fn load(path: &std::path::Path) -> Result<HostState> {
+        match std::fs::File::open(path) {
+            Ok(file) => Ok(serde_json::from_reader(std::io::BufReader::new(file))?),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(HostState::default()),
+            Err(err) => Err(InternalError::from(err)),
+        }
+    }
+
+    /// Written next to the state file and renamed over it, so a crash never leaves half a file
+    pub fn save(self: &Self, path: &std::path::Path) -> Result<()> {
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".partial");
+        let partial = std::path::PathBuf::from(partial);
+        std::fs::write(&partial, serde_json::to_string_pretty(self)?)?;
+        std::fs::rename(&partial, path)?;
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "mock"))]
+mod tests {
+    use super::*;
+    use crate::conn::common::Context;
+    use crate::conn::simulated::SimulatedContext;
+    use crate::obid::Framing;
+    use crate::protocol::common::{CommandMessage, Protocol};
+    use crate::protocol::haptic::v0::{HapticV0Protocol, OpModeBlock};
+
+    fn protocol() -> HapticV0Protocol<'static> {
+        let connection = SimulatedContext::new(Framing::Advanced)
+            .connection()
+            .unwrap();
+        HapticV0Protocol::new(connection, 0xFF)
+    }
+
+    #[test]
+    fn save_and_load_the_fabrics() {
+        let path = std::env::temp_dir().join(format!("state-{}.json", uuid::Uuid::new_v4()));
+        assert_eq!(HostState::default(), HostState::load(&path).unwrap());
+
+        let mut saving = protocol();
+        let fabric_name = String::from("Sleeve");
+        saving
+            .handle_message(&CommandMessage::AddFabric {
+                fabric_name: fabric_name.clone(),
+                uids: None,
+                check_uids: None,
+            })
+            .unwrap();
+        saving
+            .handle_message(&CommandMessage::ActuatorsCommand {
+                fabric_name: fabric_name.clone(),
+                timer_mode_blocks: None,
+                actuator_mode_blocks: None,
+                op_mode_block: Some(OpModeBlock {
+                    act_cnt8: 0,
+                    cmd_op: 2,
+                    command: 1,
+                }),
+                use_cache: None,
+                verify: None,
+            })
+            .unwrap();
+        let mut state = HostState::default();
+        state.readers.insert(String::from("left"), saving.fabrics());
+        state.save(&path).unwrap();
+
+        let state = HostState::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let fabrics = state.readers.get("left").unwrap().clone();
+        assert_eq!(1, fabrics.len());
+        assert_eq!(vec![String::from("e004010000000001")], fabrics[0].uids);
+        assert_eq!(Some(true), fabrics[0].state.as_ref().unwrap().use_cache);
+
+        let mut restored = protocol();
+        restored.restore_fabrics(fabrics).unwrap();
+        assert_eq!(saving.fabrics(), restored.fabrics());
+    }
+}
@@ -1,6 +1,9 @@
 use protocol_host_lib::error::*;
 
-use protocol_host_lib::conn::common::Context;
+use protocol_host_lib::conn::common::{Connection, Context};
+use protocol_host_lib::conn::simulated::{SimulatedContext, VirtualTransponder};
+use protocol_host_lib::conn::supervisor::SupervisedConnection;
+use protocol_host_lib::protocol::common::CommandMessage;
 use std::{sync::mpsc, thread, time::Duration};
 
 fn conn_type() -> &'static str {
@@ -8,6 +11,8 @@ fn conn_type() -> &'static str {
         return "ethernet";
     } else if cfg!(feature = "usb") {
         return "usb";
+    } else if cfg!(feature = "haptic_v0") {
+        return "simulated";
     } else {
         return "mock";
     }
@@ -36,12 +41,127 @@ pub fn connect_client_to_server(
     timeout: u64,
     client_commands: std::vec::Vec<String>,
 ) -> Result<()> {
+    connect_client_to_readers(timeout, vec!["default"], client_commands)
+}
+
+/// Serves a reader and returns the reply to each command, where every command expects success
+pub fn request_replies(
+    timeout: u64,
+    client_commands: std::vec::Vec<String>,
+) -> Result<std::vec::Vec<CommandMessage>> {
+    let client_commands = client_commands
+        .into_iter()
+        .map(|command| (command, true))
+        .collect();
+    connect_client_to_readers_with_faults(
+        timeout,
+        vec!["default"],
+        None,
+        None,
+        None,
+        client_commands,
+    )
+}
+
+/// Serves the named readers, where only mock and simulated connections may have more than one
+pub fn connect_client_to_readers(
+    timeout: u64,
+    readers: std::vec::Vec<&'static str>,
+    client_commands: std::vec::Vec<String>,
+) -> Result<()> {
+    let client_commands = client_commands
+        .into_iter()
+        .map(|command| (command, true))
+        .collect();
+    connect_client_to_readers_with_faults(timeout, readers, None, None, None, client_commands)?;
+    Ok(())
+}
+
+#[cfg(feature = "haptic_v0")]
+/// Serves a reader that keeps its fabrics in the state file, where each command expects success or failure
+pub fn connect_client_to_server_with_state_file(
+    timeout: u64,
+    state_file: &std::path::Path,
+    client_commands: std::vec::Vec<(String, bool)>,
+) -> Result<()> {
+    connect_client_to_readers_with_faults(
+        timeout,
+        vec!["default"],
+        None,
+        None,
+        Some(state_file.to_path_buf()),
+        client_commands,
+    )?;
+    Ok(())
+}
+
+/// Serves a reader whose replies get the faults, where each command expects success or failure
+#[cfg(all(feature = "haptic_v0", not(any(feature = "usb", feature = "ethernet"))))]
+pub fn connect_client_to_faulty_server(
+    timeout: u64,
+    faults: protocol_host_lib::conn::mock::FaultPlan,
+    client_commands: std::vec::Vec<(String, bool)>,
+) -> Result<()> {
+    connect_client_to_readers_with_faults(
+        timeout,
+        vec!["default"],
+        Some(faults),
+        None,
+        None,
+        client_commands,
+    )?;
+    Ok(())
+}
+
+/// Serves a simulated reader and returns the reply to each command, where each command expects success or failure
+#[cfg(all(feature = "haptic_v0", not(any(feature = "usb", feature = "ethernet"))))]
+pub fn request_simulated_replies(
+    timeout: u64,
+    client_commands: std::vec::Vec<(String, bool)>,
+) -> Result<std::vec::Vec<CommandMessage>> {
+    connect_client_to_readers_with_faults(
+        timeout,
+        vec!["default"],
+        None,
+        None,
+        None,
+        client_commands,
+    )
+}
+
+/// Serves a simulated reader with the transponders in its antenna instead of the one by default
+#[cfg(all(feature = "haptic_v0", not(any(feature = "usb", feature = "ethernet"))))]
+pub fn request_simulated_replies_with_transponders(
+    timeout: u64,
+    transponders: std::vec::Vec<VirtualTransponder>,
+    client_commands: std::vec::Vec<(String, bool)>,
+) -> Result<std::vec::Vec<CommandMessage>> {
+    connect_client_to_readers_with_faults(
+        timeout,
+        vec!["default"],
+        None,
+        Some(transponders),
+        None,
+        client_commands,
+    )
+}
+
+fn connect_client_to_readers_with_faults(
+    timeout: u64,
+    readers: std::vec::Vec<&'static str>,
+    faults: Option<protocol_host_lib::conn::mock::FaultPlan>,
+    transponders: Option<std::vec::Vec<VirtualTransponder>>,
+    state_file: Option<std::path::PathBuf>,
+    client_commands: std::vec::Vec<(String, bool)>,
+) -> Result<std::vec::Vec<CommandMessage>> {
+    let faults = faults.map(|plan| std::sync::Arc::new(std::sync::Mutex::new(plan)));
+
     // Multiple tests may attempt to re-register the logger
     let _ = simple_logger::SimpleLogger::new()
         .with_level(log::LevelFilter::Debug)
         .init();
 
-    panic_after(Duration::from_millis(timeout), move || {
+    let replies = panic_after(Duration::from_millis(timeout), move || {
         let proxy_front_endpoint = std::sync::Mutex::new(String::from(""));
         let proxy_back_endpoint = std::sync::Mutex::new(String::from(""));
 
@@ -119,46 +239,85 @@ pub fn connect_client_to_server(
                 let server_context =
                     protocol_host_lib::network::server::ServerContext::new((&endpoint).clone())?;
 
-                loop {
-                    let serve_again = match conn_type() {
-                        "mock" => {
-                            let context =
-                                Box::new(protocol_host_lib::conn::mock::MockContext::new());
-                            let connection = context.connection()?;
-                            start_server_with_connection(connection, &server_context)
+                let served = match conn_type() {
+                    "mock" | "simulated" => {
+                        let contexts: std::vec::Vec<Box<dyn Context>> = readers
+                            .iter()
+                            .map(|_| -> Box<dyn Context> {
+                                let framing = protocol_host_lib::obid::Framing::Advanced;
+                                let reader: Box<dyn Context> = if conn_type() == "simulated" {
+                                    Box::new(match &transponders {
+                                        Some(transponders) => SimulatedContext::with_transponders(
+                                            framing,
+                                            transponders.clone(),
+                                        ),
+                                        None => SimulatedContext::new(framing),
+                                    })
+                                } else {
+                                    Box::new(protocol_host_lib::conn::mock::MockContext::new(
+                                        framing,
+                                    ))
+                                };
+                                match &faults {
+                                    Some(plan) => {
+                                        Box::new(protocol_host_lib::conn::mock::FaultyContext {
+                                            reader,
+                                            framing,
+                                            plan: plan.clone(),
+                                        })
+                                    }
+                                    None => reader,
+                                }
+                            })
+                            .collect();
+                        let mut connections: std::vec::Vec<(String, Box<dyn Connection>)> = vec![];
+                        for (name, context) in readers.iter().zip(contexts.into_iter()) {
+                            connections.push((
+                                String::from(*name),
+                                Box::new(SupervisedConnection::new(context)?),
+                            ));
                         }
-                        #[cfg(feature = "usb")]
-                        "usb" => {
-                            let context = Box::new(protocol_host_lib::conn::usb::UsbContext::new(
-                                &libusb_context,
+                        start_server_with_connections(
+                            connections,
+                            state_file.as_deref(),
+                            &server_context,
+                        )
+                    }
+                    #[cfg(feature = "usb")]
+                    "usb" => {
+                        let context = Box::new(protocol_host_lib::conn::usb::UsbContext::new(
+                            &libusb_context,
+                            protocol_host_lib::obid::Framing::Advanced,
+                        )?);
+                        let connection = context.connection()?;
+                        start_server_with_connections(
+                            vec![(String::from(readers[0]), connection)],
+                            state_file.as_deref(),
+                            &server_context,
+                        )
+                    }
+                    #[cfg(feature = "ethernet")]
+                    "ethernet" => {
+                        let context =
+                            Box::new(protocol_host_lib::conn::ethernet::EthernetContext::new(
+                                "192.168.10.10:10001",
+                                protocol_host_lib::obid::Framing::Advanced,
                             )?);
-                            let connection = context.connection()?;
-                            start_server_with_connection(connection, &server_context)
-                        }
-                        #[cfg(feature = "ethernet")]
-                        "ethernet" => {
-                            let context =
-                                Box::new(protocol_host_lib::conn::ethernet::EthernetContext::new(
-                                    "192.168.10.10:10001",
-                                )?);
-                            let connection = context.connection()?;
-                            start_server_with_connection(connection, &server_context)
-                        }
-                        _ => return Err(InternalError::from("No conn_type")),
-                    };
-
-                    log::info!("Finished serving with {:?}", serve_again);
-                    match serve_again {
-                        Ok(true) => {
-                            log::info!("Continuing to serve ...");
-                        }
-                        Ok(false) => return Ok(()),
-                        Err(err) => {
-                            log::error!("Stopping serve due to: {:?}", err);
-                            return Err(err);
-                        }
+                        let connection = context.connection()?;
+                        start_server_with_connections(
+                            vec![(String::from(readers[0]), connection)],
+                            state_file.as_deref(),
+                            &server_context,
+                        )
                     }
+                    _ => return Err(InternalError::from("No conn_type")),
+                };
+
+                log::info!("Finished serving with {:?}", served);
+                if let Err(err) = &served {
+                    log::error!("Stopping serve due to: {:?}", err);
                 }
+                served
             };
             match work() {
                 Err(err) => panic!("{}", err),
@@ -187,32 +346,38 @@ pub fn connect_client_to_server(
             let mut client = client.unwrap();
 
             let mut client_commands = client_commands;
-            client_commands.push(String::from(r#"{ "Stop": {} }"#));
+            client_commands.push((String::from(r#"{ "Stop": {} }"#), true));
 
             log::info!("Running client commands:");
-            for command in &client_commands {
+            for (command, _) in &client_commands {
                 log::info!("{}", command);
             }
 
-            for command in &client_commands {
+            let mut replies = vec![];
+            for (command, success) in &client_commands {
                 let command_stream = serde_json::Deserializer::from_str(command.as_str())
-                    .into_iter::<protocol_host_lib::protocol::common::CommandMessage>(
-                );
+                    .into_iter::<CommandMessage>();
                 for command in command_stream {
                     assert!(command.is_ok());
                     let result = client.request_message(command.unwrap());
-                    assert!(result.is_ok());
+                    assert_eq!(*success, result.is_ok());
+                    replies.push(result.unwrap_or_else(|err| CommandMessage::Failure {
+                        message: err.to_string(),
+                    }));
                 }
             }
-            ()
+
+            // The reply to the Stop isn't one of the commands
+            replies.pop();
+            replies
         });
 
         let server_result = server_handle.join();
         log::info!("Server is finished.");
         let client_result = client_handle.join();
         log::info!("Client is finished.");
-        assert!(client_result.is_ok());
         assert!(server_result.is_ok());
+        let replies = client_result.expect("Client panicked");
 
         log::info!("Signaling proxy to terminate");
         assert!(publisher
@@ -223,16 +388,28 @@ pub fn connect_client_to_server(
         let proxy_result = proxy_handle.join();
         assert!(proxy_result.is_ok());
         log::info!("Success");
+        replies
     });
 
-    Ok(())
+    Ok(replies)
 }
 
-fn start_server_with_connection<'a, 'b>(
-    connection: Box<dyn protocol_host_lib::conn::common::Connection<'a> + 'a>,
+fn start_server_with_connections<'a, 'b>(
+    connections: std::vec::Vec<(
+        String,
+        Box<dyn protocol_host_lib::conn::common::Connection<'a> + 'a>,
+    )>,
+    state_file: Option<&std::path::Path>,
     server_context: &'b protocol_host_lib::network::server::ServerContext,
-) -> Result<bool> {
-    let mut server = protocol_host_lib::network::server::Server::new(server_context, connection)
-        .expect("Failed to initialize server");
+) -> Result<()> {
+    let mut server = protocol_host_lib::network::server::Server::with_readers(
+        server_context,
+        connections,
+        protocol_host_lib::obid::BROADCAST_COM_ADR,
+    )
+    .expect("Failed to initialize server");
+    if let Some(state_file) = state_file {
+        server.persist_to(state_file)?;
+    }
     server.serve()
 }
@@ -1,4 +1,5 @@
 use protocol_host_lib::error::*;
+use protocol_host_lib::protocol::common::CommandMessage;
 
 mod common;
 
@@ -13,11 +14,21 @@ fn nop_serve_to_client() -> Result<()> {
 fn system_reset() -> Result<()> {
     #[allow(unused_variables)]
     let timeout = 10000;
-    #[cfg(any(feature = "usb", feature = "ethernet"))]
+    // Allow for the delay after a reset of the reader
+    #[cfg(any(feature = "usb", feature = "ethernet", feature = "haptic_v0"))]
     let timeout = 10000;
     connect_client_to_server(timeout, vec![String::from(r#"{ "SystemReset": { } }"#)])
 }
 
+#[test]
+fn fetch_reader_info() -> Result<()> {
+    #[allow(unused_variables)]
+    let timeout = 500;
+    #[cfg(any(feature = "usb", feature = "ethernet"))]
+    let timeout = 10000;
+    connect_client_to_server(timeout, vec![String::from(r#"{ "ReaderInfo": {} }"#)])
+}
+
 #[test]
 fn connect_to_fabric() -> Result<()> {
     #[allow(unused_variables)]
@@ -32,11 +43,527 @@ fn connect_to_fabric() -> Result<()> {
     )
 }
 
+#[test]
+fn query_fabric_state() -> Result<()> {
+    #[allow(unused_variables)]
+    let timeout = 1000;
+    #[cfg(any(feature = "usb", feature = "ethernet"))]
+    let timeout = 10000;
+    let replies = request_replies(
+        timeout,
+        vec![
+            String::from(r#"{ "AddFabric": { "fabric_name": "Obid Feig LRM2500-B" } }"#),
+            String::from(r#"{ "FabricState": { "fabric_name": "Obid Feig LRM2500-B" } }"#),
+        ],
+    )?;
+    assert_eq!(2, replies.len());
+
+    // The mock protocol has no fabrics to reply with
+    if cfg!(feature = "haptic_v0") {
+        match &replies[1] {
+            CommandMessage::Fabric {
+                fabric_name,
+                uids,
+                state,
+            } => {
+                assert_eq!("Obid Feig LRM2500-B", fabric_name);
+                assert!(!uids.is_empty());
+                #[cfg(not(any(feature = "usb", feature = "ethernet")))]
+                assert_eq!(&vec![String::from("e004010000000001")], uids);
+
+                // A new fabric is all off
+                let state = state.as_ref().expect("Expected the state of the fabric");
+                assert_eq!(0, state.op_mode_block.as_ref().unwrap().command);
+                let blocks = state.actuator_mode_blocks.as_ref().unwrap();
+                assert!(blocks.indices().is_empty());
+            }
+            other => panic!("Expected the fabric but got {:?}", other),
+        }
+    } else {
+        assert!(matches!(replies[1], CommandMessage::Success {}));
+    }
+    Ok(())
+}
+
+#[test]
+fn keep_fabrics_across_system_reset() -> Result<()> {
+    #[allow(unused_variables)]
+    let timeout = 1000;
+    // Allow for the delay after a reset of the reader
+    #[cfg(any(feature = "usb", feature = "ethernet", feature = "haptic_v0"))]
+    let timeout = 10000;
+    connect_client_to_server(
+        timeout,
+        vec![
+            String::from(r#"{ "AddFabric": { "fabric_name": "Obid Feig LRM2500-B" } }"#),
+            String::from(r#"{ "SystemReset": { } }"#),
+            String::from(r#"{ "FabricState": { "fabric_name": "Obid Feig LRM2500-B" } }"#),
+            String::from(r#"{ "RemoveFabric": { "fabric_name": "Obid Feig LRM2500-B" } }"#),
+        ],
+    )
+}
+
+#[cfg(not(any(feature = "usb", feature = "ethernet")))]
+#[test]
+fn route_to_named_readers() -> Result<()> {
+    #[allow(unused_variables)]
+    let timeout = 1000;
+    #[cfg(any(feature = "usb", feature = "ethernet"))]
+    let timeout = 10000;
+    connect_client_to_readers(
+        timeout,
+        vec!["left", "right"],
+        vec![
+            String::from(r#"{ "Routed": { "reader": "right", "message": { "ReaderInfo": {} } } }"#),
+            String::from(
+                r#"{ "Routed": { "reader": "right", "message": { "AddFabric": { "fabric_name": "Right Block" } } } }"#,
+            ),
+            String::from(r#"{ "FabricState": { "fabric_name": "Right Block" } }"#),
+            String::from(r#"{ "RemoveFabric": { "fabric_name": "Right Block" } }"#),
+            String::from(r#"{ "ReaderInfo": {} }"#),
+        ],
+    )
+}
+
+#[cfg(all(feature = "haptic_v0", not(any(feature = "usb", feature = "ethernet"))))]
+#[test]
+fn recover_from_faulty_replies() -> Result<()> {
+    use protocol_host_lib::conn::mock::{Fault, FaultPlan};
+    use protocol_host_lib::obid::Status;
+    connect_client_to_faulty_server(
+        2000,
+        FaultPlan::script(vec![
+            Some(Fault::DropReply),
+            Some(Fault::CorruptCrc),
+            Some(Fault::Status(Status::NoTransponder)),
+            Some(Fault::Delay(std::time::Duration::from_millis(20))),
+        ]),
+        vec![
+            (
+                String::from(r#"{ "AddFabric": { "fabric_name": "Faulty Block" } }"#),
+                true,
+            ),
+            (
+                String::from(r#"{ "SetRadioFreqPower": { "power_level": 4 } }"#),
+                true,
+            ),
+        ],
+    )
+}
+
+#[cfg(all(feature = "haptic_v0", not(any(feature = "usb", feature = "ethernet"))))]
+#[test]
+fn keep_serving_after_reader_errors() -> Result<()> {
+    use protocol_host_lib::conn::mock::{Fault, FaultPlan};
+    use protocol_host_lib::obid::Status;
+    let add_fabric = String::from(r#"{ "AddFabric": { "fabric_name": "Faulty Block" } }"#);
+    let mut faults = vec![Some(Fault::Status(Status::RFWarning)), None];
+    faults.extend(vec![Some(Fault::Status(Status::NoTransponder)); 5]);
+    connect_client_to_faulty_server(
+        2000,
+        FaultPlan::script(faults),
+        vec![
+            (add_fabric.clone(), false),
+            (add_fabric.clone(), false),
+            (add_fabric, true),
+        ],
+    )
+}
+
+#[cfg(all(feature = "haptic_v0", not(any(feature = "usb", feature = "ethernet"))))]
+#[test]
+fn reconnect_after_disconnect() -> Result<()> {
+    use protocol_host_lib::conn::mock::{Fault, FaultPlan};
+    let add_fabric = String::from(r#"{ "AddFabric": { "fabric_name": "Faulty Block" } }"#);
+    connect_client_to_faulty_server(
+        2000,
+        FaultPlan::script(vec![None, Some(Fault::Disconnect)]),
+        vec![
+            (add_fabric.clone(), true),
+            (add_fabric.clone(), false),
+            // The fabrics are kept while the reader is reconnected
+            (
+                String::from(r#"{ "FabricState": { "fabric_name": "Faulty Block" } }"#),
+                true,
+            ),
+            (add_fabric, true),
+        ],
+    )
+}
+
+#[cfg(all(feature = "haptic_v0", not(any(feature = "usb", feature = "ethernet"))))]
+#[test]
+fn bind_fabrics_to_explicit_uids() -> Result<()> {
+    // The simulated reader has one transponder in its antenna
+    let replies = request_simulated_replies(
+        2000,
+        vec![
+            (
+                String::from(
+                    r#"{ "AddFabric": { "fabric_name": "Left Sleeve", "uids": ["e004010000000001"] } }"#,
+                ),
+                true,
+            ),
+            (
+                String::from(
+                    r#"{ "AddFabric": { "fabric_name": "Right Sleeve", "uids": ["e004010000000001"] } }"#,
+                ),
+                false,
+            ),
+            (
+                String::from(
+                    r#"{ "AddFabric": { "fabric_name": "Right Sleeve", "uids": ["e004010000000002"] } }"#,
+                ),
+                false,
+            ),
+            (
+                String::from(
+                    r#"{ "AddFabric": { "fabric_name": "Right Sleeve", "uids": ["e004010000000002"], "check_uids": false } }"#,
+                ),
+                true,
+            ),
+            (
+                String::from(r#"{ "AddFabric": { "fabric_name": "Glove", "uids": ["e00401"] } }"#),
+                false,
+            ),
+            (
+                String::from(r#"{ "AddFabric": { "fabric_name": "Glove" } }"#),
+                false,
+            ),
+            (
+                String::from(r#"{ "FabricState": { "fabric_name": "Left Sleeve" } }"#),
+                true,
+            ),
+            (
+                String::from(r#"{ "FabricState": { "fabric_name": "Right Sleeve" } }"#),
+                true,
+            ),
+        ],
+    )?;
+    assert_eq!(8, replies.len());
+
+    let uids_of = |reply: &CommandMessage| match reply {
+        CommandMessage::Fabric { uids, .. } => uids.clone(),
+        other => panic!("Expected a fabric but got {:?}", other),
+    };
+    assert_eq!(vec![String::from("e004010000000001")], uids_of(&replies[6]));
+    assert_eq!(vec![String::from("e004010000000002")], uids_of(&replies[7]));
+    Ok(())
+}
+
+#[cfg(all(feature = "haptic_v0", not(any(feature = "usb", feature = "ethernet"))))]
+#[test]
+fn list_the_transponders_in_the_antenna() -> Result<()> {
+    let replies = request_simulated_replies(
+        2000,
+        vec![
+            (String::from(r#"{ "Inventory": {} }"#), true),
+            (
+                String::from(
+                    r#"{ "AddFabric": { "fabric_name": "Left Sleeve", "uids": ["e004010000000001"] } }"#,
+                ),
+                true,
+            ),
+            (String::from(r#"{ "Inventory": {} }"#), true),
+            (
+                String::from(r#"{ "RemoveFabric": { "fabric_name": "Left Sleeve" } }"#),
+                true,
+            ),
+        ],
+    )?;
+    assert_eq!(4, replies.len());
+
+    let transponders_of = |reply: &CommandMessage| match reply {
+        CommandMessage::Transponders { transponders } => transponders
+            .iter()
+            .map(|transponder| (transponder.uid.clone(), transponder.fabric_name.clone()))
+            .collect::<Vec<_>>(),
+        other => panic!("Expected the transponders but got {:?}", other),
+    };
+    assert_eq!(
+        vec![(String::from("e004010000000001"), None)],
+        transponders_of(&replies[0])
+    );
+    assert_eq!(
+        vec![(
+            String::from("e004010000000001"),
+            Some(String::from("Left Sleeve"))
+        )],
+        transponders_of(&replies[2])
+    );
+    Ok(())
+}
+
+#[cfg(all(feature = "haptic_v0", not(any(feature = "usb", feature = "ethernet"))))]
+#[test]
+fn list_the_transponders_that_follow_more_data() -> Result<()> {
+    // More transponders than fit in the 24 of one inventory response
+    let uids: Vec<[u8; 8]> = (1..=30)
+        .map(|n| [0xE0, 0x04, 0x01, 0x00, 0x00, 0x00, 0x00, n])
+        .collect();
+    let transponders = uids
+        .iter()
+        .map(|uid| protocol_host_lib::conn::simulated::VirtualTransponder::new(*uid))
+        .collect();
+    let replies = request_simulated_replies_with_transponders(
+        2000,
+        transponders,
+        vec![
+            (String::from(r#"{ "Inventory": {} }"#), true),
+            (
+                String::from(
+                    r#"{ "AddFabric": { "fabric_name": "Glove", "uids": ["e00401000000001e"] } }"#,
+                ),
+                true,
+            ),
+        ],
+    )?;
+    assert_eq!(2, replies.len());
+
+    match &replies[0] {
+        CommandMessage::Transponders { transponders } => {
+            let found: Vec<String> = transponders
+                .iter()
+                .map(|transponder| transponder.uid.clone())
+                .collect();
+            let expected: Vec<String> = uids.iter().map(hex::encode).collect();
+            assert_eq!(expected, found);
+        }
+        other => panic!("Expected the transponders but got {:?}", other),
+    }
+    Ok(())
+}
+
+#[cfg(all(feature = "haptic_v0", not(any(feature = "usb", feature = "ethernet"))))]
+#[test]
+fn verify_and_read_actuator_state() -> Result<()> {
+    let replies = request_simulated_replies(
+        2000,
+        vec![
+            (
+                String::from(r#"{ "AddFabric": { "fabric_name": "Sleeve" } }"#),
+                true,
+            ),
+            (
+                String::from(
+                    r#"{ "ActuatorsCommand": { "fabric_name": "Sleeve", "op_mode_block": { "act_cnt8": 0, "cmd_op": 2, "command": 1 }, "actuator_mode_blocks": { "block0_31": { "b0": 15, "b1": 1, "b2": 0, "b3": 0 } }, "verify": true } }"#,
+                ),
+                true,
+            ),
+            (
+                String::from(r#"{ "ReadActuatorState": { "fabric_name": "Sleeve" } }"#),
+                true,
+            ),
+            (
+                String::from(r#"{ "ReadActuatorState": { "fabric_name": "Glove" } }"#),
+                false,
+            ),
+        ],
+    )?;
+    assert_eq!(4, replies.len());
+
+    match &replies[2] {
+        CommandMessage::ActuatorState {
+            fabric_name,
+            uid,
+            data,
+            memory,
+        } => {
+            assert_eq!("Sleeve", fabric_name);
+            assert_eq!("e004010000000001", uid);
+            // num_bytes 8, cmd_op 2 with act_cnt8 5, command 1, then actuators 0 to 3 and 8
+            assert_eq!("0f01450800000001", data);
+            assert_eq!(5, memory.op_mode_block.act_cnt8);
+            assert_eq!(2, memory.op_mode_block.cmd_op);
+            assert_eq!(1, memory.op_mode_block.command);
+            let blocks = memory.actuator_mode_blocks.as_ref().unwrap();
+            assert_eq!(vec![0, 1, 2, 3, 8], blocks.indices());
+        }
+        other => panic!("Expected the actuator state but got {:?}", other),
+    }
+    Ok(())
+}
+
+#[cfg(all(feature = "haptic_v0", not(any(feature = "usb", feature = "ethernet"))))]
+#[test]
+fn set_actuators_by_index() -> Result<()> {
+    let read = (
+        String::from(r#"{ "ReadActuatorState": { "fabric_name": "Sleeve" } }"#),
+        true,
+    );
+    let replies = request_simulated_replies(
+        2000,
+        vec![
+            (
+                String::from(r#"{ "AddFabric": { "fabric_name": "Sleeve" } }"#),
+                true,
+            ),
+            (
+                String::from(
+                    r#"{ "SetActuators": { "fabric_name": "Sleeve", "on": [0, 1, 2, 3, 35], "command": 2, "verify": true } }"#,
+                ),
+                true,
+            ),
+            read.clone(),
+            (
+                String::from(
+                    r#"{ "SetActuators": { "fabric_name": "Sleeve", "on": [128], "command": 2 } }"#,
+                ),
+                false,
+            ),
+            (
+                String::from(
+                    r#"{ "SetActuators": { "fabric_name": "Sleeve", "on": [0, 1], "command": 2, "timing": { "lf": { "period_ms": 4000, "duty_cycle": 50 } }, "use_cache": false } }"#,
+                ),
+                true,
+            ),
+            read.clone(),
+            (
+                String::from(
+                    r#"{ "SetActuators": { "fabric_name": "Sleeve", "on": [0, 1], "command": 2, "timing": { "hf": { "period_ms": 150, "duty_cycle": 20 } }, "verify": true } }"#,
+                ),
+                true,
+            ),
+            (
+                String::from(
+                    r#"{ "SetActuators": { "fabric_name": "Sleeve", "on": [], "command": 0 } }"#,
+                ),
+                true,
+            ),
+            read,
+        ],
+    )?;
+    assert_eq!(9, replies.len());
+
+    let data_of = |reply: &CommandMessage| match reply {
+        CommandMessage::ActuatorState { data, .. } => data.clone(),
+        other => panic!("Expected the actuator state but got {:?}", other),
+    };
+    // Command 2 for actuators 0 to 3 in the first block and 35 in the second
+    assert_eq!("0f02450808000000", data_of(&replies[2]));
+    // Actuators 0 and 1 with the LF timer of 2000 ms on in 4000 ms in the second block
+    assert_eq!("030261080fa007d0", data_of(&replies[5]));
+    // All off
+    assert_eq!("00002003", data_of(&replies[8]));
+    Ok(())
+}
+
+#[cfg(all(feature = "haptic_v0", not(any(feature = "usb", feature = "ethernet"))))]
+#[test]
+fn play_and_stop_a_pattern() -> Result<()> {
+    let replies = request_simulated_replies(
+        2000,
+        vec![
+            (
+                String::from(r#"{ "AddFabric": { "fabric_name": "Sleeve" } }"#),
+                true,
+            ),
+            (
+                String::from(
+                    r#"{ "PlayPattern": { "fabric_name": "Sleeve", "command": 2, "frames": [{ "actuators": [0], "duration_ms": 20 }, { "actuators": [1, 2], "duration_ms": 20 }], "repeat": 0 } }"#,
+                ),
+                true,
+            ),
+            (
+                String::from(
+                    r#"{ "PlayPattern": { "fabric_name": "Sleeve", "command": 2, "frames": [] } }"#,
+                ),
+                false,
+            ),
+            (
+                String::from(
+                    r#"{ "PlayPattern": { "fabric_name": "Sleeve", "command": 2, "frames": [{ "actuators": [72], "duration_ms": 20 }] } }"#,
+                ),
+                false,
+            ),
+            (
+                String::from(
+                    r#"{ "PlayPattern": { "fabric_name": "Unknown", "command": 2, "frames": [{ "actuators": [0], "duration_ms": 20 }] } }"#,
+                ),
+                false,
+            ),
+            (
+                String::from(r#"{ "ReadActuatorState": { "fabric_name": "Sleeve" } }"#),
+                true,
+            ),
+            (
+                String::from(r#"{ "StopPattern": { "fabric_name": "Sleeve" } }"#),
+                true,
+            ),
+            (
+                String::from(r#"{ "ReadActuatorState": { "fabric_name": "Sleeve" } }"#),
+                true,
+            ),
+        ],
+    )?;
+    assert_eq!(8, replies.len());
+
+    let data_of = |reply: &CommandMessage| match reply {
+        CommandMessage::ActuatorState { data, .. } => data.clone(),
+        other => panic!("Expected the actuator state but got {:?}", other),
+    };
+    // While the pattern plays, one of its frames is written with command 2
+    let playing = data_of(&replies[5]);
+    assert!(
+        ["0102450800000000", "0602450800000000"].contains(&playing.as_str()),
+        "Expected a frame of the pattern but found {}",
+        playing
+    );
+    // The stop leaves the fabric all off
+    assert_eq!("00002003", data_of(&replies[7]));
+    Ok(())
+}
+
+#[cfg(feature = "haptic_v0")]
+#[test]
+fn restore_fabrics_from_the_state_file() -> Result<()> {
+    #[allow(unused_variables)]
+    let timeout = 2000;
+    #[cfg(any(feature = "usb", feature = "ethernet"))]
+    let timeout = 10000;
+    let state_file = std::env::temp_dir().join(format!("state-{}.json", uuid::Uuid::new_v4()));
+    let fabric_state = String::from(r#"{ "FabricState": { "fabric_name": "Saved Block" } }"#);
+    connect_client_to_server_with_state_file(
+        timeout,
+        &state_file,
+        vec![
+            (fabric_state.clone(), false),
+            (
+                String::from(r#"{ "AddFabric": { "fabric_name": "Saved Block" } }"#),
+                true,
+            ),
+            (
+                String::from(
+                    r#"{ "ActuatorsCommand": { "fabric_name": "Saved Block", "op_mode_block": { "act_cnt8": 0, "cmd_op": 2, "command": 1 } } }"#,
+                ),
+                true,
+            ),
+        ],
+    )?;
+
+    // A restarted host knows the fabric without adding it again
+    connect_client_to_server_with_state_file(
+        timeout,
+        &state_file,
+        vec![
+            (fabric_state.clone(), true),
+            (
+                String::from(r#"{ "RemoveFabric": { "fabric_name": "Saved Block" } }"#),
+                true,
+            ),
+        ],
+    )?;
+    connect_client_to_server_with_state_file(timeout, &state_file, vec![(fabric_state, false)])?;
+    std::fs::remove_file(&state_file)?;
+    Ok(())
+}
+
 #[test]
 fn set_the_power_level() -> Result<()> {
     #[allow(unused_variables)]
     let timeout = 500;
-    #[cfg(any(feature = "usb", feature = "ethernet"))]
+    // Allow for the delay after a reset of the reader
+    #[cfg(any(feature = "usb", feature = "ethernet", feature = "haptic_v0"))]
     let timeout = 10000;
     connect_client_to_server(
         timeout,
@@ -51,7 +578,8 @@ fn set_the_power_level() -> Result<()> {
 fn set_the_power_level_low_power() -> Result<()> {
     #[allow(unused_variables)]
     let timeout = 500;
-    #[cfg(any(feature = "usb", feature = "ethernet"))]
+    // Allow for the delay after a reset of the reader
+    #[cfg(any(feature = "usb", feature = "ethernet", feature = "haptic_v0"))]
     let timeout = 10000;
     connect_client_to_server(
         timeout,
@@ -63,11 +591,80 @@ fn set_the_power_level_low_power() -> Result<()> {
 }
 
 #[test]
-fn e2e_pulsing_after_antenna_reset() -> Result<()> {
+fn set_the_power_level_addressed() -> Result<()> {
+    #[allow(unused_variables)]
+    let timeout = 500;
+    #[cfg(any(feature = "usb", feature = "ethernet"))]
+    let timeout = 10000;
+    connect_client_to_server(
+        timeout,
+        vec![String::from(
+            r#"{ "Addressed": { "com_adr": 0, "message": { "SetRadioFreqPower": { "power_level": 4 } } } }"#,
+        )],
+    )
+}
+
+#[cfg(all(feature = "haptic_v0", not(any(feature = "usb", feature = "ethernet"))))]
+#[test]
+fn reset_an_addressed_reader() -> Result<()> {
+    // The fabric names a transponder that isn't in the antenna, which the check after a reset finds
+    let replies = request_simulated_replies(
+        10000,
+        vec![
+            (
+                String::from(
+                    r#"{ "AddFabric": { "fabric_name": "Sleeve", "uids": ["e0040100000000ff"], "check_uids": false } }"#,
+                ),
+                true,
+            ),
+            (
+                String::from(
+                    r#"{ "Addressed": { "com_adr": 0, "message": { "SystemReset": { } } } }"#,
+                ),
+                false,
+            ),
+            (String::from(r#"{ "SystemReset": { } }"#), false),
+        ],
+    )?;
+    assert_eq!(3, replies.len());
+
+    // The addressed reset reconnects and checks the fabrics just like the unaddressed one
+    for reply in &replies[1..] {
+        match reply {
+            CommandMessage::Failure { message } => assert!(
+                message.contains("Fabrics missing from the antenna after the reset: Sleeve"),
+                "Expected the missing fabric but got {}",
+                message
+            ),
+            other => panic!("Expected the missing fabric but got {:?}", other),
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn set_the_power_level_in_ram() -> Result<()> {
     #[allow(unused_variables)]
     let timeout = 500;
     #[cfg(any(feature = "usb", feature = "ethernet"))]
     let timeout = 10000;
+    connect_client_to_server(
+        timeout,
+        vec![
+            String::from(r#"{ "ReadConfiguration": { "address": 3, "location": "Ram" } }"#),
+            String::from(r#"{ "SetRadioFreqPower": { "power_level": 4, "location": "Ram" } }"#),
+            String::from(r#"{ "ReadConfiguration": { "address": 3, "location": "Ram" } }"#),
+        ],
+    )
+}
+
+#[test]
+fn e2e_pulsing_after_antenna_reset() -> Result<()> {
+    #[allow(unused_variables)]
+    let timeout = 500;
+    // Allow for the delay after a reset of the reader
+    #[cfg(any(feature = "usb", feature = "ethernet", feature = "haptic_v0"))]
+    let timeout = 10000;
     // Reset the conditions of the antenna
     connect_client_to_server(
         timeout,
@@ -132,6 +729,7 @@ fn e2e_pulsing() -> Result<()> {
             b0: 0x00,
             b1: 0x00,
             b2: 0x00,
+            b3: 0x00,
         }),
 
         /*
@@ -144,6 +742,7 @@ fn e2e_pulsing() -> Result<()> {
             b0: (hf_duty_cycle & 0x00FF) as u8,
             b1: ((hf_duty_cycle & 0xFF00) >> 8) as u8,
             b2: (hf_period & 0x00FF) as u8,
+            b3: ((hf_period & 0xFF00) >> 8) as u8,
         }),
 
         /*
@@ -156,6 +755,7 @@ fn e2e_pulsing() -> Result<()> {
             b0: 0xFF, // ((4000 & 0x00FF)) as u8,
             b1: 0xFF, // ((4000 & 0xFF00) >> 8) as u8,
             b2: 0xFF, // ((4000 & 0x00FF)) as u8,
+            b3: 0xFF, // ((4000 & 0xFF00) >> 8) as u8,
         }),
     };
     let timer_mode_blocks = serde_json::to_string(&timer_mode_blocks)?;
@@ -230,6 +830,7 @@ fn send_all_off() -> Result<()> {
             b0: 0x00,
             b1: 0x00,
             b2: 0x00,
+            b3: 0x00,
         }),
 
         /*
@@ -242,6 +843,7 @@ fn send_all_off() -> Result<()> {
             b0: (hf_duty_cycle & 0x00FF) as u8,
             b1: ((hf_duty_cycle & 0xFF00) >> 8) as u8,
             b2: (hf_period & 0x00FF) as u8,
+            b3: ((hf_period & 0xFF00) >> 8) as u8,
         }),
 
         /*
@@ -254,6 +856,7 @@ fn send_all_off() -> Result<()> {
             b0: 0xFF, // ((4000 & 0x00FF)) as u8,
             b1: 0xFF, // ((4000 & 0xFF00) >> 8) as u8,
             b2: 0xFF, // ((4000 & 0x00FF)) as u8,
+            b3: 0xFF, // ((4000 & 0xFF00) >> 8) as u8,
         }),
     };
     let timer_mode_blocks = serde_json::to_string(&timer_mode_blocks)?;